@@ -0,0 +1,54 @@
+use avrman::{
+    Programmer, ProtocolType,
+    interface::{loopback::LoopbackInterface, serialport::ResetLine},
+    protocols::stk500v1::Stk500v1Params,
+};
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+
+/// A small sketch and one filling a 32KB part
+const IMAGE_SIZES_KB: [usize; 2] = [16, 32];
+const PAGE_SIZE: usize = 128;
+
+fn loopback_programmer() -> Programmer {
+    let signature = vec![0x1e, 0x95, 0x0f];
+    let interface = LoopbackInterface::new(signature.clone(), 32 * 1024);
+
+    Programmer::from_protocol_and_interface(
+        ProtocolType::Stk500v1(Stk500v1Params {
+            port: String::from("loopback"),
+            baud: 115200,
+            device_signature: signature,
+            page_size: PAGE_SIZE as u16,
+            num_pages: 256,
+            reset_line: ResetLine::Both,
+            ..Default::default()
+        }),
+        Box::new(interface),
+    )
+    .expect("Failed to create loopback programmer")
+}
+
+fn program_and_verify(c: &mut Criterion) {
+    let programmer = loopback_programmer();
+
+    let mut group = c.benchmark_group("stk500v1_loopback");
+    group.sample_size(10);
+    for image_kb in IMAGE_SIZES_KB {
+        let image_size = image_kb * 1024;
+        let image: Vec<u8> = (0..image_size).map(|i| (i % 251) as u8).collect();
+
+        // Handshake and leave progmode, plus load address + page command for
+        // every page in both the program and verify passes
+        let pages = image_size.div_ceil(PAGE_SIZE);
+        let commands = 5 + 4 * pages;
+
+        group.throughput(Throughput::Elements(commands as u64));
+        group.bench_function(format!("program_and_verify_{}k", image_kb), |b| {
+            b.iter(|| programmer.program_binary(image.clone()).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, program_and_verify);
+criterion_main!(benches);
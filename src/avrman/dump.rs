@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+
+use avrman::error::{AvrError, AvrResult};
+use clap::{Parser, ValueEnum};
+use ihex::Record;
+
+use crate::target::TargetOptions;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum DumpFormat {
+    Hex,
+    Bin,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub(crate) struct DumpOptions {
+    #[clap(flatten)]
+    target: TargetOptions,
+
+    /// File to write the flash contents to
+    #[clap(short, long)]
+    output: PathBuf,
+
+    /// Number of bytes to read from address 0, the whole flash by default
+    #[clap(long)]
+    bytes: Option<usize>,
+
+    /// Intel hex or raw binary
+    #[clap(long, value_enum, default_value_t = DumpFormat::Hex)]
+    format: DumpFormat,
+}
+
+pub(crate) fn handle_dump(opts: DumpOptions) -> AvrResult<()> {
+    let mut programmer = opts.target.programmer()?;
+    programmer.progress_bar(true);
+
+    let num_bytes = opts.bytes.unwrap_or_else(|| programmer.flash_size());
+    let contents = match opts.format {
+        DumpFormat::Bin => programmer.read_flash(num_bytes)?,
+        DumpFormat::Hex => {
+            let mut records = programmer.read_flash_records(num_bytes)?;
+            records.push(Record::EndOfFile);
+            ihex::create_object_file_representation(&records)
+                .map_err(|e| {
+                    AvrError::FirmwareError(format!("Failed to write hex records {:?}", e))
+                })?
+                .into_bytes()
+        }
+    };
+
+    std::fs::write(&opts.output, contents)
+        .map_err(|e| AvrError::FirmwareError(format!("Failed to write flash dump: {}", e)))?;
+    println!(
+        "Wrote {} bytes of flash to {}",
+        num_bytes,
+        opts.output.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use std::io::{BufRead, Write};
+
+use avrman::{error::AvrResult, fuses::EepromOnErase};
+use clap::Parser;
+
+use crate::target::TargetOptions;
+
+#[derive(Parser, Debug, Clone)]
+pub(crate) struct EraseOptions {
+    #[clap(flatten)]
+    target: TargetOptions,
+
+    /// Don't ask for confirmation
+    #[clap(short, long, default_value_t = false)]
+    yes: bool,
+
+    /// Refuse to erase when the EESAVE fuse is unprogrammed, as EEPROM would
+    /// be cleared too
+    #[clap(long, default_value_t = false, conflicts_with = "keep_eeprom")]
+    require_eesave: bool,
+
+    /// Save this many bytes of EEPROM and write them back after the erase
+    /// when EESAVE is unprogrammed
+    #[clap(long, value_name = "BYTES")]
+    keep_eeprom: Option<usize>,
+}
+
+pub(crate) fn handle_erase(opts: EraseOptions) -> AvrResult<()> {
+    if !opts.yes
+        && !confirm(&format!(
+            "Erase the whole chip on the {:?}?",
+            opts.target.board
+        ))
+    {
+        println!("Not erased");
+        return Ok(());
+    }
+
+    let eeprom = match (opts.keep_eeprom, opts.require_eesave) {
+        (Some(num_bytes), _) => EepromOnErase::Restore(num_bytes),
+        (None, true) => EepromOnErase::Refuse,
+        (None, false) => EepromOnErase::Warn,
+    };
+    opts.target.programmer()?.erase_with(eeprom)?;
+    println!("Chip erased");
+
+    Ok(())
+}
+
+/// Ask a yes/no question on the terminal, defaulting to no
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    let _ = std::io::stdout().flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
@@ -0,0 +1,70 @@
+use avrman::{
+    Microcontroller,
+    boards::{board_spec, mcu_for_product_id},
+    detect_mcu,
+    error::AvrResult,
+    list_ports,
+};
+use clap::{Parser, ValueEnum};
+
+/// Baud rate used to probe ports that don't match any known board
+const PROBE_BAUD: u32 = 115200;
+
+#[derive(Parser, Debug, Clone)]
+pub(crate) struct ListOptions {
+    /// Sync with each port's bootloader and read the signature, rather than
+    /// guessing the board from the USB product ID. Resets every board probed,
+    /// boards reset by a 1200 baud touch (Leonardo) are only guessed
+    #[clap(long, default_value_t = false)]
+    probe: bool,
+}
+
+pub(crate) fn handle_list(opts: ListOptions) -> AvrResult<()> {
+    let ports = list_ports()?;
+    if ports.is_empty() {
+        println!("No USB serial ports found");
+        return Ok(());
+    }
+
+    for port in ports {
+        let guess = port.pid.and_then(mcu_for_product_id);
+        if !opts.probe {
+            match guess {
+                Some(mcu) => println!("{}: {}", port.name, board_name(&mcu)),
+                None => println!("{}: unknown board", port.name),
+            }
+            continue;
+        }
+
+        let spec = guess.map(board_spec);
+        if let Some(spec) = spec.as_ref().filter(|spec| spec.touch_reset) {
+            println!(
+                "{}: {} (not probed, its bootloader is entered by a 1200 baud touch)",
+                port.name,
+                board_name(&spec.mcu)
+            );
+            continue;
+        }
+
+        // Boards sharing a USB chip can run their bootloaders at different
+        // rates (eg: a Mega behind the Nano's FTDI ID), so the usual rate is
+        // tried if the guessed board's one gets no answer
+        let baud = spec.map_or(PROBE_BAUD, |spec| spec.default_baud);
+        let mut detected = detect_mcu(&port.name, baud);
+        if detected.is_err() && baud != PROBE_BAUD {
+            detected = detect_mcu(&port.name, PROBE_BAUD);
+        }
+        match detected {
+            Ok(mcu) => println!("{}: {}", port.name, board_name(&mcu)),
+            Err(e) => println!("{}: no answer ({})", port.name, e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Name of the board as passed to --board
+fn board_name(mcu: &Microcontroller) -> String {
+    mcu.to_possible_value()
+        .map_or_else(|| format!("{:?}", mcu), |value| value.get_name().to_owned())
+}
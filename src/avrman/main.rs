@@ -1,8 +1,17 @@
-use avrman::error::AvrResult;
-use clap::{Parser, command};
+use avrman::error::{AvrError, AvrResult};
+use clap::Parser;
+use dump::{DumpOptions, handle_dump};
+use erase::{EraseOptions, handle_erase};
+use list::{ListOptions, handle_list};
 use program::{ProgramOptions, handle_programming};
+use read_fuses::{ReadFusesOptions, handle_read_fuses};
 
+mod dump;
+mod erase;
+mod list;
 mod program;
+mod read_fuses;
+mod target;
 
 #[derive(Parser, Debug, Clone)]
 #[command(version, long_about = None)]
@@ -10,6 +19,22 @@ enum Cli {
     /// Program target device with options
     #[command(name = "program", alias = "p")]
     Program(ProgramOptions),
+
+    /// Erase the whole chip. Needs an ISP programmer, bootloaders ignore this
+    #[command(name = "erase")]
+    Erase(EraseOptions),
+
+    /// Print the fuse bytes. Needs an ISP programmer, bootloaders don't answer fuse reads
+    #[command(name = "read-fuses")]
+    ReadFuses(ReadFusesOptions),
+
+    /// Read flash to an Intel hex or raw binary file, eg: to back up a board
+    #[command(name = "dump")]
+    Dump(DumpOptions),
+
+    /// List connected boards, guessed from their USB product IDs
+    #[command(name = "list")]
+    List(ListOptions),
 }
 
 fn main() -> AvrResult<()> {
@@ -20,8 +45,19 @@ fn main() -> AvrResult<()> {
     let cli = Cli::parse();
 
     match cli {
-        Cli::Program(opts) => handle_programming(opts)?,
+        Cli::Program(opts) => handle_programming(opts).inspect_err(print_hint)?,
+        Cli::Erase(opts) => handle_erase(opts).inspect_err(print_hint)?,
+        Cli::ReadFuses(opts) => handle_read_fuses(opts).inspect_err(print_hint)?,
+        Cli::Dump(opts) => handle_dump(opts).inspect_err(print_hint)?,
+        Cli::List(opts) => handle_list(opts)?,
     }
 
     Ok(())
 }
+
+/// Suggest a fix for errors users can usually sort out themselves
+fn print_hint(error: &AvrError) {
+    if let AvrError::DeviceNotFound { .. } = error {
+        eprintln!("Is the board plugged in? Use --serial to pick its port by hand");
+    }
+}
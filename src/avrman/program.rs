@@ -1,55 +1,197 @@
-use std::path::PathBuf;
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
 use avrman::{
-    Microcontroller,
+    boards::protocol_for_mcu,
+    diff::changed_ranges,
     error::AvrResult,
     interface::{DeviceInterfaceType, SerialportParams},
+    report::ProgramReport,
 };
 use clap::Parser;
 
+use crate::target::TargetOptions;
+
 #[derive(Parser, Debug, Clone)]
 pub(crate) struct ProgramOptions {
-    /// Board type
-    #[clap(short, long)]
-    board: Microcontroller,
+    #[clap(flatten)]
+    target: TargetOptions,
 
-    /// Firmware
+    /// Firmware, a path to a hex or raw .bin file or (with the http feature) an http(s):// URL
     #[clap(short, long)]
     firmware: PathBuf,
 
-    /// Serial port
-    #[clap(short, long)]
-    serial: Option<String>,
-
-    /// Baud rate
-    #[clap(short, long)]
-    baudrate: Option<u32>,
-
     #[clap(short, long, default_value_t = false)]
     no_verify: bool,
+
+    /// Only verify this flash region, given as <start:len> (decimal or 0x hex)
+    #[clap(long, value_parser = parse_verify_range)]
+    verify_range: Option<(usize, usize)>,
+
+    /// Don't touch the board, print every command that would be sent instead
+    #[clap(long, default_value_t = false, conflicts_with = "diff_before")]
+    dry_run: bool,
+
+    /// Read flash before programming and print the address ranges that changed
+    #[clap(long, default_value_t = false)]
+    diff_before: bool,
 }
 
 pub(crate) fn handle_programming(opts: ProgramOptions) -> AvrResult<()> {
-    let mcu = opts.board;
+    if opts.dry_run {
+        return dry_run(opts);
+    }
+
     let file = opts.firmware;
+    let mut programmer = opts.target.programmer()?;
 
-    let mut programmer = if opts.serial.is_some() || opts.baudrate.is_some() {
-        let interface = DeviceInterfaceType::Serial(SerialportParams {
-            port: opts.serial,
-            baud: opts.baudrate,
-        });
-        avrman::Programmer::from_mcu_and_interface(mcu, interface)?
+    programmer.progress_bar(true);
+    programmer.verify_after_programming(!opts.no_verify);
+    if let Some((start, len)) = opts.verify_range {
+        programmer.verify_range(start, len)?;
+    }
+
+    let before = if opts.diff_before {
+        match programmer.read_flash(programmer.flash_size()) {
+            Ok(flash) => Some(flash),
+            Err(e) => {
+                // Blank or locked chips can't always be read, programming can still go ahead
+                tracing::warn!("Could not read flash before programming, skipping diff: {e}");
+                None
+            }
+        }
     } else {
-        avrman::Programmer::new(mcu)?
+        None
     };
 
-    programmer.progress_bar(true);
-    programmer.verify_after_programming(!opts.no_verify);
+    let report = program_firmware(&programmer, &file)?;
+    tracing::info!(
+        "Wrote {} bytes in {} pages in {:.2?}, verified {} bytes in {:.2?} ({:.2?} total)",
+        report.bytes_written,
+        report.pages_written,
+        report.upload_duration,
+        report.verify_bytes,
+        report.verify_duration,
+        report.duration
+    );
+
+    if let Some(before) = before {
+        let after = programmer.read_flash(before.len())?;
+        print_changed_ranges(&changed_ranges(&before, &after));
+    }
 
-    programmer.program_hex_file(
-        file.to_str()
-            .expect("Could not convert firmware PathBuf to string"),
+    Ok(())
+}
+
+fn dry_run(opts: ProgramOptions) -> AvrResult<()> {
+    // A port is given so that no attached board is looked for
+    let protocol = protocol_for_mcu(
+        opts.target.board,
+        Some(DeviceInterfaceType::Serial(SerialportParams {
+            port: Some(String::from("dry-run")),
+            baud: opts.target.baudrate,
+        })),
     )?;
+    let (mut programmer, commands) = avrman::Programmer::dry_run(protocol)?;
+    programmer.verify_after_programming(!opts.no_verify);
+    if let Some((start, len)) = opts.verify_range {
+        programmer.verify_range(start, len)?;
+    }
+
+    program_firmware(&programmer, &opts.firmware)?;
+
+    for command in commands.lock().expect("Failed to lock command log").iter() {
+        let bytes: Vec<String> = command.iter().map(|byte| format!("{:02x}", byte)).collect();
+        println!("{}", bytes.join(" "));
+    }
 
     Ok(())
 }
+
+fn program_firmware(programmer: &avrman::Programmer, file: &Path) -> AvrResult<ProgramReport> {
+    match file.to_str() {
+        Some(url) if is_url(url) => program_url(programmer, url),
+        _ if is_bin(file) => programmer.program_bin_file(file),
+        _ => programmer.program_hex_file(file),
+    }
+}
+
+fn parse_verify_range(range: &str) -> Result<(usize, usize), String> {
+    let (start, len) = range
+        .split_once(':')
+        .ok_or_else(|| String::from("expected <start:len>"))?;
+
+    Ok((parse_number(start)?, parse_number(len)?))
+}
+
+fn parse_number(number: &str) -> Result<usize, String> {
+    let parsed = match number.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => number.parse(),
+    };
+    parsed.map_err(|_| format!("invalid number {:?}", number))
+}
+
+fn is_url(firmware: &str) -> bool {
+    firmware.starts_with("http://") || firmware.starts_with("https://")
+}
+
+fn is_bin(firmware: &Path) -> bool {
+    firmware
+        .extension()
+        .is_some_and(|extension| extension == "bin")
+}
+
+#[cfg(feature = "http")]
+fn program_url(programmer: &avrman::Programmer, url: &str) -> AvrResult<ProgramReport> {
+    programmer.program_hex_url(url)
+}
+
+#[cfg(not(feature = "http"))]
+fn program_url(_programmer: &avrman::Programmer, url: &str) -> AvrResult<ProgramReport> {
+    Err(avrman::error::AvrError::ConfigurationError(format!(
+        "Cannot program {}, avrman was built without the http feature",
+        url
+    )))
+}
+
+fn print_changed_ranges(ranges: &[Range<usize>]) {
+    if ranges.is_empty() {
+        println!("Flash contents unchanged");
+        return;
+    }
+
+    for range in ranges {
+        println!(
+            "Changed {:#07x}..{:#07x} ({} bytes)",
+            range.start,
+            range.end,
+            range.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dry_run_rejects_verify_range_outside_flash() {
+        let opts = ProgramOptions {
+            target: TargetOptions {
+                board: avrman::Microcontroller::ArduinoUno,
+                serial: None,
+                baudrate: None,
+            },
+            firmware: PathBuf::from("tests/blink.hex"),
+            no_verify: false,
+            verify_range: Some((0, 0x10000)),
+            dry_run: true,
+            diff_before: false,
+        };
+
+        assert!(dry_run(opts).is_err());
+    }
+}
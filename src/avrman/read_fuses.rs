@@ -0,0 +1,71 @@
+use avrman::{error::AvrResult, fuses::Fuses};
+use clap::Parser;
+use serde::Serialize;
+
+use crate::target::TargetOptions;
+
+#[derive(Parser, Debug, Clone)]
+pub(crate) struct ReadFusesOptions {
+    #[clap(flatten)]
+    target: TargetOptions,
+
+    /// Print the fuses as a JSON object, for scripts
+    #[clap(long, default_value_t = false)]
+    json: bool,
+}
+
+pub(crate) fn handle_read_fuses(opts: ReadFusesOptions) -> AvrResult<()> {
+    let fuses = opts.target.programmer()?.read_fuses()?;
+
+    if opts.json {
+        println!("{}", fuses_json(&fuses));
+    } else {
+        println!("low: {:#04x}", fuses.low);
+        println!("high: {:#04x}", fuses.high);
+        if let Some(extended) = fuses.extended {
+            println!("extended: {:#04x}", extended);
+        }
+    }
+
+    Ok(())
+}
+
+/// Fuses as printed with --json, each byte as a hex string
+#[derive(Serialize)]
+struct FusesJson {
+    low: String,
+    high: String,
+    extended: Option<String>,
+}
+
+/// `{"low":"0xff","high":"0xde","extended":"0xfd"}`, with a null extended
+/// fuse on parts that don't have one
+fn fuses_json(fuses: &Fuses) -> String {
+    let hex = |byte: u8| format!("{:#04x}", byte);
+
+    serde_json::to_string(&FusesJson {
+        low: hex(fuses.low),
+        high: hex(fuses.high),
+        extended: fuses.extended.map(hex),
+    })
+    .expect("Fuses are always serializable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuses_json_layout() {
+        let fuses = Fuses {
+            low: 0xff,
+            high: 0xde,
+            extended: Some(0xfd),
+        };
+
+        assert_eq!(
+            fuses_json(&fuses),
+            r#"{"low":"0xff","high":"0xde","extended":"0xfd"}"#
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use avrman::{
+    Microcontroller, Programmer,
+    error::AvrResult,
+    interface::{DeviceInterfaceType, SerialportParams},
+};
+use clap::Args;
+
+/// Which board to talk to, and how to reach it
+#[derive(Args, Debug, Clone)]
+pub(crate) struct TargetOptions {
+    /// Board type
+    #[clap(short, long)]
+    pub board: Microcontroller,
+
+    /// Serial port
+    #[clap(short, long)]
+    pub serial: Option<String>,
+
+    /// Baud rate
+    #[clap(short, long)]
+    pub baudrate: Option<u32>,
+}
+
+impl TargetOptions {
+    /// Programmer for the selected board. The port is looked up by USB
+    /// product ID unless one is given
+    pub fn programmer(&self) -> AvrResult<Programmer> {
+        if self.serial.is_some() || self.baudrate.is_some() {
+            let interface = DeviceInterfaceType::Serial(SerialportParams {
+                port: self.serial.clone(),
+                baud: self.baudrate,
+            });
+            Programmer::from_mcu_and_interface(self.board.clone(), interface)
+        } else {
+            Programmer::new(self.board.clone())
+        }
+    }
+}
@@ -1,15 +1,30 @@
 use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serialport::{SerialPortInfo, SerialPortType};
+use tracing::info;
 
 use crate::{
     ProtocolType, Stk500v1Params,
+    constants::{
+        CH340_BOOTUP_DELAY_MS, CH340_PID, CH340_RESET_LOW_MICROS,
+        DEFAULT_STK500V1_EEPROM_PAGE_SIZE, DEFAULT_STK500V1_EEPROM_SIZE,
+        DEFAULT_STK500V2_EEPROM_PAGE_SIZE, DEFAULT_STK500V2_EEPROM_SIZE, LEONARDO_BOOTLOADER_PID,
+        LEONARDO_SKETCH_PID, MAX_FLASH_SIZE, PRO_MICRO_BOOTLOADER_PID, PRO_MICRO_SKETCH_PID,
+        REENUMERATION_POLL_MS, REENUMERATION_TIMEOUT_MS,
+    },
     error::{AvrError, AvrResult},
-    interface::DeviceInterfaceType,
+    interface::{
+        DeviceInterfaceType,
+        serialport::{ResetLine, ResetTiming},
+    },
     protocols::stk500v2::Stk500v2Params,
+    util::decode_hex,
 };
 
 /// Microcontroller enum includes all boards/microcontrollers
 /// that have been tested to work with avrman
-#[derive(Debug, Clone, ValueEnum)]
+#[derive(Debug, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum Microcontroller {
     /// Atmega328p initialized with COM/serial port
     ArduinoUno,
@@ -22,6 +37,300 @@ pub enum Microcontroller {
 
     /// Arduino Mega
     ArduinoMega,
+
+    /// Arduino Leonardo (ATmega32u4, native USB). Needs a bootloader speaking
+    /// STK500v1, the stock Caterina bootloader speaks AVR109
+    ArduinoLeonardo,
+
+    /// ATtiny85 programmed through an Arduino as ISP
+    Attiny85,
+
+    /// Bare ATmega32u4 with native USB (eg: Pro Micro clones). Needs a
+    /// bootloader speaking STK500v1, the stock Caterina bootloader speaks AVR109
+    Atmega32u4,
+}
+
+/// Wire protocol spoken by a board's bootloader
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProtocolKind {
+    Stk500v1,
+    Stk500v2,
+}
+
+/// Everything avrman knows about a supported board
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoardSpec {
+    pub mcu: Microcontroller,
+    pub protocol: ProtocolKind,
+    pub signature: Vec<u8>,
+    pub page_size: u16,
+    pub num_pages: u16,
+    pub flash_size: u32,
+    pub eeprom_page_size: u16,
+    pub eeprom_size: usize,
+    pub default_baud: u32,
+    pub product_ids: Vec<u16>,
+
+    /// Enters the bootloader through a 1200 baud touch rather than DTR/RTS
+    pub touch_reset: bool,
+}
+
+/// Look up the board spec for a given MCU
+pub fn board_spec(mcu: Microcontroller) -> BoardSpec {
+    let (
+        protocol,
+        signature,
+        page_size,
+        num_pages,
+        eeprom_page_size,
+        eeprom_size,
+        default_baud,
+        product_ids,
+    ) = match mcu {
+        Microcontroller::ArduinoUno | Microcontroller::Atmega328p => (
+            ProtocolKind::Stk500v1,
+            vec![0x1e, 0x95, 0x0f],
+            128,
+            256,
+            4,
+            1024,
+            115200,
+            vec![0x0043, 0x7523, 0x0001, 0xea60, 0x6015],
+        ),
+        Microcontroller::ArduinoNano => (
+            ProtocolKind::Stk500v1,
+            vec![0x1e, 0x95, 0x0f],
+            128,
+            256,
+            4,
+            1024,
+            57600,
+            vec![0x6001, 0x7523],
+        ),
+        Microcontroller::ArduinoMega => (
+            ProtocolKind::Stk500v2,
+            vec![0x1e, 0x98, 0x01],
+            256,
+            1024,
+            8,
+            4096,
+            115200,
+            vec![0x6001, 0x7523],
+        ),
+        Microcontroller::ArduinoLeonardo => (
+            ProtocolKind::Stk500v1,
+            vec![0x1e, 0x95, 0x87],
+            128,
+            256,
+            4,
+            1024,
+            57600,
+            vec![LEONARDO_BOOTLOADER_PID, LEONARDO_SKETCH_PID],
+        ),
+        Microcontroller::Atmega32u4 => (
+            ProtocolKind::Stk500v1,
+            vec![0x1e, 0x95, 0x87],
+            128,
+            256,
+            4,
+            1024,
+            57600,
+            vec![
+                LEONARDO_BOOTLOADER_PID,
+                LEONARDO_SKETCH_PID,
+                PRO_MICRO_BOOTLOADER_PID,
+                PRO_MICRO_SKETCH_PID,
+            ],
+        ),
+        // The USB IDs are those of the Uno running the ArduinoISP sketch
+        Microcontroller::Attiny85 => (
+            ProtocolKind::Stk500v1,
+            vec![0x1e, 0x93, 0x0b],
+            64,
+            128,
+            4,
+            512,
+            19200,
+            vec![0x0043, 0x7523, 0x0001, 0xea60, 0x6015],
+        ),
+    };
+    let touch_reset = matches!(
+        mcu,
+        Microcontroller::ArduinoLeonardo | Microcontroller::Atmega32u4
+    );
+
+    BoardSpec {
+        mcu,
+        protocol,
+        signature,
+        page_size,
+        num_pages,
+        flash_size: page_size as u32 * num_pages as u32,
+        eeprom_page_size,
+        eeprom_size,
+        default_baud,
+        product_ids,
+        touch_reset,
+    }
+}
+
+/// A board that isn't in the Microcontroller enum, described by the same
+/// details avrman keeps for the boards it supports
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct McuProfile {
+    pub name: String,
+    pub signature: Vec<u8>,
+    pub page_size: u16,
+    pub num_pages: u16,
+    pub product_ids: Vec<u16>,
+    pub default_baud: u32,
+    pub protocol: ProtocolKind,
+}
+
+impl McuProfile {
+    /// Read board profiles from a TOML file with one `[[board]]` table per
+    /// board, eg:
+    ///
+    /// ```toml
+    /// [[board]]
+    /// name = "my-board"
+    /// signature = "1e9406"
+    /// page_size = 128
+    /// num_pages = 128
+    /// baud = 19200
+    /// product_ids = [0x6001]
+    /// ```
+    ///
+    /// `protocol` may be set to "stk500v2", and defaults to "stk500v1"
+    pub fn from_toml_file(path: &str) -> AvrResult<Vec<McuProfile>> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            AvrError::ConfigurationError(format!("Failed to read board file {}: {}", path, e))
+        })?;
+
+        Self::from_toml(&content)
+    }
+
+    /// Same as from_toml_file, for TOML already in memory
+    pub fn from_toml(content: &str) -> AvrResult<Vec<McuProfile>> {
+        let file: BoardsFile = toml::from_str(content)
+            .map_err(|e| AvrError::ConfigurationError(format!("Invalid board file: {}", e)))?;
+
+        file.board
+            .into_iter()
+            .map(|entry| {
+                let signature = parse_signature(&entry.signature).map_err(|e| {
+                    AvrError::ConfigurationError(format!("Board {}: {}", entry.name, e))
+                })?;
+                validate_custom_board(&signature, entry.page_size, entry.num_pages)?;
+
+                Ok(McuProfile {
+                    name: entry.name,
+                    signature,
+                    page_size: entry.page_size,
+                    num_pages: entry.num_pages,
+                    product_ids: entry.product_ids,
+                    default_baud: entry.baud,
+                    protocol: entry.protocol,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Layout of a boards TOML file
+#[derive(Deserialize)]
+struct BoardsFile {
+    #[serde(default)]
+    board: Vec<BoardEntry>,
+}
+
+#[derive(Deserialize)]
+struct BoardEntry {
+    name: String,
+    signature: String,
+    page_size: u16,
+    num_pages: u16,
+    baud: u32,
+    #[serde(default)]
+    product_ids: Vec<u16>,
+    #[serde(default = "default_board_protocol")]
+    protocol: ProtocolKind,
+}
+
+fn default_board_protocol() -> ProtocolKind {
+    ProtocolKind::Stk500v1
+}
+
+/// Parse a signature written as hex digits, with an optional 0x prefix and
+/// optional spaces between bytes (eg: "1e9406", "0x1e9406" or "1e 94 06")
+fn parse_signature(text: &str) -> Result<Vec<u8>, String> {
+    let digits: String = text
+        .trim()
+        .trim_start_matches("0x")
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+
+    if digits.len() != 6 {
+        return Err(format!(
+            "signature {:?} must be exactly three bytes of hex",
+            text
+        ));
+    }
+    decode_hex(digits.as_bytes()).ok_or_else(|| format!("signature {:?} is not valid hex", text))
+}
+
+/// Specs of every supported board
+pub fn board_specs() -> Vec<BoardSpec> {
+    Microcontroller::value_variants()
+        .iter()
+        .map(|mcu| board_spec(mcu.clone()))
+        .collect()
+}
+
+/// Supported MCU with the given signature. Boards built around the same chip
+/// share a signature (Uno and Nano are both an ATmega328p, Leonardo is an
+/// ATmega32u4), in which case the
+/// bare chip is returned as it makes no assumptions about the board
+pub fn mcu_for_signature(signature: &[u8]) -> AvrResult<Microcontroller> {
+    let matches: Vec<Microcontroller> = board_specs()
+        .into_iter()
+        .filter(|spec| spec.signature == signature)
+        .map(|spec| spec.mcu)
+        .collect();
+
+    matches
+        .iter()
+        .find(|mcu| {
+            matches!(
+                mcu,
+                Microcontroller::Atmega328p | Microcontroller::Atmega32u4
+            )
+        })
+        .or(matches.first())
+        .cloned()
+        .ok_or_else(|| {
+            AvrError::ConfigurationError(format!(
+                "Unrecognized device signature {:02x?}",
+                signature
+            ))
+        })
+}
+
+/// Best guess at the board behind a USB product ID, going by the first
+/// supported board that lists it. Clones sharing a USB chip can't be told
+/// apart this way, syncing and reading the signature is needed for that
+pub fn mcu_for_product_id(pid: u16) -> Option<Microcontroller> {
+    board_specs()
+        .into_iter()
+        .find(|spec| spec.product_ids.contains(&pid))
+        .map(|spec| spec.mcu)
+}
+
+/// Serialize the spec of every supported board to JSON
+pub fn board_specs_json() -> String {
+    serde_json::to_string_pretty(&board_specs()).expect("Board specs are always serializable")
 }
 
 /// Figure out the protocol and all associated parameters for a given MCU
@@ -33,137 +342,603 @@ pub fn protocol_for_mcu(
     mcu: Microcontroller,
     interface_type: Option<DeviceInterfaceType>,
 ) -> AvrResult<ProtocolType> {
-    match mcu {
-        Microcontroller::ArduinoUno | Microcontroller::Atmega328p => {
-            let default_baud_rate = 115200;
-            let signature = vec![0x1e, 0x95, 0x0f];
-            let page_size = 128;
-            let num_pages = 256;
-            let product_id = vec![0x0043, 0x7523, 0x0001, 0xea60, 0x6015];
-
-            let (port, baud) = match interface_type {
-                Some(interface) => {
-                    let DeviceInterfaceType::Serial(params) = interface;
-                    let port = params
-                        .port
-                        .unwrap_or(serial_port_from_product_id(&product_id)?);
-                    (port, params.baud.unwrap_or(default_baud_rate))
-                }
-                None => {
-                    // Default baud rate when none is provided
-                    let baud = default_baud_rate;
-
-                    // Try to find the serial port using product_id
-                    let port = serial_port_from_product_id(&product_id)?;
-
-                    (port, baud)
-                }
-            };
-
-            Ok(ProtocolType::Stk500v1(Stk500v1Params {
-                port,
-                baud,
-                device_signature: signature,
-                page_size,
-                num_pages,
-                product_id,
-            }))
-        }
-        Microcontroller::ArduinoNano => {
-            let default_baud_rate = 57600;
-            let signature = vec![0x1e, 0x95, 0x0f];
-            let page_size = 128;
-            let num_pages = 256;
-            let product_id = vec![0x6001, 0x7523];
-
-            let (port, baud) = match interface_type {
-                Some(interface) => {
-                    let DeviceInterfaceType::Serial(params) = interface;
-                    let port = params
-                        .port
-                        .unwrap_or(serial_port_from_product_id(&product_id)?);
-                    (port, params.baud.unwrap_or(default_baud_rate))
-                }
-                None => {
-                    // Default baud rate when none is provided
-                    let baud = default_baud_rate;
-
-                    // Try to find the serial port using product_id
-                    let port = serial_port_from_product_id(&product_id)?;
-
-                    (port, baud)
-                }
-            };
-
-            Ok(ProtocolType::Stk500v1(Stk500v1Params {
-                port,
-                baud,
-                device_signature: signature,
-                page_size,
-                num_pages,
-                product_id,
-            }))
-        }
-        Microcontroller::ArduinoMega => {
-            let default_baud_rate = 115200;
-            let signature = vec![0x1e, 0x98, 0x01];
-            let page_size = 256;
-            let product_id = vec![0x6001, 0x7523];
-
-            let (port, baud) = match interface_type {
-                Some(interface) => {
-                    let DeviceInterfaceType::Serial(params) = interface;
-                    let port = params
-                        .port
-                        .unwrap_or(serial_port_from_product_id(&product_id)?);
-                    (port, params.baud.unwrap_or(default_baud_rate))
-                }
-                None => {
-                    // Default baud rate when none is provided
-                    let baud = default_baud_rate;
-
-                    // Try to find the serial port using product_id
-                    let port = serial_port_from_product_id(&product_id)?;
-
-                    (port, baud)
-                }
-            };
-
-            Ok(ProtocolType::Stk500v2(Stk500v2Params {
-                port,
-                baud,
-                device_signature: signature,
-                page_size,
-                product_id,
-            }))
+    // The ATtiny85 has no bootloader, it's programmed through an Arduino as
+    // ISP, which has to erase the chip before writing
+    let isp_mode = matches!(mcu, Microcontroller::Attiny85);
+    let spec = board_spec(mcu);
+    let profile = McuProfile {
+        name: format!("{:?}", spec.mcu),
+        signature: spec.signature,
+        page_size: spec.page_size,
+        num_pages: spec.num_pages,
+        product_ids: spec.product_ids,
+        default_baud: spec.default_baud,
+        protocol: spec.protocol,
+    };
+
+    let mut protocol = board_protocol(
+        profile,
+        spec.eeprom_page_size,
+        spec.eeprom_size,
+        spec.touch_reset,
+        interface_type,
+    )?;
+    if let ProtocolType::Stk500v1(params) = &mut protocol {
+        params.isp_mode = isp_mode;
+    }
+    Ok(protocol)
+}
+
+/// Same as protocol_for_mcu, for a board described by a profile instead of
+/// the Microcontroller enum. The flash geometry is validated first, and the
+/// EEPROM geometry is the protocol's usual default
+pub fn protocol_for_profile(
+    profile: McuProfile,
+    interface_type: Option<DeviceInterfaceType>,
+) -> AvrResult<ProtocolType> {
+    validate_custom_board(&profile.signature, profile.page_size, profile.num_pages)?;
+
+    let (eeprom_page_size, eeprom_size) = match profile.protocol {
+        ProtocolKind::Stk500v1 => (
+            DEFAULT_STK500V1_EEPROM_PAGE_SIZE,
+            DEFAULT_STK500V1_EEPROM_SIZE,
+        ),
+        ProtocolKind::Stk500v2 => (
+            DEFAULT_STK500V2_EEPROM_PAGE_SIZE,
+            DEFAULT_STK500V2_EEPROM_SIZE,
+        ),
+    };
+    board_protocol(
+        profile,
+        eeprom_page_size,
+        eeprom_size,
+        false,
+        interface_type,
+    )
+}
+
+fn board_protocol(
+    profile: McuProfile,
+    eeprom_page_size: u16,
+    eeprom_size: usize,
+    touch_reset: bool,
+    interface_type: Option<DeviceInterfaceType>,
+) -> AvrResult<ProtocolType> {
+    let (port, baud) = match interface_type {
+        Some(DeviceInterfaceType::Serial(params)) => (params.port, params.baud),
+        // Only used to name the board in messages, the connection is made
+        // by the programmer
+        Some(
+            DeviceInterfaceType::Tcp { host, port } | DeviceInterfaceType::Rfc2217 { host, port },
+        ) => (Some(format!("{}:{}", host, port)), None),
+        None => (None, None),
+    };
+
+    // Try to find the serial port using product_id when none is provided
+    let (port, pid) = match port {
+        Some(port) => (port, None),
+        None => {
+            let (port, pid) = serial_port_from_product_id(&profile.product_ids)?;
+            (port, Some(pid))
         }
+    };
+    let baud = baud.unwrap_or(profile.default_baud);
+    let (reset_line, reset_timing) = if touch_reset {
+        (ResetLine::Touch1200, ResetTiming::default())
+    } else {
+        reset_for_adapter(pid)
+    };
+
+    match profile.protocol {
+        ProtocolKind::Stk500v1 => Ok(ProtocolType::Stk500v1(Stk500v1Params {
+            port,
+            baud,
+            device_signature: profile.signature,
+            page_size: profile.page_size,
+            num_pages: profile.num_pages,
+            product_id: profile.product_ids,
+            eeprom_page_size,
+            eeprom_size,
+            reset_line,
+            reset_timing,
+            ..Default::default()
+        })),
+        ProtocolKind::Stk500v2 => Ok(ProtocolType::Stk500v2(Stk500v2Params {
+            port,
+            baud,
+            device_signature: profile.signature,
+            page_size: profile.page_size,
+            num_pages: profile.num_pages,
+            product_id: profile.product_ids,
+            eeprom_page_size,
+            eeprom_size,
+            reset_line,
+            reset_timing,
+            ..Default::default()
+        })),
     }
 }
 
-pub(crate) fn serial_port_from_product_id(product_ids: &Vec<u16>) -> AvrResult<String> {
-    match serialport::available_ports() {
-        Ok(ports) => {
-            for port in ports {
-                if let serialport::SerialPortType::UsbPort(info) = port.port_type {
-                    for pid in product_ids {
-                        if *pid == info.pid {
-                            return Ok(port.port_name);
-                        }
-                    }
-                }
-            }
+/// Build protocol parameters for a board that speaks STK500v2 but has no
+/// entry in the Microcontroller enum. The flash geometry is validated up front
+/// so that mistakes are reported before the serial port is opened
+pub fn custom_stk500v2_protocol(
+    port: &str,
+    baud: u32,
+    signature: Vec<u8>,
+    page_size: u16,
+    num_pages: u16,
+) -> AvrResult<ProtocolType> {
+    validate_custom_board(&signature, page_size, num_pages)?;
+
+    Ok(ProtocolType::Stk500v2(Stk500v2Params {
+        port: port.to_owned(),
+        baud,
+        device_signature: signature,
+        page_size,
+        num_pages,
+        ..Default::default()
+    }))
+}
+
+/// Sanity checks for user provided board parameters. AVR parts have a three
+/// byte signature, a power of two page size and at most 256KB of flash
+pub(crate) fn validate_custom_board(
+    signature: &[u8],
+    page_size: u16,
+    num_pages: u16,
+) -> AvrResult<()> {
+    if signature.len() != 3 {
+        return Err(AvrError::ConfigurationError(format!(
+            "Device signature must be exactly 3 bytes, got {:?}",
+            signature
+        )));
+    }
+
+    if page_size == 0 || !page_size.is_power_of_two() {
+        return Err(AvrError::ConfigurationError(format!(
+            "Page size must be a non-zero power of two, got {}",
+            page_size
+        )));
+    }
+
+    let flash_size = page_size as u32 * num_pages as u32;
+    if num_pages == 0 || flash_size > MAX_FLASH_SIZE {
+        return Err(AvrError::ConfigurationError(format!(
+            "Flash geometry of {} pages x {} bytes is not valid for an AVR part",
+            num_pages, page_size
+        )));
+    }
+
+    Ok(())
+}
+
+/// Reset settings for the USB-serial adapter with the given product ID.
+/// CH340 clones reset unreliably with the default pulse and can misbehave when
+/// RTS is toggled, so they get DTR only with a longer pulse and boot delay.
+/// Pass explicit protocol parameters to override this
+pub(crate) fn reset_for_adapter(pid: Option<u16>) -> (ResetLine, ResetTiming) {
+    match pid {
+        Some(CH340_PID) => {
+            info!("Applied CH340 reset preset");
+            (
+                ResetLine::Dtr,
+                ResetTiming {
+                    low_micros: CH340_RESET_LOW_MICROS,
+                    bootup_delay_ms: CH340_BOOTUP_DELAY_MS,
+                },
+            )
         }
-        Err(e) => {
-            return Err(AvrError::ConfigurationError(format!(
-                "Could not get available ports. Err {:?}",
-                e
-            )));
+        _ => (ResetLine::default(), ResetTiming::default()),
+    }
+}
+
+/// A USB serial port a board could be attached to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortInfo {
+    pub name: String,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub product: Option<String>,
+}
+
+/// List the USB serial ports currently present, for presenting a choice of
+/// ports to the user. Built-in, PCI and Bluetooth ports are left out
+pub fn list_ports() -> AvrResult<Vec<PortInfo>> {
+    let ports = serialport::available_ports().map_err(|e| {
+        AvrError::ConfigurationError(format!("Could not get available ports. Err {:?}", e))
+    })?;
+
+    Ok(usb_ports(ports))
+}
+
+fn usb_ports(ports: Vec<SerialPortInfo>) -> Vec<PortInfo> {
+    ports
+        .into_iter()
+        .filter_map(|port| match port.port_type {
+            SerialPortType::UsbPort(usb) => Some(PortInfo {
+                name: port.port_name,
+                vid: Some(usb.vid),
+                pid: Some(usb.pid),
+                product: usb.product,
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Find the first USB serial port with one of the given product IDs, returning
+/// its name and the product ID that matched
+pub(crate) fn serial_port_from_product_id(product_ids: &[u16]) -> AvrResult<(String, u16)> {
+    let ports = serialport::available_ports().map_err(|e| {
+        AvrError::ConfigurationError(format!("Could not get available ports. Err {:?}", e))
+    })?;
+
+    port_with_product_id(ports, product_ids)
+}
+
+/// First USB port in `ports` whose product ID is one of `product_ids`
+fn port_with_product_id(
+    ports: Vec<SerialPortInfo>,
+    product_ids: &[u16],
+) -> AvrResult<(String, u16)> {
+    for port in ports {
+        if let SerialPortType::UsbPort(info) = port.port_type
+            && product_ids.contains(&info.pid)
+        {
+            return Ok((port.port_name, info.pid));
         }
+    }
+
+    Err(AvrError::DeviceNotFound {
+        product_ids: product_ids.to_vec(),
+    })
+}
+
+/// Find a port matching one of the product IDs that is present in `after` but
+/// wasn't in `before`. If nothing new showed up but `failed_port` is gone, any
+/// other matching port is taken as the board's new home
+pub(crate) fn newly_appeared_port(
+    failed_port: &str,
+    before: &[SerialPortInfo],
+    after: &[SerialPortInfo],
+    product_ids: &[u16],
+) -> Option<String> {
+    let matches = |info: &SerialPortInfo| match &info.port_type {
+        SerialPortType::UsbPort(usb) => product_ids.contains(&usb.pid),
+        _ => false,
     };
 
+    let candidates: Vec<&SerialPortInfo> = after
+        .iter()
+        .filter(|info| matches(info) && info.port_name != failed_port)
+        .collect();
+
+    let newly_appeared = candidates
+        .iter()
+        .find(|info| !before.iter().any(|b| b.port_name == info.port_name));
+    if let Some(info) = newly_appeared {
+        return Some(info.port_name.clone());
+    }
+
+    let failed_port_gone = !after.iter().any(|info| info.port_name == failed_port);
+    if failed_port_gone {
+        candidates.first().map(|info| info.port_name.clone())
+    } else {
+        None
+    }
+}
+
+/// Poll the available ports until a board that vanished from `failed_port`
+/// shows up on a new port, which happens when native USB boards re-enumerate
+pub(crate) fn wait_for_reenumerated_port(
+    failed_port: &str,
+    before: &[SerialPortInfo],
+    product_ids: &[u16],
+) -> AvrResult<String> {
+    let deadline =
+        std::time::Instant::now() + std::time::Duration::from_millis(REENUMERATION_TIMEOUT_MS);
+
+    while std::time::Instant::now() < deadline {
+        let after = serialport::available_ports().map_err(|e| {
+            AvrError::ConfigurationError(format!("Could not get available ports. Err {:?}", e))
+        })?;
+
+        if let Some(port) = newly_appeared_port(failed_port, before, &after, product_ids) {
+            return Ok(port);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(REENUMERATION_POLL_MS));
+    }
+
     Err(AvrError::ConfigurationError(format!(
-        "Looked at all available serial ports; could not find one that matches one of 
-        product IDs {:?}. Try specifying a serial port for the given MCU?",
-        product_ids
+        "Could not open {} and no new port with product IDs {:?} appeared",
+        failed_port, product_ids
     )))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serialport::UsbPortInfo;
+
+    fn usb_port(name: &str, pid: u16) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x2341,
+                pid,
+                serial_number: None,
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_shared_signature_resolves_to_bare_chip() {
+        assert_eq!(
+            mcu_for_signature(&[0x1e, 0x95, 0x0f]).unwrap(),
+            Microcontroller::Atmega328p
+        );
+        assert_eq!(
+            mcu_for_signature(&[0x1e, 0x95, 0x87]).unwrap(),
+            Microcontroller::Atmega32u4
+        );
+        assert_eq!(
+            mcu_for_signature(&[0x1e, 0x98, 0x01]).unwrap(),
+            Microcontroller::ArduinoMega
+        );
+    }
+
+    #[test]
+    fn test_attiny85_uses_stk500v1_with_small_pages() {
+        let spec = board_spec(Microcontroller::Attiny85);
+
+        assert_eq!(spec.protocol, ProtocolKind::Stk500v1);
+        assert_eq!(spec.flash_size, 8 * 1024);
+        assert_eq!(
+            mcu_for_signature(&[0x1e, 0x93, 0x0b]).unwrap(),
+            Microcontroller::Attiny85
+        );
+    }
+
+    #[test]
+    fn test_product_id_guesses_board() {
+        assert_eq!(
+            mcu_for_product_id(PRO_MICRO_BOOTLOADER_PID),
+            Some(Microcontroller::Atmega32u4)
+        );
+        assert_eq!(
+            mcu_for_product_id(LEONARDO_SKETCH_PID),
+            Some(Microcontroller::ArduinoLeonardo)
+        );
+        assert_eq!(mcu_for_product_id(0xdead), None);
+    }
+
+    #[test]
+    fn test_unknown_signature_is_listed_in_error() {
+        match mcu_for_signature(&[0x1e, 0x94, 0x06]) {
+            Err(AvrError::ConfigurationError(message)) => assert!(message.contains("1e, 94, 06")),
+            result => panic!("Expected a configuration error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_only_usb_ports_are_listed() {
+        let ports = vec![
+            SerialPortInfo {
+                port_name: "/dev/ttyS0".to_string(),
+                port_type: SerialPortType::PciPort,
+            },
+            usb_port("/dev/ttyACM0", 0x0043),
+        ];
+
+        assert_eq!(
+            usb_ports(ports),
+            vec![PortInfo {
+                name: "/dev/ttyACM0".to_string(),
+                vid: Some(0x2341),
+                pid: Some(0x0043),
+                product: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_missing_board_is_device_not_found() {
+        let ports = vec![usb_port("/dev/ttyUSB0", 0x6001)];
+
+        assert_eq!(
+            port_with_product_id(ports.clone(), &[0x7523, 0x6001]).unwrap(),
+            ("/dev/ttyUSB0".to_string(), 0x6001)
+        );
+        match port_with_product_id(ports, &[0x0043]) {
+            Err(AvrError::DeviceNotFound { product_ids }) => assert_eq!(product_ids, vec![0x0043]),
+            result => panic!("Expected DeviceNotFound, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_newly_appeared_port_is_tried() {
+        let before = vec![
+            usb_port("/dev/ttyACM0", 0x8036),
+            usb_port("/dev/ttyUSB0", 0x6001),
+        ];
+        let after = vec![
+            usb_port("/dev/ttyUSB0", 0x6001),
+            usb_port("/dev/ttyACM1", 0x0036),
+        ];
+
+        let port = newly_appeared_port("/dev/ttyACM0", &before, &after, &[0x8036, 0x0036]);
+        assert_eq!(port, Some("/dev/ttyACM1".to_string()));
+    }
+
+    #[test]
+    fn test_no_port_while_original_is_still_there() {
+        let before = vec![
+            usb_port("/dev/ttyACM0", 0x8036),
+            usb_port("/dev/ttyACM1", 0x8036),
+        ];
+        let after = before.clone();
+
+        let port = newly_appeared_port("/dev/ttyACM0", &before, &after, &[0x8036]);
+        assert_eq!(port, None);
+    }
+
+    #[test]
+    fn test_board_specs_json_round_trips() {
+        let json = board_specs_json();
+        let specs: Vec<BoardSpec> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(specs, board_specs());
+        assert!(json.contains("\"mcu\": \"arduino-uno\""));
+        assert!(json.contains("\"protocol\": \"stk500v2\""));
+    }
+
+    #[test]
+    fn test_ch340_reset_preset_is_selected() {
+        let (line, timing) = reset_for_adapter(Some(0x7523));
+        assert_eq!(line, ResetLine::Dtr);
+        assert_eq!(timing.low_micros, CH340_RESET_LOW_MICROS);
+        assert_eq!(timing.bootup_delay_ms, CH340_BOOTUP_DELAY_MS);
+
+        let (line, timing) = reset_for_adapter(Some(0x0043));
+        assert_eq!(line, ResetLine::Both);
+        assert_eq!(timing, ResetTiming::default());
+
+        let (line, _) = reset_for_adapter(None);
+        assert_eq!(line, ResetLine::Both);
+    }
+
+    #[test]
+    fn test_leonardo_uses_touch_reset() {
+        let interface = DeviceInterfaceType::Serial(crate::interface::SerialportParams {
+            port: Some(String::from("/dev/ttyACM0")),
+            baud: None,
+        });
+
+        match protocol_for_mcu(Microcontroller::ArduinoLeonardo, Some(interface)).unwrap() {
+            ProtocolType::Stk500v1(params) => {
+                assert_eq!(params.reset_line, ResetLine::Touch1200);
+                assert_eq!(params.device_signature, vec![0x1e, 0x95, 0x87]);
+            }
+            _ => panic!("Expected Stk500v1 parameters"),
+        }
+    }
+
+    fn custom_profile() -> McuProfile {
+        McuProfile {
+            name: String::from("my-board"),
+            signature: vec![0x1e, 0x94, 0x06],
+            page_size: 128,
+            num_pages: 128,
+            product_ids: vec![0x6001],
+            default_baud: 19200,
+            protocol: ProtocolKind::Stk500v1,
+        }
+    }
+
+    #[test]
+    fn test_profile_becomes_stk500v1_parameters() {
+        let interface = DeviceInterfaceType::Serial(crate::interface::SerialportParams {
+            port: Some(String::from("/dev/ttyUSB0")),
+            baud: None,
+        });
+
+        match protocol_for_profile(custom_profile(), Some(interface)).unwrap() {
+            ProtocolType::Stk500v1(params) => {
+                assert_eq!(params.port, "/dev/ttyUSB0");
+                assert_eq!(params.baud, 19200);
+                assert_eq!(params.device_signature, vec![0x1e, 0x94, 0x06]);
+                assert_eq!(params.page_size, 128);
+                assert_eq!(params.num_pages, 128);
+                assert_eq!(params.eeprom_page_size, DEFAULT_STK500V1_EEPROM_PAGE_SIZE);
+                assert_eq!(params.eeprom_size, DEFAULT_STK500V1_EEPROM_SIZE);
+            }
+            _ => panic!("Expected Stk500v1 parameters"),
+        }
+    }
+
+    #[test]
+    fn test_profile_with_bad_geometry_is_refused() {
+        let profile = McuProfile {
+            page_size: 100,
+            ..custom_profile()
+        };
+
+        assert!(matches!(
+            protocol_for_profile(profile, None),
+            Err(AvrError::ConfigurationError(_))
+        ));
+    }
+
+    const BOARDS_TOML: &str = r#"
+        [[board]]
+        name = "my-board"
+        signature = "1e9406"
+        page_size = 128
+        num_pages = 128
+        baud = 19200
+        product_ids = [0x6001]
+
+        [[board]]
+        name = "my-mega"
+        signature = "0x1e 98 01"
+        page_size = 256
+        num_pages = 1024
+        baud = 115200
+        protocol = "stk500v2"
+    "#;
+
+    #[test]
+    fn test_profiles_are_read_from_toml() {
+        let profiles = McuProfile::from_toml(BOARDS_TOML).unwrap();
+
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(
+            profiles[0],
+            McuProfile {
+                default_baud: 19200,
+                ..custom_profile()
+            }
+        );
+        assert_eq!(profiles[1].signature, vec![0x1e, 0x98, 0x01]);
+        assert_eq!(profiles[1].protocol, ProtocolKind::Stk500v2);
+        assert!(profiles[1].product_ids.is_empty());
+    }
+
+    #[test]
+    fn test_toml_signature_must_be_three_bytes() {
+        let toml = r#"
+            [[board]]
+            name = "short"
+            signature = "1e94"
+            page_size = 128
+            num_pages = 128
+            baud = 19200
+        "#;
+
+        match McuProfile::from_toml(toml) {
+            Err(AvrError::ConfigurationError(message)) => assert!(message.contains("short")),
+            result => panic!("Expected a configuration error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_toml_signature_with_non_ascii_is_refused() {
+        // Six bytes long, like three bytes of hex, but '€' isn't hex
+        let toml = r#"
+            [[board]]
+            name = "euro"
+            signature = "1e9€"
+            page_size = 128
+            num_pages = 128
+            baud = 19200
+        "#;
+
+        match McuProfile::from_toml(toml) {
+            Err(AvrError::ConfigurationError(message)) => assert!(message.contains("euro")),
+            result => panic!("Expected a configuration error, got {:?}", result),
+        }
+    }
+}
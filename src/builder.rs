@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use crate::error::{AvrError, AvrResult};
+use crate::interface::{DeviceInterfaceType, SerialportParams};
+use crate::{Microcontroller, Programmer};
+
+/// Step by step configuration of a Programmer for a supported board. Only
+/// the microcontroller is required; without a port the board is looked up
+/// by its USB product ID, as Programmer::new does
+///
+/// ```no_run
+/// use avrman::{Microcontroller, builder::ProgrammerBuilder};
+///
+/// let programmer = ProgrammerBuilder::new()
+///     .mcu(Microcontroller::ArduinoUno)
+///     .port("/dev/ttyUSB0")
+///     .retries(2)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProgrammerBuilder {
+    mcu: Option<Microcontroller>,
+    port: Option<String>,
+    baud: Option<u32>,
+    verify: bool,
+    progress: bool,
+    timeout: Option<Duration>,
+    retries: u32,
+}
+
+impl Default for ProgrammerBuilder {
+    fn default() -> Self {
+        ProgrammerBuilder {
+            mcu: None,
+            port: None,
+            baud: None,
+            verify: true,
+            progress: false,
+            timeout: None,
+            retries: 0,
+        }
+    }
+}
+
+impl ProgrammerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Board or chip to program
+    pub fn mcu(mut self, mcu: Microcontroller) -> Self {
+        self.mcu = Some(mcu);
+        self
+    }
+
+    /// Serial port the board is attached to
+    pub fn port(mut self, port: impl Into<String>) -> Self {
+        self.port = Some(port.into());
+        self
+    }
+
+    /// Baud rate, instead of the board's usual one
+    pub fn baud(mut self, baud: u32) -> Self {
+        self.baud = Some(baud);
+        self
+    }
+
+    /// Read flash back after programming (on by default)
+    pub fn verify(mut self, enable: bool) -> Self {
+        self.verify = enable;
+        self
+    }
+
+    /// Show a progress bar on the terminal (off by default)
+    pub fn progress(mut self, enable: bool) -> Self {
+        self.progress = enable;
+        self
+    }
+
+    /// How long each serial read waits for data, see
+    /// Programmer::with_timeout_ms
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Extra attempts at a failed programming run, see Programmer::set_retries
+    pub fn retries(mut self, n: u32) -> Self {
+        self.retries = n;
+        self
+    }
+
+    /// Open the port and set up the programmer
+    pub fn build(self) -> AvrResult<Programmer> {
+        let mcu = self.mcu.ok_or_else(|| {
+            AvrError::ConfigurationError(String::from(
+                "No microcontroller given, set one with ProgrammerBuilder::mcu",
+            ))
+        })?;
+
+        let mut programmer = if self.port.is_some() || self.baud.is_some() {
+            Programmer::from_mcu_and_interface(
+                mcu,
+                DeviceInterfaceType::Serial(SerialportParams {
+                    port: self.port,
+                    baud: self.baud,
+                }),
+            )?
+        } else {
+            Programmer::new(mcu)?
+        };
+        if let Some(timeout) = self.timeout {
+            programmer = programmer.with_timeout_ms(timeout.as_millis() as u64)?;
+        }
+
+        programmer.verify_after_programming(self.verify);
+        programmer.progress_bar(self.progress);
+        programmer.set_retries(self.retries);
+        Ok(programmer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_without_mcu_is_refused() {
+        let result = ProgrammerBuilder::new().port("/dev/ttyUSB0").build();
+        assert!(matches!(result, Err(AvrError::ConfigurationError(_))));
+    }
+}
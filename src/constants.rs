@@ -1,6 +1,86 @@
 pub(crate) const SERIAL_TIMEOUT_MS: u64 = 1;
 pub(crate) const TRANSPORT_THREAD_SLEEP_MICROS: u64 = 100;
+/// Empty reads the receiver thread retries straight away before sleeping
+/// TRANSPORT_THREAD_SLEEP_MICROS between reads
+pub(crate) const TRANSPORT_IDLE_SPINS: u32 = 64;
 pub(crate) const MAX_RESPONSE_SIZE: usize = 1024;
+pub(crate) const COMMAND_CHANNEL_CAPACITY: usize = 16;
+pub(crate) const RESPONSE_CHANNEL_CAPACITY: usize = 64;
+/// How long a response may keep the caller waiting when no deadline was set
+/// for the operation, so an unresponsive board can't hang a call forever
+pub(crate) const DEFAULT_RESPONSE_TIMEOUT_MS: u64 = 2000;
+/// How often a caller waiting for a response checks for an error in the
+/// transport threads
+pub(crate) const THREAD_ERROR_POLL_MS: u64 = 10;
 
 pub(crate) const RESET_DTR_RTS_LOW_MICROS: u64 = 100;
 pub(crate) const POST_RESET_BOOTUP_DELAY_MS: u64 = 250;
+
+/// CH340 USB-serial adapters
+pub(crate) const CH340_PID: u16 = 0x7523;
+pub(crate) const CH340_RESET_LOW_MICROS: u64 = 50_000;
+pub(crate) const CH340_BOOTUP_DELAY_MS: u64 = 300;
+
+/// Opening and closing the port at this baud rate sends native USB boards
+/// (ATmega32u4) to their bootloader
+pub(crate) const TOUCH_RESET_BAUD: u32 = 1200;
+
+/// Leonardo USB product IDs, in the bootloader and running a sketch
+pub(crate) const LEONARDO_BOOTLOADER_PID: u16 = 0x0036;
+pub(crate) const LEONARDO_SKETCH_PID: u16 = 0x8036;
+
+/// SparkFun Pro Micro (5V) USB product IDs, in the bootloader and running a sketch
+pub(crate) const PRO_MICRO_BOOTLOADER_PID: u16 = 0x9205;
+pub(crate) const PRO_MICRO_SKETCH_PID: u16 = 0x9206;
+
+/// How long to wait for a board to show up on a new port after it vanished
+pub(crate) const REENUMERATION_TIMEOUT_MS: u64 = 3000;
+pub(crate) const REENUMERATION_POLL_MS: u64 = 100;
+
+/// How long an RFC 2217 server gets to accept serial port control
+pub(crate) const RFC2217_NEGOTIATION_TIMEOUT_MS: u64 = 500;
+
+/// Chip erase takes up to ~10ms on most parts, give it plenty of room
+pub(crate) const CHIP_ERASE_POLL_ATTEMPTS: u32 = 50;
+pub(crate) const CHIP_ERASE_POLL_INTERVAL_MS: u64 = 10;
+/// A page write takes about 4.5ms, polled in 1ms steps
+pub(crate) const PAGE_WRITE_POLL_ATTEMPTS: u32 = 20;
+pub(crate) const PAGE_WRITE_POLL_INTERVAL_MS: u64 = 1;
+
+/// Sync attempts before giving up, for bootloaders that are slow to start
+pub(crate) const DEFAULT_SYNC_ATTEMPTS: u32 = 10;
+pub(crate) const DEFAULT_SYNC_RETRY_DELAY_MS: u64 = 50;
+
+/// How long a single sync attempt waits for the bootloader to answer
+pub(crate) const SYNC_RESPONSE_TIMEOUT_MS: u64 = 200;
+
+/// Leaving programming mode is retried this many times in total
+pub(crate) const LEAVE_PROGMODE_ATTEMPTS: u32 = 3;
+pub(crate) const LEAVE_PROGMODE_RETRY_DELAY_MS: u64 = 20;
+
+/// Largest flash of any AVR part (ATmega2560/ATmega2561)
+pub(crate) const MAX_FLASH_SIZE: u32 = 256 * 1024;
+
+/// EEPROM page size assumed for custom STK500v1 boards (the ATmega328p's)
+pub(crate) const DEFAULT_STK500V1_EEPROM_PAGE_SIZE: u16 = 4;
+
+/// EEPROM page size assumed for custom STK500v2 boards (the ATmega2560's)
+pub(crate) const DEFAULT_STK500V2_EEPROM_PAGE_SIZE: u16 = 8;
+
+/// EEPROM size assumed for custom STK500v1 boards (the ATmega328p's)
+pub(crate) const DEFAULT_STK500V1_EEPROM_SIZE: usize = 1024;
+
+/// EEPROM size assumed for custom STK500v2 boards (the ATmega2560's)
+pub(crate) const DEFAULT_STK500V2_EEPROM_SIZE: usize = 4096;
+
+/// Trailing 0xFF runs at least this long are trimmed from flash dumps
+pub(crate) const DEFAULT_DUMP_TRIM_THRESHOLD: usize = 1;
+
+/// Number of page transfer rates averaged into the reported bytes/sec
+pub(crate) const PROGRESS_RATE_WINDOW: usize = 4;
+
+/// Intel hex for a full 256KB flash is a bit under 720KB
+#[cfg(feature = "http")]
+pub(crate) const HTTP_MAX_FIRMWARE_SIZE: u64 = 1024 * 1024;
+#[cfg(feature = "http")]
+pub(crate) const HTTP_TIMEOUT_SECS: u64 = 30;
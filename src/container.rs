@@ -0,0 +1,128 @@
+use crate::error::{AvrError, AvrResult};
+
+/// Marks the start of an avrman firmware container
+pub const CONTAINER_MAGIC: [u8; 4] = *b"AVRM";
+pub const CONTAINER_VERSION: u8 = 1;
+
+/// Magic, version, signature, payload length and CRC
+const HEADER_SIZE: usize = 4 + 1 + 3 + 4 + 4;
+
+/// Self-describing firmware image for field updates. On the wire it is the
+/// magic, a version byte, the target's three signature bytes, the payload
+/// length and the CRC-32 of the payload (both little endian), then the payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Container {
+    pub signature: [u8; 3],
+    pub payload: Vec<u8>,
+}
+
+impl Container {
+    pub fn new(signature: [u8; 3], payload: Vec<u8>) -> Self {
+        Container { signature, payload }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        bytes.extend_from_slice(&CONTAINER_MAGIC);
+        bytes.push(CONTAINER_VERSION);
+        bytes.extend_from_slice(&self.signature);
+        bytes.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&crc32(&self.payload).to_le_bytes());
+        bytes.extend_from_slice(&self.payload);
+
+        bytes
+    }
+
+    /// Parse a container, refusing unknown versions, truncated or padded
+    /// files and payloads whose CRC doesn't match
+    pub fn decode(bytes: &[u8]) -> AvrResult<Self> {
+        if bytes.len() < HEADER_SIZE || bytes[..4] != CONTAINER_MAGIC {
+            return Err(AvrError::FirmwareError(String::from(
+                "Not an avrman firmware container",
+            )));
+        }
+
+        let version = bytes[4];
+        if version != CONTAINER_VERSION {
+            return Err(AvrError::FirmwareError(format!(
+                "Unsupported firmware container version {}",
+                version
+            )));
+        }
+
+        let signature = [bytes[5], bytes[6], bytes[7]];
+        let length = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize;
+        let crc = u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
+
+        let payload = &bytes[HEADER_SIZE..];
+        if payload.len() != length {
+            return Err(AvrError::FirmwareError(format!(
+                "Firmware container payload is {} bytes, header says {}",
+                payload.len(),
+                length
+            )));
+        }
+        if crc32(payload) != crc {
+            return Err(AvrError::FirmwareError(String::from(
+                "Firmware container CRC mismatch, the file is corrupted",
+            )));
+        }
+
+        Ok(Container::new(signature, payload.to_vec()))
+    }
+}
+
+/// CRC-32 (IEEE 802.3), the same checksum zip and PNG use
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_container_round_trip() {
+        let container = Container::new([0x1e, 0x95, 0x0f], (0..=255).collect());
+
+        let decoded = Container::decode(&container.encode()).unwrap();
+        assert_eq!(decoded, container);
+    }
+
+    #[test]
+    fn test_corrupted_payload_is_refused() {
+        let mut bytes = Container::new([0x1e, 0x95, 0x0f], vec![1, 2, 3, 4]).encode();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+
+        match Container::decode(&bytes) {
+            Err(AvrError::FirmwareError(message)) => assert!(message.contains("CRC")),
+            result => panic!("Expected a firmware error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_truncated_container_is_refused() {
+        let bytes = Container::new([0x1e, 0x95, 0x0f], vec![1, 2, 3, 4]).encode();
+
+        assert!(Container::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Container::decode(&bytes[..8]).is_err());
+    }
+}
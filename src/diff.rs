@@ -0,0 +1,94 @@
+use std::ops::Range;
+
+/// Compare two flash images and return the address ranges whose contents
+/// differ. Bytes past the end of the shorter image are treated as erased (0xFF)
+pub fn changed_ranges(before: &[u8], after: &[u8]) -> Vec<Range<usize>> {
+    let len = before.len().max(after.len());
+    let byte_at = |image: &[u8], address: usize| image.get(address).copied().unwrap_or(0xFF);
+
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for address in 0..len {
+        if byte_at(before, address) == byte_at(after, address) {
+            continue;
+        }
+
+        match ranges.last_mut() {
+            Some(range) if range.end == address => range.end += 1,
+            _ => ranges.push(address..address + 1),
+        }
+    }
+
+    ranges
+}
+
+/// A byte read back from flash that isn't what was expected there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    pub address: usize,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+/// Every byte of `expected` that `actual` disagrees with. Bytes missing from
+/// `actual` are treated as erased (0xFF)
+pub fn mismatches(expected: &[u8], actual: &[u8]) -> Vec<Mismatch> {
+    expected
+        .iter()
+        .enumerate()
+        .filter_map(|(address, &expected)| {
+            let actual = actual.get(address).copied().unwrap_or(0xFF);
+            (actual != expected).then_some(Mismatch {
+                address,
+                expected,
+                actual,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_mismatching_byte_is_listed() {
+        let expected = vec![0x00, 0x01, 0x02, 0x03];
+        let actual = vec![0x00, 0x11, 0x02];
+
+        assert_eq!(
+            mismatches(&expected, &actual),
+            vec![
+                Mismatch {
+                    address: 1,
+                    expected: 0x01,
+                    actual: 0x11
+                },
+                Mismatch {
+                    address: 3,
+                    expected: 0x03,
+                    actual: 0xFF
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_changed_ranges_reflect_differences() {
+        let before = vec![0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07];
+        let mut after = before.clone();
+        after[1] = 0xAA;
+        after[2] = 0xBB;
+        after[6] = 0xCC;
+
+        assert_eq!(changed_ranges(&before, &after), vec![1..3, 6..7]);
+    }
+
+    #[test]
+    fn test_changed_ranges_treat_missing_bytes_as_erased() {
+        let before = vec![0x00, 0x01, 0xFF, 0xFF];
+        let after = vec![0x00, 0x01, 0x02];
+
+        assert_eq!(changed_ranges(&before, &after), vec![2..3]);
+        assert!(changed_ranges(&after, &after).is_empty());
+    }
+}
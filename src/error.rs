@@ -11,8 +11,31 @@ pub enum AvrError {
     #[error("Programmer error: {0}")]
     ProgrammerError(String),
 
+    #[error("Fuse error: {0}")]
+    FuseError(String),
+
+    #[error("Preflight check failed: {0}")]
+    PreflightError(String),
+
     #[error("Configuration error: {0}")]
     ConfigurationError(String),
+
+    #[error("Network error: {0}")]
+    NetworkError(String),
+
+    /// The device interface has no way to reset the board. Put the board
+    /// in its bootloader by hand and turn off auto reset
+    #[error("The device interface can't reset the board")]
+    NoResetSupported,
+
+    /// No attached serial port has one of the board's USB product IDs
+    #[error("No serial port found with product ID in {product_ids:04x?}")]
+    DeviceNotFound { product_ids: Vec<u16> },
+
+    /// Opening, configuring or talking to the serial port failed. Match on
+    /// the error's kind to tell eg: a missing port from a permission problem
+    #[error("Serial port error: {0}")]
+    Serial(#[from] serialport::Error),
 }
 
 pub type AvrResult<T> = std::result::Result<T, AvrError>;
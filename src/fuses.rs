@@ -0,0 +1,183 @@
+use crate::error::{AvrError, AvrResult};
+
+/// High fuse bits. A fuse bit is "programmed" when it reads as 0
+const HIGH_FUSE_RSTDISBL: u8 = 1 << 7;
+const HIGH_FUSE_DWEN: u8 = 1 << 6;
+const HIGH_FUSE_SPIEN: u8 = 1 << 5;
+const HIGH_FUSE_EESAVE: u8 = 1 << 3;
+
+/// ATtiny13 keeps SPIEN and EESAVE in the low fuse, its high fuse bit 5 is
+/// unused and reads as 1
+const LOW_FUSE_SPIEN_TINY13: u8 = 1 << 7;
+const LOW_FUSE_EESAVE_TINY13: u8 = 1 << 6;
+const ATTINY13: [u8; 3] = [0x1e, 0x90, 0x07];
+
+/// Parts whose high fuse carries RSTDISBL and DWEN in bits 7 and 6
+/// (ATmega48/88/168/328 families and ATtiny25/45/85)
+const RSTDISBL_IN_HIGH_FUSE: [[u8; 3]; 6] = [
+    [0x1e, 0x95, 0x0f], // ATmega328p
+    [0x1e, 0x95, 0x14], // ATmega328
+    [0x1e, 0x94, 0x0b], // ATmega168p
+    [0x1e, 0x93, 0x0b], // ATtiny85
+    [0x1e, 0x92, 0x06], // ATtiny45
+    [0x1e, 0x91, 0x08], // ATtiny25
+];
+
+/// ATmega48/88/168/328 family parts, whose low fuse CKSEL3..0 value 0b0001
+/// is reserved
+const MEGA_X8: [[u8; 3]; 3] = [
+    [0x1e, 0x95, 0x0f], // ATmega328p
+    [0x1e, 0x95, 0x14], // ATmega328
+    [0x1e, 0x94, 0x0b], // ATmega168p
+];
+const LOW_FUSE_CKSEL_MASK: u8 = 0x0f;
+const CKSEL_RESERVED: u8 = 0b0001;
+
+/// Parts that only have low and high fuses (ATmega8, ATmega16, ATmega32 and
+/// ATtiny13)
+const NO_EXTENDED_FUSE: [[u8; 3]; 4] = [
+    [0x1e, 0x93, 0x07], // ATmega8
+    [0x1e, 0x94, 0x03], // ATmega16
+    [0x1e, 0x95, 0x02], // ATmega32
+    [0x1e, 0x90, 0x07], // ATtiny13
+];
+
+/// What to do about EEPROM contents when erasing the chip. A chip erase
+/// clears EEPROM along with flash unless the EESAVE fuse is programmed, so
+/// every option other than `Erase` reads the fuses first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EepromOnErase {
+    /// Erase without looking at EESAVE
+    #[default]
+    Erase,
+
+    /// Log a warning when EEPROM is about to be erased, then erase anyway
+    Warn,
+
+    /// Refuse to erase when EESAVE is unprogrammed
+    Refuse,
+
+    /// Read this many bytes of EEPROM from address 0 before erasing and write
+    /// them back afterwards when EESAVE is unprogrammed.
+    ///
+    /// The restore rewrites every EEPROM page it covers, using up one of the
+    /// roughly 100,000 write cycles of each cell per erase; flash wear is the
+    /// same as a plain erase. The saved copy only lives in memory, so losing
+    /// the connection between erase and restore loses the EEPROM contents.
+    /// Programming EESAVE once avoids both
+    Restore(usize),
+}
+
+/// Fuse bytes of an AVR microcontroller. `extended` is populated on parts
+/// with an extended fuse byte, which is every supported board (ATmega328p,
+/// ATmega2560) and most newer parts. It is `None` on the older parts that only
+/// have low and high fuses, like the ATmega8/16/32 and ATtiny13
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fuses {
+    pub low: u8,
+    pub high: u8,
+    pub extended: Option<u8>,
+}
+
+impl Fuses {
+    /// Whether the part with the given signature has an extended fuse byte
+    pub(crate) fn has_extended(signature: &[u8]) -> bool {
+        !NO_EXTENDED_FUSE.iter().any(|s| s == signature)
+    }
+
+    /// Whether EESAVE is programmed, so a chip erase leaves EEPROM alone
+    pub fn preserves_eeprom(&self, signature: &[u8]) -> bool {
+        if signature == ATTINY13 {
+            self.low & LOW_FUSE_EESAVE_TINY13 == 0
+        } else {
+            self.high & HIGH_FUSE_EESAVE == 0
+        }
+    }
+
+    /// Check for fuse settings that make programming over ISP impossible,
+    /// naming the offending fuse in the returned error
+    pub(crate) fn check_isp_programmable(&self, signature: &[u8]) -> AvrResult<()> {
+        let (spien_unprogrammed, fuse_name, fuse) = if signature == ATTINY13 {
+            (self.low & LOW_FUSE_SPIEN_TINY13 != 0, "low", self.low)
+        } else {
+            (self.high & HIGH_FUSE_SPIEN != 0, "high", self.high)
+        };
+        if spien_unprogrammed {
+            return Err(AvrError::FuseError(format!(
+                "SPIEN is unprogrammed ({} fuse {:#04x}); serial programming is disabled \
+                and the part needs a high voltage programmer",
+                fuse_name, fuse
+            )));
+        }
+
+        if RSTDISBL_IN_HIGH_FUSE.iter().any(|s| s == signature) {
+            if self.high & HIGH_FUSE_RSTDISBL == 0 {
+                return Err(AvrError::FuseError(format!(
+                    "RSTDISBL is programmed (high fuse {:#04x}); the reset pin is disabled \
+                    so the part can't enter ISP mode. Use a high voltage programmer to recover",
+                    self.high
+                )));
+            }
+
+            if self.high & HIGH_FUSE_DWEN == 0 {
+                return Err(AvrError::FuseError(format!(
+                    "DWEN is programmed (high fuse {:#04x}); debugWIRE has taken over the \
+                    reset pin. Disable debugWIRE before programming over ISP",
+                    self.high
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that writing these fuses won't lock the part out of ISP
+    /// programming or leave it without a clock, explaining the problem in a
+    /// `ConfigurationError` otherwise
+    pub(crate) fn check_safe_to_write(&self, signature: &[u8]) -> AvrResult<()> {
+        self.check_isp_programmable(signature)
+            .map_err(|e| match e {
+                AvrError::FuseError(message) => {
+                    AvrError::ConfigurationError(format!("Refusing to write fuses: {}", message))
+                }
+                e => e,
+            })?;
+
+        if MEGA_X8.iter().any(|s| s == signature)
+            && self.low & LOW_FUSE_CKSEL_MASK == CKSEL_RESERVED
+        {
+            return Err(AvrError::ConfigurationError(format!(
+                "Refusing to write fuses: low fuse {:#04x} selects the reserved clock source \
+                CKSEL=0001, the part would not start",
+                self.low
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attiny13_spien_is_read_from_low_fuse() {
+        // Factory fuses: SPIEN programmed in the low fuse, high fuse all ones
+        let factory = Fuses {
+            low: 0x6a,
+            high: 0xff,
+            extended: None,
+        };
+        assert!(factory.check_safe_to_write(&ATTINY13).is_ok());
+
+        let spien_unprogrammed = Fuses {
+            low: 0xea,
+            ..factory
+        };
+        assert!(matches!(
+            spien_unprogrammed.check_isp_programmable(&ATTINY13),
+            Err(AvrError::FuseError(_))
+        ));
+    }
+}
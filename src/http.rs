@@ -0,0 +1,64 @@
+use std::{io::Read, time::Duration};
+
+use crate::{
+    constants::{HTTP_MAX_FIRMWARE_SIZE, HTTP_TIMEOUT_SECS},
+    error::{AvrError, AvrResult},
+};
+
+/// Content types a hex file is commonly served with. Anything else (an HTML
+/// login or error page, most likely) is refused rather than parsed
+const HEX_CONTENT_TYPES: [&str; 4] = [
+    "text/plain",
+    "application/octet-stream",
+    "application/x-ihex",
+    "text/x-hex",
+];
+
+/// Download an intel hex file, refusing unexpected content types and
+/// anything larger than HTTP_MAX_FIRMWARE_SIZE
+pub(crate) fn fetch_hex(url: &str) -> AvrResult<String> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(HTTP_TIMEOUT_SECS))
+        .build();
+
+    let response = agent
+        .get(url)
+        .call()
+        .map_err(|e| AvrError::NetworkError(format!("Failed to fetch {}: {}", url, e)))?;
+
+    let content_type = response.content_type().to_ascii_lowercase();
+    if !HEX_CONTENT_TYPES.contains(&content_type.as_str()) {
+        return Err(AvrError::NetworkError(format!(
+            "Unexpected content type {:?} for {}",
+            content_type, url
+        )));
+    }
+
+    let length = response
+        .header("Content-Length")
+        .and_then(|length| length.parse::<u64>().ok());
+    if length.is_some_and(|length| length > HTTP_MAX_FIRMWARE_SIZE) {
+        return Err(too_large(url));
+    }
+
+    // Content-Length can be missing or wrong, so the limit is enforced on the body too
+    let mut body = Vec::new();
+    response
+        .into_reader()
+        .take(HTTP_MAX_FIRMWARE_SIZE + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| AvrError::NetworkError(format!("Failed to download {}: {}", url, e)))?;
+    if body.len() as u64 > HTTP_MAX_FIRMWARE_SIZE {
+        return Err(too_large(url));
+    }
+
+    String::from_utf8(body)
+        .map_err(|_| AvrError::FirmwareError(format!("{} is not a text hex file", url)))
+}
+
+fn too_large(url: &str) -> AvrError {
+    AvrError::NetworkError(format!(
+        "{} is larger than the {} byte firmware download limit",
+        url, HTTP_MAX_FIRMWARE_SIZE
+    ))
+}
@@ -0,0 +1,46 @@
+use super::DeviceInterface;
+use crate::error::AvrResult;
+
+type SendFn = Box<dyn FnMut(Vec<u8>) -> AvrResult<()> + Send>;
+type ReceiveFn = Box<dyn FnMut() -> AvrResult<Vec<u8>> + Send>;
+type ResetFn = Box<dyn FnMut() -> AvrResult<()> + Send>;
+
+/// Device interface backed by user supplied closures. Use this to program
+/// boards from environments where the serialport crate isn't available, for
+/// instance WebSerial in a browser or a host MCU bridging the UART
+///
+/// `receive` should return whatever bytes are available, or an empty vector
+/// when nothing arrived within a short timeout
+pub struct CallbackInterface {
+    send: SendFn,
+    receive: ReceiveFn,
+    reset: ResetFn,
+}
+
+impl CallbackInterface {
+    pub fn new(
+        send: impl FnMut(Vec<u8>) -> AvrResult<()> + Send + 'static,
+        receive: impl FnMut() -> AvrResult<Vec<u8>> + Send + 'static,
+        reset: impl FnMut() -> AvrResult<()> + Send + 'static,
+    ) -> Self {
+        CallbackInterface {
+            send: Box::new(send),
+            receive: Box::new(receive),
+            reset: Box::new(reset),
+        }
+    }
+}
+
+impl DeviceInterface for CallbackInterface {
+    fn send(&mut self, command: Vec<u8>) -> AvrResult<()> {
+        (self.send)(command)
+    }
+
+    fn receive(&mut self) -> AvrResult<Vec<u8>> {
+        (self.receive)()
+    }
+
+    fn reset(&mut self) -> AvrResult<()> {
+        (self.reset)()
+    }
+}
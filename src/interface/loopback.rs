@@ -0,0 +1,216 @@
+use super::DeviceInterface;
+use crate::error::AvrResult;
+use crate::fuses::Fuses;
+use crate::protocols::isp::{
+    CHIP_ERASE, LOAD_PROGRAM_MEMORY_HIGH, LOAD_PROGRAM_MEMORY_LOW, READ_EXTENDED_FUSE,
+    READ_HIGH_FUSE, READ_LOW_FUSE, WRITE_PROGRAM_MEMORY_PAGE,
+};
+use crate::protocols::stk500v1::Stk500v1Message;
+use std::sync::{Arc, Mutex};
+
+const RESP_STK_UNKNOWN: u8 = 0x12;
+const EEPROM_SIZE: usize = 1024;
+
+/// In-memory device interface with a tiny STK500v1 bootloader simulator behind
+/// it. Written pages are stored in memory that can be inspected through the
+/// handles returned by `flash()` and `eeprom()`, which makes it useful for tests
+/// and benchmarks that need to run without hardware
+///
+/// Flash pages written with raw ISP instructions behave as on a real chip:
+/// they can only clear bits, so the flash has to be erased first
+pub struct LoopbackInterface {
+    signature: Vec<u8>,
+    flash: Arc<Mutex<Vec<u8>>>,
+    eeprom: Arc<Mutex<Vec<u8>>>,
+    fuses: Fuses,
+    address: usize,
+    extended_address: usize,
+
+    /// Flash byte address and value of each byte loaded into the ISP page
+    /// buffer since the last page write
+    page_buffer: Vec<(usize, u8)>,
+    pending: Vec<u8>,
+}
+
+impl LoopbackInterface {
+    /// Create a simulated device with the given signature and an erased flash
+    /// of `flash_size` bytes
+    pub fn new(signature: Vec<u8>, flash_size: usize) -> Self {
+        LoopbackInterface {
+            signature,
+            flash: Arc::new(Mutex::new(vec![0xFF; flash_size])),
+            eeprom: Arc::new(Mutex::new(vec![0xFF; EEPROM_SIZE])),
+            fuses: Fuses {
+                low: 0x00,
+                high: 0x00,
+                extended: Some(0x00),
+            },
+            address: 0,
+            extended_address: 0,
+            page_buffer: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Fuse bytes reported to ISP fuse reads. They default to all zeros,
+    /// which has EESAVE programmed so chip erase leaves EEPROM alone
+    pub fn with_fuses(mut self, fuses: Fuses) -> Self {
+        self.fuses = fuses;
+        self
+    }
+
+    /// Handle to the simulated flash memory
+    pub fn flash(&self) -> Arc<Mutex<Vec<u8>>> {
+        Arc::clone(&self.flash)
+    }
+
+    /// Handle to the simulated EEPROM
+    pub fn eeprom(&self) -> Arc<Mutex<Vec<u8>>> {
+        Arc::clone(&self.eeprom)
+    }
+
+    /// Erase flash, and EEPROM unless the fuses have EESAVE programmed
+    fn chip_erase(&self) {
+        self.flash
+            .lock()
+            .expect("Failed to lock loopback memory")
+            .fill(0xFF);
+        if !self.fuses.preserves_eeprom(&self.signature) {
+            self.eeprom
+                .lock()
+                .expect("Failed to lock loopback memory")
+                .fill(0xFF);
+        }
+    }
+
+    /// Commit the ISP page buffer. Without an erase first, a page write can
+    /// only clear bits
+    fn write_page_buffer(&mut self) {
+        let mut flash = self.flash.lock().expect("Failed to lock loopback memory");
+        for (address, byte) in self.page_buffer.drain(..) {
+            if let Some(cell) = flash.get_mut(address) {
+                *cell &= byte;
+            }
+        }
+    }
+
+    /// Memory selected by the memory type byte and the byte offset of the
+    /// loaded address within it. Like Optiboot, the loaded address is doubled
+    /// for EEPROM as well as flash
+    fn memory_at(&self, memory_type: u8) -> (Arc<Mutex<Vec<u8>>>, usize) {
+        if memory_type == b'E' {
+            (self.eeprom(), self.address * 2)
+        } else {
+            (
+                self.flash(),
+                (self.extended_address << 16 | self.address) * 2,
+            )
+        }
+    }
+
+    fn respond(&mut self, command: &[u8]) -> Vec<u8> {
+        let in_sync = Stk500v1Message::RespStkInSync as u8;
+        let ok = Stk500v1Message::RespStkOk as u8;
+
+        let Some(&opcode) = command.first() else {
+            return vec![RESP_STK_UNKNOWN];
+        };
+
+        match opcode {
+            op if op == Stk500v1Message::CmndStkGetSync as u8
+                || op == Stk500v1Message::CmndStkSetDevice as u8
+                || op == Stk500v1Message::CmndStkEnterProgMode as u8
+                || op == Stk500v1Message::CmndStkLeaveProgMode as u8 =>
+            {
+                vec![in_sync, ok]
+            }
+            op if op == Stk500v1Message::CmndStkReadSign as u8 => {
+                [vec![in_sync], self.signature.clone(), vec![ok]].concat()
+            }
+            op if op == Stk500v1Message::CmndStkLoadAddress as u8 && command.len() >= 3 => {
+                self.address = command[1] as usize | (command[2] as usize) << 8;
+                vec![in_sync, ok]
+            }
+            op if op == Stk500v1Message::CmndStkUniversal as u8 => {
+                // Load extended address
+                if command.get(1) == Some(&0x4D) && command.len() >= 4 {
+                    self.extended_address = command[3] as usize;
+                }
+                let value = match command.get(1..5) {
+                    Some(instruction) if instruction == READ_LOW_FUSE => self.fuses.low,
+                    Some(instruction) if instruction == READ_HIGH_FUSE => self.fuses.high,
+                    Some(instruction) if instruction == READ_EXTENDED_FUSE => {
+                        self.fuses.extended.unwrap_or(0x00)
+                    }
+                    Some(instruction) if instruction == CHIP_ERASE => {
+                        self.chip_erase();
+                        0x00
+                    }
+                    Some(&[instruction, word_high, word_low, byte])
+                        if instruction == LOAD_PROGRAM_MEMORY_LOW
+                            || instruction == LOAD_PROGRAM_MEMORY_HIGH =>
+                    {
+                        let word = self.extended_address << 16
+                            | (word_high as usize) << 8
+                            | word_low as usize;
+                        let high = (instruction == LOAD_PROGRAM_MEMORY_HIGH) as usize;
+                        self.page_buffer.push((word * 2 + high, byte));
+                        0x00
+                    }
+                    Some(&[instruction, ..]) if instruction == WRITE_PROGRAM_MEMORY_PAGE => {
+                        self.write_page_buffer();
+                        0x00
+                    }
+                    _ => 0x00,
+                };
+                vec![in_sync, value, ok]
+            }
+            op if op == Stk500v1Message::CmndStkProgPage as u8 && command.len() >= 5 => {
+                let size = (command[1] as usize) << 8 | command[2] as usize;
+                let data = &command[4..command.len() - 1];
+                let (memory, offset) = self.memory_at(command[3]);
+                let mut memory = memory.lock().expect("Failed to lock loopback memory");
+
+                for (index, byte) in data.iter().take(size).enumerate() {
+                    if let Some(cell) = memory.get_mut(offset + index) {
+                        *cell = *byte;
+                    }
+                }
+                vec![in_sync, ok]
+            }
+            op if op == Stk500v1Message::CmndStkReadPage as u8 && command.len() >= 4 => {
+                let size = (command[1] as usize) << 8 | command[2] as usize;
+                let (memory, offset) = self.memory_at(command[3]);
+                let memory = memory.lock().expect("Failed to lock loopback memory");
+
+                let data = (offset..offset + size)
+                    .map(|address| memory.get(address).copied().unwrap_or(0xFF));
+                std::iter::once(in_sync)
+                    .chain(data)
+                    .chain(std::iter::once(ok))
+                    .collect()
+            }
+            _ => vec![RESP_STK_UNKNOWN],
+        }
+    }
+}
+
+impl DeviceInterface for LoopbackInterface {
+    fn send(&mut self, command: Vec<u8>) -> AvrResult<()> {
+        let response = self.respond(&command);
+        self.pending.extend(response);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> AvrResult<Vec<u8>> {
+        Ok(std::mem::take(&mut self.pending))
+    }
+
+    fn reset(&mut self) -> AvrResult<()> {
+        self.address = 0;
+        self.extended_address = 0;
+        self.page_buffer.clear();
+        self.pending.clear();
+        Ok(())
+    }
+}
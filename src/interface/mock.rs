@@ -0,0 +1,77 @@
+use super::DeviceInterface;
+use crate::error::AvrResult;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Scripted device interface for unit testing protocols without hardware.
+/// Each command sent must match the next expected command in the script, and
+/// is answered with the canned response paired with it
+pub(crate) struct MockDeviceInterface {
+    script: VecDeque<(Vec<u8>, Vec<u8>)>,
+    pending: Vec<u8>,
+    reset_noise: Vec<u8>,
+    log: Arc<Mutex<MockLog>>,
+}
+
+/// What the protocol did to the mock, shared with the test for inspection
+#[derive(Debug, Default)]
+pub(crate) struct MockLog {
+    pub sent: Vec<Vec<u8>>,
+    pub unexpected: Vec<Vec<u8>>,
+    pub resets: usize,
+}
+
+impl MockDeviceInterface {
+    pub fn new(script: Vec<(Vec<u8>, Vec<u8>)>) -> (Self, Arc<Mutex<MockLog>>) {
+        let log = Arc::new(Mutex::new(MockLog::default()));
+        let mock = MockDeviceInterface {
+            script: script.into(),
+            pending: Vec::new(),
+            reset_noise: Vec::new(),
+            log: Arc::clone(&log),
+        };
+        (mock, log)
+    }
+
+    /// Bytes that show up to be read after every reset, like a bootloader
+    /// banner
+    pub fn emit_on_reset(&mut self, noise: Vec<u8>) {
+        self.reset_noise = noise;
+    }
+}
+
+impl DeviceInterface for MockDeviceInterface {
+    fn send(&mut self, command: Vec<u8>) -> AvrResult<()> {
+        let mut log = self.log.lock().unwrap();
+        log.sent.push(command.clone());
+
+        match self.script.front() {
+            Some((expected, _)) if *expected == command => {
+                let (_, response) = self.script.pop_front().unwrap();
+                self.pending.extend(response);
+            }
+            _ => {
+                // Answer with enough garbage to satisfy any read so the
+                // protocol fails fast instead of waiting forever
+                log.unexpected.push(command);
+                self.pending.extend(vec![0x00; 1024]);
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> AvrResult<Vec<u8>> {
+        Ok(std::mem::take(&mut self.pending))
+    }
+
+    fn reset(&mut self) -> AvrResult<()> {
+        self.log.lock().unwrap().resets += 1;
+        self.pending.extend(&self.reset_noise);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> AvrResult<()> {
+        self.pending.clear();
+        Ok(())
+    }
+}
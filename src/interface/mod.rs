@@ -1,9 +1,19 @@
+pub mod callback;
+pub mod loopback;
+#[cfg(test)]
+pub(crate) mod mock;
+pub mod recording;
 pub mod serialport;
+pub mod tcp;
 use serialport::{BaudRate, ComPort};
 
+use std::time::Duration;
+
 use crate::error::AvrResult;
 
-pub(crate) trait DeviceInterface {
+/// Byte level link to the target device. Implement this to program boards
+/// over something other than a local serial port
+pub trait DeviceInterface {
     /// Send a command to the target device
     fn send(&mut self, command: Vec<u8>) -> AvrResult<()>;
 
@@ -12,6 +22,26 @@ pub(crate) trait DeviceInterface {
 
     /// Reset the target device
     fn reset(&mut self) -> AvrResult<()>;
+
+    /// Change how long a single receive waits for data. Interfaces without
+    /// a read timeout can ignore this
+    fn set_timeout(&mut self, _timeout: Duration) -> AvrResult<()> {
+        Ok(())
+    }
+
+    /// Throw away whatever is waiting to be read or written, eg: a banner
+    /// the bootloader printed on reset. Interfaces without buffers can
+    /// ignore this
+    fn flush(&mut self) -> AvrResult<()> {
+        Ok(())
+    }
+
+    /// Second handle to the same device, used only for sending, so commands
+    /// can go out while a receive is waiting for data. Interfaces that can't
+    /// duplicate their handle return None and reads and writes take turns
+    fn try_clone_sender(&self) -> Option<Box<dyn DeviceInterface + Send>> {
+        None
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -26,4 +56,19 @@ pub struct SerialportParams {
 #[derive(Debug, Clone)]
 pub enum DeviceInterfaceType {
     Serial(SerialportParams),
+
+    /// Serial port shared over the network as a raw TCP stream, see
+    /// tcp::TcpDeviceInterface. The board is not reset, since a raw stream
+    /// has no control lines
+    Tcp {
+        host: String,
+        port: u16,
+    },
+
+    /// Serial port shared over the network by an RFC 2217 server, which
+    /// lets reset toggle the remote DTR/RTS lines
+    Rfc2217 {
+        host: String,
+        port: u16,
+    },
 }
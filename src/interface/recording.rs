@@ -0,0 +1,65 @@
+use super::DeviceInterface;
+use crate::error::AvrResult;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Every command sent through a RecordingInterface, in order
+pub type CommandLog = Arc<Mutex<Vec<Vec<u8>>>>;
+
+/// Wraps another device interface and keeps a copy of every command sent
+/// through it. Paired with the loopback interface this gives a dry run: the
+/// full command stream for an image, without any hardware attached
+pub struct RecordingInterface {
+    inner: Box<dyn DeviceInterface + Send>,
+    commands: CommandLog,
+}
+
+impl RecordingInterface {
+    pub fn new(inner: Box<dyn DeviceInterface + Send>) -> Self {
+        RecordingInterface {
+            inner,
+            commands: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Handle to the commands recorded so far
+    pub fn commands(&self) -> CommandLog {
+        Arc::clone(&self.commands)
+    }
+}
+
+impl DeviceInterface for RecordingInterface {
+    fn send(&mut self, command: Vec<u8>) -> AvrResult<()> {
+        self.commands
+            .lock()
+            .expect("Failed to lock command log")
+            .push(command.clone());
+        self.inner.send(command)
+    }
+
+    fn receive(&mut self) -> AvrResult<Vec<u8>> {
+        self.inner.receive()
+    }
+
+    fn reset(&mut self) -> AvrResult<()> {
+        self.inner.reset()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> AvrResult<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn flush(&mut self) -> AvrResult<()> {
+        self.inner.flush()
+    }
+
+    fn try_clone_sender(&self) -> Option<Box<dyn DeviceInterface + Send>> {
+        // Record through the clone too, commands only go out through it
+        self.inner.try_clone_sender().map(|inner| {
+            Box::new(RecordingInterface {
+                inner,
+                commands: Arc::clone(&self.commands),
+            }) as Box<dyn DeviceInterface + Send>
+        })
+    }
+}
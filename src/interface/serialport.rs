@@ -1,53 +1,350 @@
 use super::DeviceInterface;
 use crate::constants::{
-    MAX_RESPONSE_SIZE, POST_RESET_BOOTUP_DELAY_MS, RESET_DTR_RTS_LOW_MICROS, SERIAL_TIMEOUT_MS,
+    MAX_RESPONSE_SIZE, POST_RESET_BOOTUP_DELAY_MS, REENUMERATION_POLL_MS, REENUMERATION_TIMEOUT_MS,
+    RESET_DTR_RTS_LOW_MICROS, SERIAL_TIMEOUT_MS, TOUCH_RESET_BAUD,
 };
 
+use crate::boards::wait_for_reenumerated_port;
 use crate::error::{AvrError, AvrResult};
+use serialport::SerialPortInfo;
 use std::io::{Read, Write};
+use std::time::Duration;
+use tracing::{debug, info, warn};
 
 pub type ComPort = String;
 pub type BaudRate = u32;
+
+/// Serial read timeout used unless a protocol asks for another
+pub const DEFAULT_SERIAL_TIMEOUT_MS: u64 = SERIAL_TIMEOUT_MS;
+
+/// Control line(s) toggled to reset the target. Most adapters wire DTR
+/// (through a capacitor) to reset, some use RTS. Boards with native USB
+/// (ATmega32u4) have no reset line and instead jump to the bootloader when
+/// the port is opened and closed at 1200 baud. That is done when the port is
+/// opened, and again on every later reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResetLine {
+    Dtr,
+    Rts,
+    #[default]
+    Both,
+    Touch1200,
+}
+
+impl ResetLine {
+    fn uses_dtr(&self) -> bool {
+        matches!(self, ResetLine::Dtr | ResetLine::Both)
+    }
+
+    fn uses_rts(&self) -> bool {
+        matches!(self, ResetLine::Rts | ResetLine::Both)
+    }
+}
+
+/// How long the reset line(s) are held low, and how long the bootloader is
+/// given to start up afterwards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResetTiming {
+    pub low_micros: u64,
+    pub bootup_delay_ms: u64,
+}
+
+impl Default for ResetTiming {
+    fn default() -> Self {
+        ResetTiming {
+            low_micros: RESET_DTR_RTS_LOW_MICROS,
+            bootup_delay_ms: POST_RESET_BOOTUP_DELAY_MS,
+        }
+    }
+}
+
+/// Modem control lines of a serial port
+pub(crate) trait ControlLines {
+    fn write_dtr(&mut self, level: bool) -> AvrResult<()>;
+    fn write_rts(&mut self, level: bool) -> AvrResult<()>;
+}
+
+impl ControlLines for Box<dyn serialport::SerialPort> {
+    fn write_dtr(&mut self, level: bool) -> AvrResult<()> {
+        Ok(self.write_data_terminal_ready(level)?)
+    }
+
+    fn write_rts(&mut self, level: bool) -> AvrResult<()> {
+        Ok(self.write_request_to_send(level)?)
+    }
+}
+
+/// Pull the selected reset line(s) low, release them and wait for the
+/// bootloader to come up
+pub(crate) fn pulse_reset(
+    lines: &mut impl ControlLines,
+    reset_line: ResetLine,
+    timing: ResetTiming,
+) -> AvrResult<()> {
+    if reset_line.uses_dtr() {
+        lines.write_dtr(false)?;
+    }
+    if reset_line.uses_rts() {
+        lines.write_rts(false)?;
+    }
+
+    std::thread::sleep(Duration::from_micros(timing.low_micros));
+
+    if reset_line.uses_dtr() {
+        lines.write_dtr(true)?;
+    }
+    if reset_line.uses_rts() {
+        lines.write_rts(true)?;
+    }
+
+    std::thread::sleep(Duration::from_millis(timing.bootup_delay_ms));
+    Ok(())
+}
+
+/// Write `command` in one go, or byte by byte with `delay` between bytes when
+/// the delay is nonzero
+fn write_command(
+    writer: &mut impl Write,
+    command: &[u8],
+    delay: std::time::Duration,
+) -> AvrResult<()> {
+    if delay.is_zero() {
+        return writer
+            .write_all(command)
+            .map_err(|e| serialport::Error::from(e).into());
+    }
+
+    for (index, byte) in command.iter().enumerate() {
+        if index > 0 {
+            std::thread::sleep(delay);
+        }
+        writer
+            .write_all(&[*byte])
+            .and_then(|_| writer.flush())
+            .map_err(serialport::Error::from)?;
+    }
+    Ok(())
+}
+
+/// Kick a native USB board into its bootloader by opening `port` at 1200 baud
+/// with DTR off and closing it again, then wait for the bootloader's port to
+/// show up
+pub fn touch_reset_1200(port: &str) -> AvrResult<()> {
+    touch_reset_to_bootloader(port).map(|_| ())
+}
+
+/// Same as touch_reset_1200, returning the port the bootloader appeared on.
+/// That is a new port on some systems, and the same port re-enumerated on others
+pub(crate) fn touch_reset_to_bootloader(port: &str) -> AvrResult<ComPort> {
+    touch_and_wait(port)?.ok_or_else(|| {
+        AvrError::ConfigurationError(format!(
+            "No bootloader port appeared after the 1200 baud touch reset of {}",
+            port
+        ))
+    })
+}
+
+/// Send the 1200 baud touch to `port` and wait for the bootloader's port.
+/// None when nothing re-enumerated in time
+fn touch_and_wait(port: &str) -> AvrResult<Option<ComPort>> {
+    let list_ports = || {
+        serialport::available_ports().map_err(|e| {
+            AvrError::ConfigurationError(format!("Could not get available ports. Err {:?}", e))
+        })
+    };
+    let before = list_ports()?;
+
+    let touch = serialport::new(port, TOUCH_RESET_BAUD)
+        .dtr_on_open(false)
+        .open()?;
+    drop(touch);
+    info!("Sent 1200 baud touch reset to {}", port);
+
+    let deadline =
+        std::time::Instant::now() + std::time::Duration::from_millis(REENUMERATION_TIMEOUT_MS);
+    let mut vanished = false;
+    while std::time::Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(REENUMERATION_POLL_MS));
+        let after = list_ports()?;
+
+        if let Some(new_port) = newly_listed_port(&before, &after) {
+            return Ok(Some(new_port));
+        }
+        let present = after.iter().any(|info| info.port_name == port);
+        if !present {
+            vanished = true;
+        } else if vanished {
+            return Ok(Some(port.to_owned()));
+        }
+    }
+    Ok(None)
+}
+
+fn open_port(
+    port: &str,
+    baud: BaudRate,
+    timeout: Duration,
+) -> AvrResult<Box<dyn serialport::SerialPort>> {
+    Ok(serialport::new(port, baud)
+        .timeout(timeout)
+        .dtr_on_open(false)
+        .open()?)
+}
+
+/// First port in `after` that wasn't listed in `before`
+fn newly_listed_port(before: &[SerialPortInfo], after: &[SerialPortInfo]) -> Option<ComPort> {
+    after
+        .iter()
+        .find(|info| !before.iter().any(|old| old.port_name == info.port_name))
+        .map(|info| info.port_name.clone())
+}
+
 /// Serial port device_interface layer
 pub(crate) struct SerialPortDevice {
-    pub serial_port: Box<dyn serialport::SerialPort>,
+    /// None only while a touch reset has the port closed
+    serial_port: Option<Box<dyn serialport::SerialPort>>,
+
+    /// Port open and its baud rate, to open it again after a touch reset
+    port: ComPort,
+    baud: BaudRate,
+    reset_line: ResetLine,
+    reset_timing: ResetTiming,
+    inter_byte_delay: Duration,
+
+    /// The board was touched into its bootloader just before the port was
+    /// opened, so the reset starting the first operation has nothing to do
+    touched: bool,
 }
 
 impl SerialPortDevice {
-    pub fn new(port: ComPort, baud: BaudRate) -> AvrResult<SerialPortDevice> {
-        let serial_port = serialport::new(port, baud)
-            .timeout(std::time::Duration::from_millis(SERIAL_TIMEOUT_MS))
-            .dtr_on_open(false)
-            .open()
-            .map_err(|e| AvrError::Communication(format!("{:?}", e)))?;
+    pub fn new(
+        port: ComPort,
+        baud: BaudRate,
+        reset_line: ResetLine,
+        reset_timing: ResetTiming,
+        inter_byte_delay_micros: u64,
+        timeout_ms: u64,
+    ) -> AvrResult<SerialPortDevice> {
+        let serial_port = open_port(&port, baud, Duration::from_millis(timeout_ms))?;
+
+        Ok(SerialPortDevice {
+            serial_port: Some(serial_port),
+            port,
+            baud,
+            reset_line,
+            reset_timing,
+            inter_byte_delay: Duration::from_micros(inter_byte_delay_micros),
+            touched: false,
+        })
+    }
+
+    /// Open `port`, and if that fails because the board re-enumerated (eg: native
+    /// USB boards after a reset), retry on a newly appeared port whose product ID
+    /// matches one of `product_ids`
+    pub fn open_with_retry(
+        port: ComPort,
+        baud: BaudRate,
+        reset_line: ResetLine,
+        reset_timing: ResetTiming,
+        inter_byte_delay_micros: u64,
+        timeout_ms: u64,
+        product_ids: &[u16],
+    ) -> AvrResult<SerialPortDevice> {
+        let touched = reset_line == ResetLine::Touch1200;
+        let port = if touched {
+            touch_reset_to_bootloader(&port)?
+        } else {
+            port
+        };
+        let before = serialport::available_ports().unwrap_or_default();
 
-        Ok(SerialPortDevice { serial_port })
+        let device = match Self::new(
+            port.clone(),
+            baud,
+            reset_line,
+            reset_timing,
+            inter_byte_delay_micros,
+            timeout_ms,
+        ) {
+            Ok(device) => Ok(device),
+            Err(e) if product_ids.is_empty() => Err(e),
+            Err(e) => {
+                warn!(
+                    "Could not open {}, looking for a re-enumerated port: {}",
+                    port, e
+                );
+                let new_port = wait_for_reenumerated_port(&port, &before, product_ids)?;
+                info!("Retrying on newly appeared port {}", new_port);
+                Self::new(
+                    new_port,
+                    baud,
+                    reset_line,
+                    reset_timing,
+                    inter_byte_delay_micros,
+                    timeout_ms,
+                )
+            }
+        }?;
+        Ok(SerialPortDevice { touched, ..device })
+    }
+
+    fn serial_port(&mut self) -> AvrResult<&mut Box<dyn serialport::SerialPort>> {
+        self.serial_port.as_mut().ok_or_else(|| {
+            AvrError::Communication(format!(
+                "{} was not reopened after the touch reset",
+                self.port
+            ))
+        })
+    }
+
+    /// Touch the board back into its bootloader and reopen the port the
+    /// bootloader comes up on. Caterina leaves the bootloader after about 8
+    /// seconds, so a later operation would otherwise talk to the sketch
+    fn touch_and_reopen(&mut self) -> AvrResult<()> {
+        let timeout = self.serial_port()?.timeout();
+        // The touch opens the port itself
+        self.serial_port = None;
+
+        let port = match touch_and_wait(&self.port)? {
+            Some(port) => port,
+            // A bootloader still running ignores the touch and stays put
+            None => self.port.clone(),
+        };
+        self.serial_port = Some(open_port(&port, self.baud, timeout)?);
+        self.port = port;
+        Ok(())
     }
 }
 
 impl DeviceInterface for SerialPortDevice {
     fn send(&mut self, command: Vec<u8>) -> AvrResult<()> {
-        self.serial_port
-            .write_all(&command)
-            .map_err(|e| AvrError::Communication(format!("{:?}", e)))?;
-        Ok(())
+        let delay = self.inter_byte_delay;
+        write_command(self.serial_port()?, &command, delay)
     }
 
     fn receive(&mut self) -> AvrResult<Vec<u8>> {
         let mut buffer: Vec<u8> = vec![0; MAX_RESPONSE_SIZE];
 
-        let size = self
-            .serial_port
-            .read(&mut buffer)
+        let size = match self.serial_port()?.read(&mut buffer) {
+            Ok(size) => size,
             // Timeout error is fine, just continue
-            .or_else(|e| {
-                if e.kind() == std::io::ErrorKind::TimedOut {
-                    Ok(0)
-                } else {
-                    Err(e)
-                }
-            })
-            .map_err(|e| AvrError::Communication(format!("{:?}", e)))?;
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::TimedOut | std::io::ErrorKind::Interrupted
+                ) =>
+            {
+                0
+            }
+            // Any other read error on an open port means the device went
+            // away, eg: the USB cable was pulled
+            Err(e) => {
+                return Err(AvrError::Communication(format!(
+                    "Device disconnected: {}",
+                    e
+                )));
+            }
+        };
 
         // Return a buffer with the actual length
         buffer.truncate(size);
@@ -55,24 +352,164 @@ impl DeviceInterface for SerialPortDevice {
     }
 
     fn reset(&mut self) -> AvrResult<()> {
-        // Reset logic for the serial port
-        self.serial_port
-            .write_data_terminal_ready(false)
-            .map_err(|e| AvrError::Communication(format!("Failed to set DTR false: {:?}", e)))?;
-        self.serial_port
-            .write_request_to_send(false)
-            .map_err(|e| AvrError::Communication(format!("Failed to set RTS false: {:?}", e)))?;
-
-        std::thread::sleep(std::time::Duration::from_micros(RESET_DTR_RTS_LOW_MICROS));
-
-        self.serial_port
-            .write_data_terminal_ready(true)
-            .map_err(|e| AvrError::Communication(format!("Failed to set DTR true: {:?}", e)))?;
-        self.serial_port
-            .write_request_to_send(true)
-            .map_err(|e| AvrError::Communication(format!("Failed to set RTS true: {:?}", e)))?;
-
-        std::thread::sleep(std::time::Duration::from_millis(POST_RESET_BOOTUP_DELAY_MS));
-        Ok(())
+        if self.reset_line != ResetLine::Touch1200 {
+            let (reset_line, reset_timing) = (self.reset_line, self.reset_timing);
+            return pulse_reset(self.serial_port()?, reset_line, reset_timing);
+        }
+        if std::mem::take(&mut self.touched) {
+            return Ok(());
+        }
+        self.touch_and_reopen()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> AvrResult<()> {
+        Ok(self.serial_port()?.set_timeout(timeout)?)
+    }
+
+    fn flush(&mut self) -> AvrResult<()> {
+        Ok(self.serial_port()?.clear(serialport::ClearBuffer::All)?)
+    }
+
+    fn try_clone_sender(&self) -> Option<Box<dyn DeviceInterface + Send>> {
+        // A touch reset replaces the port, which a clone wouldn't follow
+        if self.reset_line == ResetLine::Touch1200 {
+            return None;
+        }
+
+        match self.serial_port.as_ref()?.try_clone() {
+            Ok(serial_port) => Some(Box::new(SerialPortDevice {
+                serial_port: Some(serial_port),
+                port: self.port.clone(),
+                baud: self.baud,
+                reset_line: self.reset_line,
+                reset_timing: self.reset_timing,
+                inter_byte_delay: self.inter_byte_delay,
+                touched: false,
+            })),
+            Err(e) => {
+                debug!("Could not clone serial port, sharing one handle: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records every control line write
+    #[derive(Default)]
+    struct RecordingLines {
+        writes: Vec<(&'static str, bool)>,
+    }
+
+    impl ControlLines for RecordingLines {
+        fn write_dtr(&mut self, level: bool) -> AvrResult<()> {
+            self.writes.push(("DTR", level));
+            Ok(())
+        }
+
+        fn write_rts(&mut self, level: bool) -> AvrResult<()> {
+            self.writes.push(("RTS", level));
+            Ok(())
+        }
+    }
+
+    const QUICK: ResetTiming = ResetTiming {
+        low_micros: 1,
+        bootup_delay_ms: 0,
+    };
+
+    #[test]
+    fn test_only_selected_reset_lines_are_toggled() {
+        let mut lines = RecordingLines::default();
+        pulse_reset(&mut lines, ResetLine::Dtr, QUICK).unwrap();
+        assert_eq!(lines.writes, vec![("DTR", false), ("DTR", true)]);
+
+        let mut lines = RecordingLines::default();
+        pulse_reset(&mut lines, ResetLine::Rts, QUICK).unwrap();
+        assert_eq!(lines.writes, vec![("RTS", false), ("RTS", true)]);
+
+        let mut lines = RecordingLines::default();
+        pulse_reset(&mut lines, ResetLine::Both, QUICK).unwrap();
+        assert_eq!(
+            lines.writes,
+            vec![("DTR", false), ("RTS", false), ("DTR", true), ("RTS", true)]
+        );
+
+        // Touch reset boards are already in the bootloader, nothing to toggle
+        let mut lines = RecordingLines::default();
+        pulse_reset(&mut lines, ResetLine::Touch1200, QUICK).unwrap();
+        assert!(lines.writes.is_empty());
+    }
+
+    #[test]
+    fn test_bootloader_port_is_the_newly_listed_one() {
+        let port = |name: &str| SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: serialport::SerialPortType::Unknown,
+        };
+        let before = vec![port("COM3"), port("COM5")];
+
+        assert_eq!(newly_listed_port(&before, &[port("COM3")]), None);
+        assert_eq!(
+            newly_listed_port(&before, &[port("COM3"), port("COM7")]),
+            Some(String::from("COM7"))
+        );
+    }
+
+    /// Records every write call
+    #[derive(Default)]
+    struct RecordingWriter {
+        writes: Vec<Vec<u8>>,
+    }
+
+    impl Write for RecordingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_inter_byte_delay_writes_bytes_individually() {
+        let command = [0x30, 0x20, 0x41];
+
+        let mut writer = RecordingWriter::default();
+        write_command(&mut writer, &command, std::time::Duration::ZERO).unwrap();
+        assert_eq!(writer.writes, vec![command.to_vec()]);
+
+        let mut writer = RecordingWriter::default();
+        write_command(&mut writer, &command, std::time::Duration::from_micros(10)).unwrap();
+        assert_eq!(writer.writes, vec![vec![0x30], vec![0x20], vec![0x41]]);
+    }
+
+    /// Writer for a port that went away
+    struct DisconnectedWriter;
+
+    impl Write for DisconnectedWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_error_keeps_its_kind() {
+        match write_command(&mut DisconnectedWriter, &[0x30, 0x20], Duration::ZERO) {
+            Err(AvrError::Serial(e)) => assert_eq!(
+                e.kind(),
+                serialport::ErrorKind::Io(std::io::ErrorKind::BrokenPipe)
+            ),
+            result => panic!("Expected a serial port error, got {:?}", result),
+        }
     }
 }
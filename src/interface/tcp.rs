@@ -0,0 +1,490 @@
+use super::DeviceInterface;
+use super::serialport::{ControlLines, ResetLine, ResetTiming, pulse_reset};
+use crate::constants::{MAX_RESPONSE_SIZE, RFC2217_NEGOTIATION_TIMEOUT_MS, SERIAL_TIMEOUT_MS};
+use crate::error::{AvrError, AvrResult};
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Telnet command bytes (RFC 854)
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+/// Telnet option for controlling the remote serial port (RFC 2217), and the
+/// SET-CONTROL values for its modem lines
+const COM_PORT_OPTION: u8 = 44;
+const SET_CONTROL: u8 = 5;
+const DTR_ON: u8 = 8;
+const DTR_OFF: u8 = 9;
+const RTS_ON: u8 = 11;
+const RTS_OFF: u8 = 12;
+
+/// Serial port exported over TCP, eg: by ser2net on a Raspberry Pi in a
+/// board farm. The protocols run over it unchanged
+///
+/// A raw socket carries no modem control lines, and reset fails with
+/// NoResetSupported. Servers speaking RFC 2217 (telnet COM port control)
+/// can toggle DTR/RTS on the remote port, see connect_rfc2217
+pub struct TcpDeviceInterface {
+    stream: TcpStream,
+
+    /// Telnet session state, None on a raw stream
+    telnet: Option<Telnet>,
+    reset_line: ResetLine,
+    reset_timing: ResetTiming,
+}
+
+/// Where the decoder is within the telnet byte stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum TelnetState {
+    #[default]
+    Data,
+    Iac,
+
+    /// Waiting for the option of a WILL/WONT/DO/DONT
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// How the server answered our offer to control its serial port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ComPortControl {
+    Offered,
+    Accepted,
+    Refused,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Telnet {
+    state: TelnetState,
+    com_port: ComPortControl,
+}
+
+impl Telnet {
+    /// Strip telnet commands from `raw` and return the serial data in it.
+    /// Options the server asks for, other than COM port control, are
+    /// refused through `replies`
+    fn decode(&mut self, raw: &[u8], replies: &mut Vec<u8>) -> Vec<u8> {
+        let mut data = Vec::with_capacity(raw.len());
+
+        for &byte in raw {
+            self.state = match (self.state, byte) {
+                (TelnetState::Data, IAC) => TelnetState::Iac,
+                (TelnetState::Data, byte) => {
+                    data.push(byte);
+                    TelnetState::Data
+                }
+                (TelnetState::Iac, IAC) => {
+                    data.push(IAC);
+                    TelnetState::Data
+                }
+                (TelnetState::Iac, command @ (WILL | WONT | DO | DONT)) => {
+                    TelnetState::Negotiation(command)
+                }
+                (TelnetState::Iac, SB) => TelnetState::Subnegotiation,
+                (TelnetState::Iac, _) => TelnetState::Data,
+                (TelnetState::Negotiation(command), option) => {
+                    self.negotiate(command, option, replies);
+                    TelnetState::Data
+                }
+                // Notifications from the server (line state, modem state)
+                // aren't needed and are dropped
+                (TelnetState::Subnegotiation, IAC) => TelnetState::SubnegotiationIac,
+                (TelnetState::Subnegotiation, _) => TelnetState::Subnegotiation,
+                (TelnetState::SubnegotiationIac, SE) => TelnetState::Data,
+                (TelnetState::SubnegotiationIac, _) => TelnetState::Subnegotiation,
+            };
+        }
+        data
+    }
+
+    fn negotiate(&mut self, command: u8, option: u8, replies: &mut Vec<u8>) {
+        match (command, option) {
+            (DO, COM_PORT_OPTION) => self.com_port = ComPortControl::Accepted,
+            (DONT, COM_PORT_OPTION) => self.com_port = ComPortControl::Refused,
+            (DO, option) => replies.extend([IAC, WONT, option]),
+            (WILL, option) => replies.extend([IAC, DONT, option]),
+            _ => {}
+        }
+    }
+}
+
+/// Double every IAC byte in serial data, as the telnet stream requires
+fn escape(command: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(command.len());
+    for &byte in command {
+        if byte == IAC {
+            escaped.push(IAC);
+        }
+        escaped.push(byte);
+    }
+    escaped
+}
+
+impl TcpDeviceInterface {
+    /// Connect to the serial server at `host:port`, which relays the serial
+    /// data as is
+    pub fn connect(host: &str, port: u16) -> AvrResult<Self> {
+        let stream = TcpStream::connect((host, port)).map_err(|e| {
+            AvrError::NetworkError(format!("Could not connect to {}:{}: {}", host, port, e))
+        })?;
+        stream.set_nodelay(true).map_err(tcp_error)?;
+        stream
+            .set_read_timeout(Some(Duration::from_millis(SERIAL_TIMEOUT_MS)))
+            .map_err(tcp_error)?;
+
+        debug!("Connected to {}:{}", host, port);
+        Ok(TcpDeviceInterface {
+            stream,
+            telnet: None,
+            reset_line: ResetLine::default(),
+            reset_timing: ResetTiming::default(),
+        })
+    }
+
+    /// Connect to an RFC 2217 server at `host:port` and ask to control its
+    /// serial port, so reset can pulse `reset_line` on it. When the server
+    /// refuses, or doesn't answer, the connection is still usable for data
+    /// but reset fails with NoResetSupported
+    pub fn connect_rfc2217(
+        host: &str,
+        port: u16,
+        reset_line: ResetLine,
+        reset_timing: ResetTiming,
+    ) -> AvrResult<Self> {
+        let mut device = Self::connect(host, port)?;
+        device.reset_line = reset_line;
+        device.reset_timing = reset_timing;
+        device.telnet = Some(Telnet {
+            state: TelnetState::default(),
+            com_port: ComPortControl::Offered,
+        });
+
+        device
+            .stream
+            .write_all(&[IAC, WILL, COM_PORT_OPTION])
+            .map_err(tcp_error)?;
+
+        // Serial data arriving in the meantime is of no use before a reset
+        let deadline = Instant::now() + Duration::from_millis(RFC2217_NEGOTIATION_TIMEOUT_MS);
+        while device.com_port() == Some(ComPortControl::Offered) && Instant::now() < deadline {
+            device.receive()?;
+        }
+
+        match device.com_port() {
+            Some(ComPortControl::Accepted) => debug!("Server accepted RFC 2217 port control"),
+            _ => warn!(
+                "{}:{} doesn't support RFC 2217, the board has to be reset by hand",
+                host, port
+            ),
+        }
+        Ok(device)
+    }
+
+    /// Whether reset can toggle the remote port's control lines, ie: the
+    /// server accepted RFC 2217 port control
+    pub fn can_reset(&self) -> bool {
+        self.com_port() == Some(ComPortControl::Accepted)
+    }
+
+    fn com_port(&self) -> Option<ComPortControl> {
+        self.telnet.map(|telnet| telnet.com_port)
+    }
+
+    /// Pass raw bytes from the server through the telnet decoder, answering
+    /// any option requests in them
+    fn decode(&mut self, raw: Vec<u8>) -> AvrResult<Vec<u8>> {
+        let Some(telnet) = self.telnet.as_mut() else {
+            return Ok(raw);
+        };
+
+        let mut replies = Vec::new();
+        let data = telnet.decode(&raw, &mut replies);
+        if !replies.is_empty() {
+            self.stream.write_all(&replies).map_err(tcp_error)?;
+        }
+        Ok(data)
+    }
+
+    fn set_control(&mut self, value: u8) -> AvrResult<()> {
+        self.stream
+            .write_all(&[IAC, SB, COM_PORT_OPTION, SET_CONTROL, value, IAC, SE])
+            .map_err(tcp_error)
+    }
+}
+
+fn tcp_error(e: std::io::Error) -> AvrError {
+    AvrError::Communication(format!("TCP error: {}", e))
+}
+
+/// A read that ran into its timeout, which only means nothing arrived
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+impl ControlLines for TcpDeviceInterface {
+    fn write_dtr(&mut self, level: bool) -> AvrResult<()> {
+        self.set_control(if level { DTR_ON } else { DTR_OFF })
+    }
+
+    fn write_rts(&mut self, level: bool) -> AvrResult<()> {
+        self.set_control(if level { RTS_ON } else { RTS_OFF })
+    }
+}
+
+impl DeviceInterface for TcpDeviceInterface {
+    fn send(&mut self, command: Vec<u8>) -> AvrResult<()> {
+        let command = match self.telnet {
+            Some(_) => escape(&command),
+            None => command,
+        };
+        self.stream.write_all(&command).map_err(tcp_error)
+    }
+
+    fn receive(&mut self) -> AvrResult<Vec<u8>> {
+        let mut buffer: Vec<u8> = vec![0; MAX_RESPONSE_SIZE];
+
+        let size = match self.stream.read(&mut buffer) {
+            Ok(0) => {
+                return Err(AvrError::Communication(String::from(
+                    "TCP connection closed by the server",
+                )));
+            }
+            Ok(size) => size,
+            Err(e) if is_timeout(&e) => 0,
+            Err(e) => return Err(tcp_error(e)),
+        };
+
+        buffer.truncate(size);
+        self.decode(buffer)
+    }
+
+    fn reset(&mut self) -> AvrResult<()> {
+        if self.com_port() != Some(ComPortControl::Accepted) {
+            return Err(AvrError::NoResetSupported);
+        }
+        let (reset_line, reset_timing) = (self.reset_line, self.reset_timing);
+        pulse_reset(self, reset_line, reset_timing)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> AvrResult<()> {
+        self.stream
+            .set_read_timeout(Some(timeout))
+            .map_err(tcp_error)
+    }
+
+    fn flush(&mut self) -> AvrResult<()> {
+        self.stream.set_nonblocking(true).map_err(tcp_error)?;
+        let mut buffer = [0; MAX_RESPONSE_SIZE];
+        let drained = loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => break Ok(()),
+                // Still decoded, so telnet commands among the dropped data
+                // are seen
+                Ok(size) => {
+                    if let Err(e) = self.decode(buffer[..size].to_vec()) {
+                        break Err(e);
+                    }
+                }
+                Err(e) if is_timeout(&e) => break Ok(()),
+                Err(e) => break Err(tcp_error(e)),
+            }
+        };
+        self.stream.set_nonblocking(false).map_err(tcp_error)?;
+        drained
+    }
+
+    fn try_clone_sender(&self) -> Option<Box<dyn DeviceInterface + Send>> {
+        match self.stream.try_clone() {
+            Ok(stream) => Some(Box::new(TcpDeviceInterface {
+                stream,
+                telnet: self.telnet,
+                reset_line: self.reset_line,
+                reset_timing: self.reset_timing,
+            })),
+            Err(e) => {
+                debug!("Could not clone TCP stream, sharing one handle: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// Server that echoes back everything it receives, until the client
+    /// hangs up
+    fn echo_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; 64];
+            while let Ok(size @ 1..) = stream.read(&mut buffer) {
+                stream.write_all(&buffer[..size]).unwrap();
+            }
+        });
+        port
+    }
+
+    /// RFC 2217 server that answers the COM port option offer with `answer`,
+    /// then records everything else the client sends. `greeting` goes out
+    /// once the client sent something, so it isn't taken for noise during
+    /// the negotiation
+    fn rfc2217_server(answer: u8, greeting: Vec<u8>) -> (u16, Arc<Mutex<Vec<u8>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&received);
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut offer = [0; 3];
+            stream.read_exact(&mut offer).unwrap();
+            assert_eq!(offer, [IAC, WILL, COM_PORT_OPTION]);
+            stream.write_all(&[IAC, answer, COM_PORT_OPTION]).unwrap();
+
+            let mut greeting = Some(greeting);
+            let mut buffer = [0; 64];
+            while let Ok(size @ 1..) = stream.read(&mut buffer) {
+                log.lock().unwrap().extend(&buffer[..size]);
+                if let Some(greeting) = greeting.take() {
+                    stream.write_all(&greeting).unwrap();
+                }
+            }
+        });
+        (port, received)
+    }
+
+    const QUICK: ResetTiming = ResetTiming {
+        low_micros: 1,
+        bootup_delay_ms: 0,
+    };
+
+    /// Wait for the server to have recorded `len` bytes
+    fn recorded(received: &Mutex<Vec<u8>>, len: usize) -> Vec<u8> {
+        let start = Instant::now();
+        while received.lock().unwrap().len() < len {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        received.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn test_bytes_are_relayed_over_the_connection() {
+        let mut device = TcpDeviceInterface::connect("127.0.0.1", echo_server()).unwrap();
+
+        // Nothing sent yet, the read times out empty
+        assert!(device.receive().unwrap().is_empty());
+
+        device.send(vec![0x30, 0x20]).unwrap();
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            received.extend(device.receive().unwrap());
+        }
+        assert_eq!(received, vec![0x30, 0x20]);
+    }
+
+    #[test]
+    fn test_unreachable_server_is_a_network_error() {
+        // Bind and drop to get a port nobody listens on
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        assert!(matches!(
+            TcpDeviceInterface::connect("127.0.0.1", port),
+            Err(AvrError::NetworkError(_))
+        ));
+    }
+
+    #[test]
+    fn test_raw_connection_cannot_reset() {
+        let mut device = TcpDeviceInterface::connect("127.0.0.1", echo_server()).unwrap();
+        assert!(matches!(device.reset(), Err(AvrError::NoResetSupported)));
+    }
+
+    #[test]
+    fn test_rfc2217_reset_sends_control_sequences() {
+        let (port, received) = rfc2217_server(DO, vec![]);
+        let mut device =
+            TcpDeviceInterface::connect_rfc2217("127.0.0.1", port, ResetLine::Both, QUICK).unwrap();
+
+        device.reset().unwrap();
+
+        let control = |value| [IAC, SB, COM_PORT_OPTION, SET_CONTROL, value, IAC, SE];
+        let expected: Vec<u8> = [
+            control(DTR_OFF),
+            control(RTS_OFF),
+            control(DTR_ON),
+            control(RTS_ON),
+        ]
+        .concat();
+        assert_eq!(recorded(&received, expected.len()), expected);
+    }
+
+    #[test]
+    fn test_rfc2217_refused_falls_back_to_no_reset() {
+        let (port, _received) = rfc2217_server(DONT, vec![]);
+        let mut device =
+            TcpDeviceInterface::connect_rfc2217("127.0.0.1", port, ResetLine::Both, QUICK).unwrap();
+
+        assert!(!device.can_reset());
+        assert!(matches!(device.reset(), Err(AvrError::NoResetSupported)));
+    }
+
+    #[test]
+    fn test_telnet_commands_and_escapes_are_stripped_from_data() {
+        // Serial data with an escaped 0xFF, split by a modem state
+        // notification and a request to echo
+        let greeting = vec![
+            0x14,
+            IAC,
+            IAC,
+            IAC,
+            SB,
+            COM_PORT_OPTION,
+            107,
+            0x30,
+            IAC,
+            SE,
+            IAC,
+            WILL,
+            1,
+            0x10,
+        ];
+        let (port, received) = rfc2217_server(DO, greeting);
+        let mut device =
+            TcpDeviceInterface::connect_rfc2217("127.0.0.1", port, ResetLine::Both, QUICK).unwrap();
+
+        // 0xFF in data goes out doubled
+        device.send(vec![0xFF, 0x01]).unwrap();
+        assert_eq!(recorded(&received, 3), vec![IAC, IAC, 0x01]);
+
+        let mut data = Vec::new();
+        let start = Instant::now();
+        while data.len() < 3 {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            data.extend(device.receive().unwrap());
+        }
+        assert_eq!(data, vec![0x14, 0xFF, 0x10]);
+
+        // The echo request is refused
+        assert_eq!(recorded(&received, 6), vec![IAC, IAC, 0x01, IAC, DONT, 1]);
+    }
+}
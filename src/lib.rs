@@ -1,30 +1,130 @@
 #![doc = include_str!("../README.md")]
 
-use std::{fs::File, io::Read};
+#[cfg(feature = "async")]
+use std::sync::Arc;
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 
-pub use boards::Microcontroller;
-use boards::protocol_for_mcu;
+use boards::mcu_for_signature;
+pub use boards::{McuProfile, Microcontroller, PortInfo, list_ports};
+use boards::{board_specs_json, custom_stk500v2_protocol, protocol_for_mcu, protocol_for_profile};
+use builder::ProgrammerBuilder;
+use constants::DEFAULT_DUMP_TRIM_THRESHOLD;
+use container::Container;
+use diff::Mismatch;
 use error::{AvrError, AvrResult};
-use ihex::Reader;
-use interface::DeviceInterfaceType;
-use protocols::{ProgrammerTrait, stk500v1::Stk500v1Params, stk500v2::Stk500v2Params};
+use fuses::{EepromOnErase, Fuses};
+use interface::{
+    DeviceInterface, DeviceInterfaceType,
+    loopback::LoopbackInterface,
+    recording::{CommandLog, RecordingInterface},
+    serialport::{ResetLine, ResetTiming},
+    tcp::TcpDeviceInterface,
+};
+use preflight::Preflight;
+use progress::ProgressEvent;
+use protocols::{
+    ProgrammerTrait, isp::READ_CALIBRATION_BYTE, stk500v1::Stk500v1Params, stk500v2::Stk500v2Params,
+};
+use provision::{FirmwareSource, ProvisionPlan, ProvisionReport, step_failed};
+use report::ProgramReport;
+use tracing::warn;
+use util::{
+    binary_to_intel_hex, binary_to_records, intel_hex_to_binary, srec::srec_to_binary,
+    trim_erased_tail,
+};
 
 pub mod boards;
+pub mod builder;
 pub(crate) mod constants;
+pub mod container;
+pub mod diff;
 pub mod error;
+pub mod fuses;
+#[cfg(feature = "http")]
+pub(crate) mod http;
 pub mod interface;
+pub mod preflight;
+pub mod progress;
 pub mod protocols;
+pub mod provision;
+pub mod report;
 pub(crate) mod util;
 
+/// Work out which supported MCU is attached to `port` by syncing with its
+/// bootloader and reading the signature. STK500v1 is tried first, then an
+/// STK500v2 sign-on (eg: Arduino Mega), resetting the board over DTR/RTS
+/// each time. When several boards share the signature, the bare chip (eg:
+/// Atmega328p for an Uno) is returned.
+///
+/// Boards entering their bootloader through a 1200 baud touch (Leonardo,
+/// ATmega32u4) can't be detected: the bootloader comes up on a port of its
+/// own, and a sketch running on the board doesn't answer either protocol
+pub fn detect_mcu(port: &str, baud: u32) -> AvrResult<Microcontroller> {
+    // The signature isn't checked when only reading it
+    let v1 = ProtocolType::Stk500v1(Stk500v1Params {
+        port: port.to_owned(),
+        baud,
+        device_signature: vec![],
+        ..Default::default()
+    });
+    let v2 = ProtocolType::Stk500v2(Stk500v2Params {
+        port: port.to_owned(),
+        baud,
+        device_signature: vec![],
+        ..Default::default()
+    });
+
+    // Each programmer is dropped after reading, freeing the port for the next
+    let read_signature =
+        |protocol| Programmer::from_protocol(protocol).and_then(|p| p.read_signature());
+    let signature = match read_signature(v1) {
+        Ok(signature) => signature,
+        Err(v1_error) => read_signature(v2).map_err(|v2_error| {
+            AvrError::ProgrammerError(format!(
+                "No answer over STK500v1 ({}) or STK500v2 ({})",
+                v1_error, v2_error
+            ))
+        })?,
+    };
+
+    mcu_for_signature(&signature)
+}
+
 pub enum ProtocolType {
     Stk500v1(Stk500v1Params),
+
+    /// Stk500v1 without the background sender and receiver threads: every
+    /// command and answer goes over the serial port on the calling thread.
+    /// Lighter on small embedded hosts, at the cost of some throughput
+    BlockingStk500v1(Stk500v1Params),
     Stk500v2(Stk500v2Params),
 }
 
+impl ProtocolType {
+    /// Reset line(s) and timing the board is reset with
+    fn reset(&self) -> (ResetLine, ResetTiming) {
+        match self {
+            ProtocolType::Stk500v1(params) | ProtocolType::BlockingStk500v1(params) => {
+                (params.reset_line, params.reset_timing)
+            }
+            ProtocolType::Stk500v2(params) => (params.reset_line, params.reset_timing),
+        }
+    }
+}
+
 pub struct Programmer {
     programmer: Box<dyn ProgrammerTrait>,
     verify: bool,
     progress_bar_enable: bool,
+    dump_trim_threshold: usize,
+    retries: u32,
 }
 
 impl Programmer {
@@ -36,19 +136,76 @@ impl Programmer {
         Self::from_protocol(protocol)
     }
 
+    /// Configure a programmer one setting at a time, see ProgrammerBuilder
+    pub fn builder() -> ProgrammerBuilder {
+        ProgrammerBuilder::new()
+    }
+
     /// Create a programmer with a specific set of protocol parameters. This is can be used to program boards
     /// for which there is no official support on avrman, that use the Stk500v1 protocol
     pub fn from_protocol(protocol: ProtocolType) -> AvrResult<Self> {
         let programmer: Box<dyn ProgrammerTrait> = match protocol {
             ProtocolType::Stk500v1(params) => Box::new(protocols::stk500v1::Stk500v1::new(params)?),
+            ProtocolType::BlockingStk500v1(params) => {
+                Box::new(protocols::stk500v1::Stk500v1::new_blocking(params)?)
+            }
             ProtocolType::Stk500v2(params) => Box::new(protocols::stk500v2::Stk500v2::new(params)?),
         };
 
-        Ok(Programmer {
+        Ok(Self::with_programmer(programmer))
+    }
+
+    /// Create a programmer that talks to the target through the given device interface
+    /// instead of opening a serial port. The port and baud rate in the protocol
+    /// parameters are ignored
+    pub fn from_protocol_and_interface(
+        protocol: ProtocolType,
+        interface: Box<dyn DeviceInterface + Send>,
+    ) -> AvrResult<Self> {
+        let programmer: Box<dyn ProgrammerTrait> = match protocol {
+            ProtocolType::Stk500v1(params) => Box::new(
+                protocols::stk500v1::Stk500v1::with_interface(params, interface),
+            ),
+            ProtocolType::BlockingStk500v1(params) => Box::new(
+                protocols::stk500v1::Stk500v1::blocking_with_interface(params, interface),
+            ),
+            ProtocolType::Stk500v2(params) => Box::new(
+                protocols::stk500v2::Stk500v2::with_interface(params, interface),
+            ),
+        };
+
+        Ok(Self::with_programmer(programmer))
+    }
+
+    /// Create a programmer that talks to a simulated STK500v1 bootloader
+    /// instead of a board, recording every command it sends. Program as
+    /// usual, then read the exact command byte stream from the returned log.
+    /// The port and baud rate in the protocol parameters are ignored
+    pub fn dry_run(protocol: ProtocolType) -> AvrResult<(Self, CommandLog)> {
+        let ProtocolType::Stk500v1(params) = protocol else {
+            return Err(AvrError::ConfigurationError(String::from(
+                "Dry runs are only supported for Stk500v1 boards",
+            )));
+        };
+
+        let flash_size = params.page_size as usize * params.num_pages as usize;
+        let device = LoopbackInterface::new(params.device_signature.clone(), flash_size);
+        let interface = RecordingInterface::new(Box::new(device));
+        let commands = interface.commands();
+
+        let programmer =
+            Self::from_protocol_and_interface(ProtocolType::Stk500v1(params), Box::new(interface))?;
+        Ok((programmer, commands))
+    }
+
+    fn with_programmer(programmer: Box<dyn ProgrammerTrait>) -> Self {
+        Programmer {
             programmer,
             progress_bar_enable: false,
             verify: true,
-        })
+            dump_trim_threshold: DEFAULT_DUMP_TRIM_THRESHOLD,
+            retries: 0,
+        }
     }
 
     /// Create a programmer for a given MCU, with interface parameters (eg: for a COM port,
@@ -58,73 +215,628 @@ impl Programmer {
         mcu: Microcontroller,
         interface: DeviceInterfaceType,
     ) -> AvrResult<Self> {
-        let protocol = protocol_for_mcu(mcu, Some(interface))?;
+        let protocol = protocol_for_mcu(mcu, Some(interface.clone()))?;
+        Self::from_protocol_over(protocol, Some(interface))
+    }
+
+    /// Create a programmer for a board described by a profile rather than the
+    /// Microcontroller enum. As with Programmer::new, the serial port is found
+    /// by product ID unless `interface` gives one
+    pub fn from_profile(
+        profile: McuProfile,
+        interface: Option<DeviceInterfaceType>,
+    ) -> AvrResult<Self> {
+        let protocol = protocol_for_profile(profile, interface.clone())?;
+        Self::from_protocol_over(protocol, interface)
+    }
+
+    /// Connect to the board over `interface` when it isn't a local serial
+    /// port, which the protocol opens itself
+    fn from_protocol_over(
+        protocol: ProtocolType,
+        interface: Option<DeviceInterfaceType>,
+    ) -> AvrResult<Self> {
+        match interface {
+            Some(DeviceInterfaceType::Tcp { host, port }) => {
+                let mut programmer = Self::from_protocol_and_interface(
+                    protocol,
+                    Box::new(TcpDeviceInterface::connect(&host, port)?),
+                )?;
+                // Nothing to reset the board with over a raw stream
+                programmer.set_auto_reset(false);
+                Ok(programmer)
+            }
+            Some(DeviceInterfaceType::Rfc2217 { host, port }) => {
+                let (reset_line, reset_timing) = protocol.reset();
+                let interface =
+                    TcpDeviceInterface::connect_rfc2217(&host, port, reset_line, reset_timing)?;
+                let can_reset = interface.can_reset();
+                let mut programmer =
+                    Self::from_protocol_and_interface(protocol, Box::new(interface))?;
+                // A server refusing port control is no better than a raw
+                // stream, the board has to be in its bootloader already
+                if !can_reset {
+                    programmer.set_auto_reset(false);
+                }
+                Ok(programmer)
+            }
+            _ => Self::from_protocol(protocol),
+        }
+    }
+
+    /// Create a programmer for a board that uses the Stk500v2 protocol but isn't
+    /// officially supported. The page size and number of pages are validated
+    /// before the serial port is opened
+    pub fn custom_stk500v2(
+        port: &str,
+        baud: u32,
+        signature: Vec<u8>,
+        page_size: u16,
+        num_pages: u16,
+    ) -> AvrResult<Self> {
+        let protocol = custom_stk500v2_protocol(port, baud, signature, page_size, num_pages)?;
         Self::from_protocol(protocol)
     }
 
+    /// JSON description of every supported board (signature, page size,
+    /// flash size, baud rate and protocol), for tools that want to reuse
+    /// avrman's board knowledge
+    pub fn board_specs_json() -> String {
+        board_specs_json()
+    }
+
     /// Enable or disable a progress bar during programming/verify
-    /// Progress bar is disabled by default
+    /// Progress bar is disabled by default, and this does nothing when built
+    /// without the `progress` feature
     pub fn progress_bar(&mut self, enable: bool) {
         self.progress_bar_enable = enable;
     }
 
+    /// Call `callback` after every page programmed or verified, with a
+    /// rolling bytes/sec estimate in each event. Independent of the progress bar
+    pub fn on_progress(&mut self, callback: impl Fn(ProgressEvent) + Send + Sync + 'static) {
+        self.programmer.set_progress_callback(Box::new(callback));
+    }
+
+    /// Send every progress event to the returned channel instead of a
+    /// callback, for async code that shouldn't block inside on_progress.
+    /// Replaces any callback set with on_progress
+    #[cfg(feature = "async")]
+    pub fn progress_channel(&mut self) -> tokio::sync::mpsc::UnboundedReceiver<ProgressEvent> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.forward_progress(move |event| sender.send(event));
+        receiver
+    }
+
+    /// Hand every progress event to `send`, replacing any callback set with
+    /// on_progress. A receiver that went away is no reason to stop
+    /// programming, so `send` failing is ignored
+    fn forward_progress<E>(
+        &mut self,
+        send: impl Fn(ProgressEvent) -> Result<(), E> + Send + Sync + 'static,
+    ) {
+        self.on_progress(move |event| {
+            let _ = send(event);
+        });
+    }
+
+    /// Set how long each serial read waits for data, in milliseconds. Raising
+    /// it from the default of 1ms trades throughput for reliability on high
+    /// latency or flaky USB-serial adapters
+    pub fn with_timeout_ms(self, timeout_ms: u64) -> AvrResult<Self> {
+        self.programmer.set_timeout_ms(timeout_ms)?;
+        Ok(self)
+    }
+
+    /// Give up on any operation (programming, reading fuses, ...) that is
+    /// still waiting for the board `deadline` after it started, with a
+    /// communication error. Without one, each response from the board is
+    /// waited for up to 2 seconds instead
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.programmer.set_deadline(deadline);
+        self
+    }
+
     /// Enable or disable verification after programming
     /// Enabled by default
     pub fn verify_after_programming(&mut self, enable: bool) {
         self.verify = enable;
     }
 
-    /// Parse intel hex file raw string to binary
-    fn parse_intel_hex(&self, hex_content: &str) -> AvrResult<Vec<u8>> {
-        let mut bin = Vec::new();
-        let parser = Reader::new(hex_content);
-        for record in parser {
-            match record {
-                Ok(rec) => {
-                    if let ihex::Record::Data { value, .. } = rec {
-                        bin.extend_from_slice(&value);
-                    }
-                }
-                Err(e) => {
-                    return Err(AvrError::ProgrammerError(format!(
-                        "Failed parsing record in hex file {:?}",
-                        e
-                    )));
-                }
-            }
+    /// Run the whole programming sequence, reset and sync included, up to `n`
+    /// more times when it fails with a programmer or communication error. Helps
+    /// with boards whose auto-reset only works some of the time. Off (0) by
+    /// default; the last error is returned if every attempt fails
+    pub fn set_retries(&mut self, n: u32) {
+        self.retries = n;
+    }
+
+    /// When enabled, programming parses the image and runs every check on it,
+    /// then walks the page loop without opening a session with the target or
+    /// writing anything. The report counts the pages that would be written.
+    /// Useful as a hardware free lint of firmware images, eg: in CI
+    pub fn set_dry_run(&mut self, enable: bool) {
+        self.programmer.set_dry_run(enable);
+    }
+
+    /// Reset the board before programming (the default). Turn off for boards
+    /// without an auto-reset circuit that are already waiting in their
+    /// bootloader, where toggling DTR/RTS does nothing or gets in the way
+    pub fn set_auto_reset(&mut self, enable: bool) {
+        self.programmer.set_auto_reset(enable);
+    }
+
+    /// Only verify the flash pages covering `len` bytes from `start` after
+    /// programming, instead of the whole image. The range has to lie within
+    /// the flash, and within the image when it is programmed
+    pub fn verify_range(&mut self, start: usize, len: usize) -> AvrResult<()> {
+        let flash_size = self.programmer.flash_size();
+        if len == 0 || start.saturating_add(len) > flash_size {
+            return Err(AvrError::ConfigurationError(format!(
+                "Verify range {:#x}+{:#x} is outside the {} byte flash",
+                start, len, flash_size
+            )));
         }
 
-        Ok(bin)
+        self.programmer.set_verify_range(Some(start..start + len));
+        Ok(())
+    }
+
+    /// Refuse to program unless the chip matches the expected signature, and
+    /// fuses and lock bits when given. Any mismatch aborts programming before
+    /// anything is written. Needs an ISP programmer, as bootloaders don't
+    /// answer fuse reads
+    pub fn require_preflight(&mut self, expected: Preflight) -> AvrResult<()> {
+        self.programmer.set_preflight(expected)
+    }
+
+    /// Minimum length of a trailing run of 0xFF bytes that is treated as erased
+    /// flash and left out of dump_hex. Defaults to 1 (trim every trailing 0xFF)
+    pub fn dump_trim_threshold(&mut self, bytes: usize) {
+        self.dump_trim_threshold = bytes;
     }
 
     /// Program board with provided intelhex file
-    pub fn program_hex_file(&self, file_path: &str) -> AvrResult<()> {
-        let mut file = File::open(file_path)
+    pub fn program_hex_file(&self, file_path: impl AsRef<Path>) -> AvrResult<ProgramReport> {
+        let file = File::open(file_path)
             .map_err(|e| AvrError::FirmwareError(format!("Failed to read file: {}", e)))?;
+
+        self.program_hex_reader(file)
+    }
+
+    /// Program an intelhex file without blocking the async executor. The file
+    /// is read and programmed on tokio's blocking thread pool, which is why
+    /// the programmer is shared through an Arc. Progress events arrive on the
+    /// receiver from progress_channel
+    #[cfg(feature = "async")]
+    pub async fn program_hex_file_async(
+        self: Arc<Self>,
+        file_path: impl AsRef<Path>,
+    ) -> AvrResult<ProgramReport> {
+        let file_path = file_path.as_ref().to_path_buf();
+        tokio::task::spawn_blocking(move || self.program_hex_file(file_path))
+            .await
+            .map_err(|e| AvrError::ProgrammerError(format!("Programming task failed: {}", e)))?
+    }
+
+    /// Program `source` on a new thread, streaming a progress event per page
+    /// programmed or verified over the returned channel, which suits GUIs and
+    /// TUIs with their own event loop. The channel closes once programming
+    /// finishes. The programmer moves to the thread and is dropped there,
+    /// releasing the port. Replaces any callback set with on_progress
+    pub fn program_with_progress(
+        mut self,
+        source: FirmwareSource,
+    ) -> (
+        JoinHandle<AvrResult<ProgramReport>>,
+        mpsc::Receiver<ProgressEvent>,
+    ) {
+        let (sender, receiver) = mpsc::channel();
+        self.forward_progress(move |event| sender.send(event));
+
+        let handle = thread::spawn(move || self.program_binary(source.to_binary()?));
+        (handle, receiver)
+    }
+
+    /// Program intelhex read to the end from `reader`, eg: stdin or a TCP
+    /// stream, without going through a file
+    pub fn program_hex_reader<R: Read>(&self, mut reader: R) -> AvrResult<ProgramReport> {
         let mut hex_content = String::new();
-        file.read_to_string(&mut hex_content).map_err(|e| {
+        reader.read_to_string(&mut hex_content).map_err(|e| {
             AvrError::FirmwareError(format!("Could not read given hex file to string {:?}", e))
         })?;
 
-        let bin = self.parse_intel_hex(&hex_content)?;
-        self.programmer
-            .program_firmware(bin, self.verify, self.progress_bar_enable)?;
-
-        Ok(())
+        self.program_hex_buffer(&hex_content)
     }
 
     /// Program provided intelhex, provided as string read from a .hex file
-    pub fn program_hex_buffer(&self, hex_content: &str) -> AvrResult<()> {
-        let bin = self.parse_intel_hex(hex_content)?;
-        self.programmer
-            .program_firmware(bin, self.verify, self.progress_bar_enable)?;
-        Ok(())
+    pub fn program_hex_buffer(&self, hex_content: &str) -> AvrResult<ProgramReport> {
+        let bin = intel_hex_to_binary(hex_content)?;
+        self.program_with_retries(bin, 0)
+    }
+
+    /// Download an intelhex file from an http(s) URL and program it. The download
+    /// is refused if it is larger than 1MB or isn't served as text or binary data
+    #[cfg(feature = "http")]
+    pub fn program_hex_url(&self, url: &str) -> AvrResult<ProgramReport> {
+        let hex_content = http::fetch_hex(url)?;
+        self.program_hex_buffer(&hex_content)
+    }
+
+    /// Program board with provided Motorola S-record (.srec/.s19) file
+    pub fn program_srec_file(&self, file_path: impl AsRef<Path>) -> AvrResult<ProgramReport> {
+        let srec_content = std::fs::read_to_string(file_path)
+            .map_err(|e| AvrError::FirmwareError(format!("Failed to read file: {}", e)))?;
+
+        self.program_srec_buffer(&srec_content)
+    }
+
+    /// Program provided Motorola S-records, provided as string read from a .srec file
+    pub fn program_srec_buffer(&self, srec_content: &str) -> AvrResult<ProgramReport> {
+        let bin = srec_to_binary(srec_content)?;
+        self.program_with_retries(bin, 0)
+    }
+
+    /// Program a raw binary image (eg: PlatformIO's firmware.bin). The image
+    /// is assumed to start at flash address 0
+    pub fn program_bin_file(&self, file_path: impl AsRef<Path>) -> AvrResult<ProgramReport> {
+        let file_path = file_path.as_ref();
+        let bin = std::fs::read(file_path)
+            .map_err(|e| AvrError::FirmwareError(format!("Failed to read file: {}", e)))?;
+        if bin.is_empty() {
+            return Err(AvrError::FirmwareError(format!(
+                "Binary file {} is empty",
+                file_path.display()
+            )));
+        }
+
+        self.program_with_retries(bin, 0)
     }
 
     /// Program binary data
-    pub fn program_binary(&self, bin: Vec<u8>) -> AvrResult<()> {
+    pub fn program_binary(&self, bin: Vec<u8>) -> AvrResult<ProgramReport> {
+        self.program_with_retries(bin, 0)
+    }
+
+    /// Program `data` at flash address `start_addr` and leave the rest of the
+    /// flash alone, eg: to write a bootloader separately from the application.
+    /// The address has to be the start of a flash page
+    pub fn program_binary_at(&self, data: Vec<u8>, start_addr: u32) -> AvrResult<ProgramReport> {
+        self.program_with_retries(data, start_addr as usize)
+    }
+
+    /// Program `bin` at flash address `start`, starting over as configured
+    /// with set_retries. Each attempt resets the board and syncs again, and
+    /// every command drops stale bytes left over from the failed attempt
+    fn program_with_retries(&self, bin: Vec<u8>, start: usize) -> AvrResult<ProgramReport> {
+        let mut attempt = 0;
+        loop {
+            let result = self.programmer.program_firmware_at(
+                bin.clone(),
+                start,
+                self.verify,
+                self.progress_bar_enable,
+            );
+            match result {
+                Err(e @ (AvrError::ProgrammerError(_) | AvrError::Communication(_)))
+                    if attempt < self.retries =>
+                {
+                    attempt += 1;
+                    warn!(
+                        "Programming failed, retrying ({}/{}): {}",
+                        attempt, self.retries, e
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Run a production line provisioning plan: erase, write fuses, program
+    /// and verify the firmware, restore the EEPROM saved by the erase, then
+    /// write the lock bits, skipping whatever the plan leaves out. The firmware is loaded before anything is
+    /// touched, and the first step to fail stops the run with an error
+    /// naming it
+    pub fn provision(&self, plan: ProvisionPlan) -> AvrResult<ProvisionReport> {
+        let bin = plan
+            .firmware
+            .to_binary()
+            .map_err(|e| step_failed("loading firmware", e))?;
+
+        let saved_eeprom = if plan.erase {
+            self.erase_saving_eeprom(plan.eeprom)
+                .map_err(|e| step_failed("chip erase", e))?
+        } else {
+            None
+        };
+        if let Some(fuses) = plan.fuses {
+            self.write_fuses(fuses, false)
+                .map_err(|e| step_failed("writing fuses", e))?;
+        }
+        let program = self
+            .program_binary(bin)
+            .map_err(|e| step_failed("programming firmware", e))?;
+        // Programming in ISP mode erases the chip again, so the EEPROM only
+        // goes back once the firmware is in
+        if let Some(saved) = saved_eeprom {
+            self.programmer
+                .program_eeprom(saved, self.verify)
+                .map_err(|e| step_failed("restoring EEPROM", e))?;
+        }
+        if let Some(bits) = plan.lock_bits {
+            self.write_lock_bits(bits)
+                .map_err(|e| step_failed("writing lock bits", e))?;
+        }
+
+        Ok(ProvisionReport {
+            erased: plan.erase,
+            fuses_written: plan.fuses,
+            program,
+            lock_bits_written: plan.lock_bits,
+        })
+    }
+
+    /// Check that the board already holds the firmware in the given intelhex
+    /// file, without programming it. A mismatch is reported as a programmer
+    /// error naming the first page that differs
+    pub fn verify_hex_file(&self, file_path: impl AsRef<Path>) -> AvrResult<()> {
+        let hex_content = std::fs::read_to_string(file_path)
+            .map_err(|e| AvrError::FirmwareError(format!("Failed to read file: {}", e)))?;
+
+        self.verify_binary(intel_hex_to_binary(&hex_content)?)
+    }
+
+    /// Check that the board already holds `bin` from address 0, without
+    /// programming it
+    pub fn verify_binary(&self, bin: Vec<u8>) -> AvrResult<()> {
         self.programmer
-            .program_firmware(bin, self.verify, self.progress_bar_enable)?;
+            .verify_firmware(bin, self.progress_bar_enable)
+    }
+
+    /// Read back the flash `bin` covers and list every byte that differs from
+    /// it. Unlike verify_binary this doesn't stop at the first bad page, which
+    /// tells a single flaky page apart from corruption all over (eg: a wrong
+    /// baud rate)
+    pub fn diff_flash(&self, bin: &[u8]) -> AvrResult<Vec<Mismatch>> {
+        self.programmer.full_verify(bin)
+    }
+
+    /// Program the payload of an avrman firmware container. The container's
+    /// CRC is checked, and its target signature has to match the connected
+    /// chip, before anything is written
+    pub fn program_container(&self, bytes: &[u8]) -> AvrResult<ProgramReport> {
+        let container = Container::decode(bytes)?;
+
+        let signature = self.programmer.read_signature()?;
+        if signature != container.signature {
+            return Err(AvrError::FirmwareError(format!(
+                "Firmware container is for a chip with signature {:02x?}, connected chip is {:02x?}",
+                container.signature, signature
+            )));
+        }
+
+        self.program_with_retries(container.payload, 0)
+    }
+
+    /// Flash bytes written so far by the current (or last) programming run.
+    /// Cheap enough to poll from another thread while programming, for UIs
+    /// that don't want a progress callback
+    pub fn bytes_programmed(&self) -> usize {
+        self.programmer.bytes_programmed()
+    }
+
+    /// Size of the target's flash memory in bytes
+    pub fn flash_size(&self) -> usize {
+        self.programmer.flash_size()
+    }
+
+    /// Read `num_bytes` of flash starting at address 0
+    pub fn read_flash(&self, num_bytes: usize) -> AvrResult<Vec<u8>> {
+        self.programmer
+            .read_flash(num_bytes, self.progress_bar_enable)
+    }
+
+    /// Read `num_bytes` of flash starting at address 0 and write them to
+    /// `path` as a raw binary image
+    pub fn dump_flash_to_file(&self, path: &str, num_bytes: usize) -> AvrResult<()> {
+        let flash = self
+            .programmer
+            .read_flash(num_bytes, self.progress_bar_enable)?;
+
+        let mut file = File::create(path)
+            .map_err(|e| AvrError::FirmwareError(format!("Failed to create file: {}", e)))?;
+        file.write_all(&flash)
+            .map_err(|e| AvrError::FirmwareError(format!("Failed to write flash dump: {}", e)))?;
+
         Ok(())
     }
+
+    /// Read `num_bytes` of flash starting at address 0 as Intel hex data
+    /// records, with extended linear address records for anything above 64KB,
+    /// ready to hand to other ihex tooling
+    pub fn read_flash_records(&self, num_bytes: usize) -> AvrResult<Vec<ihex::Record>> {
+        let flash = self
+            .programmer
+            .read_flash(num_bytes, self.progress_bar_enable)?;
+
+        Ok(binary_to_records(&flash))
+    }
+
+    /// Address one past the last non-0xFF byte in flash. The used region is
+    /// located with a binary search over the flash pages, so this assumes the
+    /// program is a single block starting at address 0
+    pub fn find_program_end(&self) -> AvrResult<usize> {
+        self.programmer.find_program_end()
+    }
+
+    /// Read back the used part of the flash and return it as an intel hex
+    /// string, with the trailing erased region left out
+    pub fn dump_hex(&self) -> AvrResult<String> {
+        let flash_size = self.programmer.flash_size();
+        let mut end = self.programmer.find_program_end()?;
+
+        // The erased tail is shorter than the trim threshold, so it is kept
+        if flash_size - end < self.dump_trim_threshold {
+            end = flash_size;
+        }
+        let flash = self.programmer.read_flash(end, self.progress_bar_enable)?;
+
+        binary_to_intel_hex(trim_erased_tail(&flash, self.dump_trim_threshold))
+    }
+
+    /// Program binary data into EEPROM starting at address 0, verifying it
+    /// afterwards unless verification is disabled
+    pub fn program_eeprom_binary(&self, data: Vec<u8>) -> AvrResult<()> {
+        self.programmer.program_eeprom(data, self.verify)
+    }
+
+    /// Read `num_bytes` of EEPROM from address 0
+    pub fn read_eeprom(&self, num_bytes: usize) -> AvrResult<Vec<u8>> {
+        self.programmer.read_eeprom(num_bytes)
+    }
+
+    /// Write `bytes` to EEPROM starting at `address`. Every EEPROM page that is
+    /// touched is read back first and patched, so the surrounding cells keep
+    /// their contents
+    pub fn update_eeprom_at(&self, address: usize, bytes: &[u8]) -> AvrResult<()> {
+        self.programmer.update_eeprom(address, bytes)
+    }
+
+    /// Read the three signature bytes of the connected chip without
+    /// programming it. The bytes are returned as read, even when they don't
+    /// match the signature the programmer was configured with
+    pub fn read_signature(&self) -> AvrResult<[u8; 3]> {
+        self.programmer.read_signature()
+    }
+
+    /// Stop the transport threads and release the serial port, keeping the
+    /// programmer's configuration. Every operation fails until reopen is
+    /// called, which lets long running services avoid holding idle ports
+    pub fn close(&mut self) {
+        self.programmer.close();
+    }
+
+    /// Open the serial port again after close. Programmers created on a
+    /// caller supplied interface can't be reopened
+    pub fn reopen(&mut self) -> AvrResult<()> {
+        self.programmer.reopen()
+    }
+
+    /// Major and minor version the bootloader reports (eg: (8, 0) for
+    /// Optiboot 8.0), to check a board runs the expected bootloader before
+    /// flashing it
+    pub fn bootloader_version(&self) -> AvrResult<(u8, u8)> {
+        self.programmer.bootloader_version()
+    }
+
+    /// Read the low, high and (where the part has one) extended fuse bytes.
+    /// Needs an ISP programmer, as bootloaders don't answer fuse reads
+    pub fn read_fuses(&self) -> AvrResult<Fuses> {
+        self.programmer.read_fuses()
+    }
+
+    /// Write the fuse bytes and read them back to check they took. Unless
+    /// `allow_dangerous` is set, writes that would disable the reset pin or
+    /// serial programming, or select a reserved clock source, are refused.
+    /// Needs an ISP programmer
+    pub fn write_fuses(&self, fuses: Fuses, allow_dangerous: bool) -> AvrResult<()> {
+        self.programmer.write_fuses(fuses, allow_dangerous)
+    }
+
+    /// Read the lock bits. Needs an ISP programmer
+    pub fn read_lock_bits(&self) -> AvrResult<u8> {
+        self.programmer.read_lock_bits()
+    }
+
+    /// Write the lock bits and read them back to check they took, eg: to stop
+    /// the firmware being read out after final programming. Needs an ISP
+    /// programmer.
+    ///
+    /// Locked flash reads back as garbage, so verifying or dumping fails
+    /// afterwards, and lock bits can only be cleared again by erasing the chip
+    pub fn write_lock_bits(&self, bits: u8) -> AvrResult<()> {
+        self.programmer.write_lock_bits(bits)
+    }
+
+    /// Send a raw 4 byte serial programming instruction to the target and
+    /// return the byte it answers with during the last transfer, eg: to read
+    /// calibration bytes or the signature row. See the device datasheet's
+    /// "Serial Programming Instruction Set" table. Needs an ISP programmer
+    pub fn spi_instruction(&self, instruction: [u8; 4]) -> AvrResult<u8> {
+        self.programmer.spi_instruction(instruction)
+    }
+
+    /// Read the factory calibration byte of the internal RC oscillator, to
+    /// store in EEPROM or load into OSCCAL at runtime. Only meaningful on
+    /// parts with an internal oscillator. Needs an ISP programmer
+    pub fn read_calibration_byte(&self) -> AvrResult<u8> {
+        self.spi_instruction(READ_CALIBRATION_BYTE)
+    }
+
+    /// Erase the whole chip (flash, and EEPROM unless the EESAVE fuse is
+    /// programmed). This uses the serial programming chip erase instruction,
+    /// so it needs an ISP programmer; bootloaders usually ignore it
+    pub fn erase(&self) -> AvrResult<()> {
+        self.programmer.erase_chip()
+    }
+
+    /// Erase the whole chip, first reading the EESAVE fuse to decide what to
+    /// do about EEPROM, see EepromOnErase. With EESAVE programmed the chip is
+    /// erased as usual. Needs an ISP programmer
+    pub fn erase_with(&self, eeprom: EepromOnErase) -> AvrResult<()> {
+        match self.erase_saving_eeprom(eeprom)? {
+            Some(saved) => self.programmer.program_eeprom(saved, self.verify),
+            None => Ok(()),
+        }
+    }
+
+    /// Same as erase_with, but the EEPROM saved for EepromOnErase::Restore
+    /// is handed back rather than written, for callers with more to do
+    /// before restoring it
+    fn erase_saving_eeprom(&self, eeprom: EepromOnErase) -> AvrResult<Option<Vec<u8>>> {
+        if eeprom == EepromOnErase::Erase {
+            return self.erase().map(|_| None);
+        }
+
+        let signature = self.programmer.read_signature()?;
+        if self.programmer.read_fuses()?.preserves_eeprom(&signature) {
+            return self.erase().map(|_| None);
+        }
+
+        match eeprom {
+            EepromOnErase::Erase => self.erase().map(|_| None),
+            EepromOnErase::Warn => {
+                warn!("EESAVE is unprogrammed, chip erase will clear EEPROM");
+                self.erase().map(|_| None)
+            }
+            EepromOnErase::Refuse => Err(AvrError::ConfigurationError(String::from(
+                "Refusing to erase: EESAVE is unprogrammed, so EEPROM would be cleared",
+            ))),
+            EepromOnErase::Restore(num_bytes) => {
+                let saved = self.read_eeprom(num_bytes)?;
+                self.erase()?;
+                Ok(Some(saved))
+            }
+        }
+    }
+
+    /// Read `num_bytes` of flash from address 0 and check that all of it is
+    /// erased (0xFF). Handy after erase, to catch erase failures on worn
+    /// chips. The first byte that isn't erased is logged
+    pub fn blank_check(&self, num_bytes: usize) -> AvrResult<bool> {
+        let flash = self
+            .programmer
+            .read_flash(num_bytes, self.progress_bar_enable)?;
+
+        match flash.iter().position(|byte| *byte != 0xFF) {
+            Some(address) => {
+                warn!(
+                    "Flash is not blank, found {:#04x} at address {:#x}",
+                    flash[address], address
+                );
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
 }
@@ -0,0 +1,68 @@
+use crate::error::{AvrError, AvrResult};
+use crate::fuses::Fuses;
+
+/// Fingerprint a chip has to match before it is programmed. Fuses and lock
+/// bits are only compared when given; an expected extended fuse of `None`
+/// matches any extended fuse
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preflight {
+    pub signature: Vec<u8>,
+    pub fuses: Option<Fuses>,
+    pub lock: Option<u8>,
+}
+
+impl Preflight {
+    /// Compare what was read from the chip against the expected fingerprint,
+    /// listing every mismatch in the returned error
+    pub(crate) fn check(&self, signature: &[u8], fuses: &Fuses, lock: u8) -> AvrResult<()> {
+        let mut mismatches = Vec::new();
+
+        if self.signature != signature {
+            mismatches.push(format!(
+                "signature {:02x?} (expected {:02x?})",
+                signature, self.signature
+            ));
+        }
+
+        if let Some(expected) = &self.fuses {
+            if expected.low != fuses.low {
+                mismatches.push(format!(
+                    "low fuse {:#04x} (expected {:#04x})",
+                    fuses.low, expected.low
+                ));
+            }
+            if expected.high != fuses.high {
+                mismatches.push(format!(
+                    "high fuse {:#04x} (expected {:#04x})",
+                    fuses.high, expected.high
+                ));
+            }
+            if let Some(extended) = expected.extended
+                && fuses.extended != Some(extended)
+            {
+                mismatches.push(format!(
+                    "extended fuse {:02x?} (expected {:#04x})",
+                    fuses.extended, extended
+                ));
+            }
+        }
+
+        if let Some(expected) = self.lock
+            && expected != lock
+        {
+            mismatches.push(format!(
+                "lock bits {:#04x} (expected {:#04x})",
+                lock, expected
+            ));
+        }
+
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(AvrError::PreflightError(format!(
+                "Chip does not match the expected fingerprint: {}",
+                mismatches.join(", ")
+            )))
+        }
+    }
+}
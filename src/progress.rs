@@ -0,0 +1,134 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use crate::constants::PROGRESS_RATE_WINDOW;
+
+/// Stage of a long running operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Programming,
+    Verifying,
+}
+
+/// Emitted once per page while programming or verifying
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProgressEvent {
+    pub phase: Phase,
+
+    /// Number of pages done so far, including this one
+    pub page: usize,
+    pub total_pages: usize,
+
+    /// Throughput over the last few pages
+    pub bytes_per_second: f64,
+}
+
+pub type ProgressCallback = Box<dyn Fn(ProgressEvent) + Send + Sync>;
+
+/// Rolling bytes/sec estimate. Each sample is the rate since the previous
+/// one, and the estimate is the mean of the last PROGRESS_RATE_WINDOW samples
+/// so a single slow or fast page doesn't make the reported rate jump around
+pub(crate) struct RateEstimator {
+    last: Instant,
+    samples: VecDeque<f64>,
+}
+
+impl RateEstimator {
+    pub fn new(start: Instant) -> Self {
+        RateEstimator {
+            last: start,
+            samples: VecDeque::with_capacity(PROGRESS_RATE_WINDOW),
+        }
+    }
+
+    /// Record that `bytes` were transferred since the last update
+    pub fn update(&mut self, bytes: usize, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+
+        // Two pages in the same clock tick say nothing about the rate
+        if elapsed > 0.0 {
+            if self.samples.len() == PROGRESS_RATE_WINDOW {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(bytes as f64 / elapsed);
+        }
+
+        if self.samples.is_empty() {
+            0.0
+        } else {
+            self.samples.iter().sum::<f64>() / self.samples.len() as f64
+        }
+    }
+}
+
+/// Turns page completions into progress events for an optional callback
+pub(crate) struct ProgressReporter<'a> {
+    callback: Option<&'a ProgressCallback>,
+    phase: Phase,
+    page: usize,
+    total_pages: usize,
+    rate: RateEstimator,
+}
+
+impl<'a> ProgressReporter<'a> {
+    pub fn new(callback: Option<&'a ProgressCallback>, phase: Phase, total_pages: usize) -> Self {
+        ProgressReporter {
+            callback,
+            phase,
+            page: 0,
+            total_pages,
+            rate: RateEstimator::new(Instant::now()),
+        }
+    }
+
+    pub fn page_done(&mut self, bytes: usize) {
+        self.page_done_at(bytes, Instant::now());
+    }
+
+    fn page_done_at(&mut self, bytes: usize, now: Instant) {
+        self.page += 1;
+        let bytes_per_second = self.rate.update(bytes, now);
+
+        if let Some(callback) = self.callback {
+            callback(ProgressEvent {
+                phase: self.phase,
+                page: self.page,
+                total_pages: self.total_pages,
+                bytes_per_second,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_is_populated_for_successive_events() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        let callback: ProgressCallback = Box::new(move |event| sink.lock().unwrap().push(event));
+
+        let start = Instant::now();
+        let mut reporter = ProgressReporter::new(Some(&callback), Phase::Programming, 3);
+        reporter.rate = RateEstimator::new(start);
+
+        reporter.page_done_at(128, start + Duration::from_millis(100));
+        reporter.page_done_at(128, start + Duration::from_millis(200));
+        reporter.page_done_at(128, start + Duration::from_millis(400));
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|event| event.bytes_per_second > 0.0));
+        assert_eq!(events[2].page, 3);
+        assert_eq!(events[2].total_pages, 3);
+
+        // 1280, 1280 and 640 bytes/sec, smoothed over the window
+        assert!((events[1].bytes_per_second - 1280.0).abs() < 1.0);
+        assert!((events[2].bytes_per_second - 1066.7).abs() < 1.0);
+    }
+}
@@ -0,0 +1,34 @@
+//! Serial programming (ISP) instructions, sent to the target through the
+//! STK500 universal command
+
+pub(crate) const CHIP_ERASE: [u8; 4] = [0xAC, 0x80, 0x00, 0x00];
+pub(crate) const POLL_RDY_BSY: [u8; 4] = [0xF0, 0x00, 0x00, 0x00];
+
+pub(crate) const READ_LOW_FUSE: [u8; 4] = [0x50, 0x00, 0x00, 0x00];
+pub(crate) const READ_HIGH_FUSE: [u8; 4] = [0x58, 0x08, 0x00, 0x00];
+pub(crate) const READ_EXTENDED_FUSE: [u8; 4] = [0x50, 0x08, 0x00, 0x00];
+/// Write instructions take the new fuse value as their last byte
+pub(crate) const WRITE_LOW_FUSE: [u8; 3] = [0xAC, 0xA0, 0x00];
+pub(crate) const WRITE_HIGH_FUSE: [u8; 3] = [0xAC, 0xA8, 0x00];
+pub(crate) const WRITE_EXTENDED_FUSE: [u8; 3] = [0xAC, 0xA4, 0x00];
+
+pub(crate) const READ_LOCK_BITS: [u8; 4] = [0x58, 0x00, 0x00, 0x00];
+/// Takes the new lock bits as its last byte
+pub(crate) const WRITE_LOCK_BITS: [u8; 3] = [0xAC, 0xE0, 0x00];
+/// Lock bits in use, the top two bits read back as 1
+pub(crate) const LOCK_BITS_MASK: u8 = 0x3F;
+
+/// Factory calibration value for the internal RC oscillator (OSCCAL)
+pub(crate) const READ_CALIBRATION_BYTE: [u8; 4] = [0x38, 0x00, 0x00, 0x00];
+
+/// Load one byte into the target's flash page buffer. Take the word address
+/// high and low bytes, then the data byte
+pub(crate) const LOAD_PROGRAM_MEMORY_LOW: u8 = 0x40;
+pub(crate) const LOAD_PROGRAM_MEMORY_HIGH: u8 = 0x48;
+/// Commit the page buffer to the flash page at the given word address
+pub(crate) const WRITE_PROGRAM_MEMORY_PAGE: u8 = 0x4C;
+
+/// Loads the extended (bits 16 and up) part of the flash word address, on
+/// parts with more than 128KB of flash. Takes the address byte third
+pub(crate) const LOAD_EXTENDED_ADDRESS: u8 = 0x4D;
+pub(crate) const EXTENDED_ADDRESS_FLASH_SIZE: usize = 128 * 1024;
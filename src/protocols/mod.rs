@@ -1,15 +1,219 @@
-use crate::error::AvrResult;
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::diff::{Mismatch, mismatches};
+use crate::error::{AvrError, AvrResult};
+use crate::fuses::Fuses;
+use crate::preflight::Preflight;
+use crate::progress::ProgressCallback;
+use crate::report::ProgramReport;
+pub(crate) mod isp;
 pub mod stk500v1;
 pub mod stk500v2;
+pub(crate) mod transport;
+
+/// Memory type byte sent with page load/read commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Program memory, 'F'
+    Flash,
+
+    /// Data EEPROM, 'E'
+    Eeprom,
+
+    /// Any other memory type byte understood by an exotic bootloader
+    Custom(u8),
+}
+
+impl MemoryType {
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            MemoryType::Flash => b'F',
+            MemoryType::Eeprom => b'E',
+            MemoryType::Custom(byte) => *byte,
+        }
+    }
+}
 
 /// Currently only implements program/reset. Can be extended in
 /// future to do other operations like dump flash, erase chip, etc.,
-pub(crate) trait ProgrammerTrait {
-    fn program_firmware(
+pub(crate) trait ProgrammerTrait: Send + Sync {
+    /// Program `firmware` at flash address `start`, leaving the pages below
+    /// it alone
+    fn program_firmware_at(
         &self,
         firmware: Vec<u8>,
+        start: usize,
         verify: bool,
         enable_progress_bar: bool,
-    ) -> AvrResult<()>;
+    ) -> AvrResult<ProgramReport>;
+    fn read_flash(&self, num_bytes: usize, enable_progress_bar: bool) -> AvrResult<Vec<u8>>;
+
+    /// Compare flash against `firmware` without writing anything
+    fn verify_firmware(&self, firmware: Vec<u8>, enable_progress_bar: bool) -> AvrResult<()>;
+
+    /// Read back the whole region `bin` covers and list every byte that
+    /// differs, instead of stopping at the first bad page like verify
+    fn full_verify(&self, bin: &[u8]) -> AvrResult<Vec<Mismatch>> {
+        let actual = self.read_flash(bin.len(), false)?;
+        Ok(mismatches(bin, &actual))
+    }
+
+    /// One past the address of the last non-0xFF byte in flash. Protocols
+    /// that can read individual pages should override this to avoid reading
+    /// the whole flash
+    fn find_program_end(&self) -> AvrResult<usize> {
+        let flash = self.read_flash(self.flash_size(), false)?;
+        Ok(flash
+            .iter()
+            .rposition(|byte| *byte != 0xFF)
+            .map_or(0, |last| last + 1))
+    }
+    fn program_eeprom(&self, data: Vec<u8>, verify: bool) -> AvrResult<()>;
+    fn read_eeprom(&self, num_bytes: usize) -> AvrResult<Vec<u8>>;
+    fn update_eeprom(&self, address: usize, bytes: &[u8]) -> AvrResult<()>;
+    fn erase_chip(&self) -> AvrResult<()>;
+    fn read_signature(&self) -> AvrResult<[u8; 3]>;
+
+    /// Major and minor software version of the bootloader
+    fn bootloader_version(&self) -> AvrResult<(u8, u8)>;
+    fn read_fuses(&self) -> AvrResult<Fuses>;
+    fn write_fuses(&self, fuses: Fuses, allow_dangerous: bool) -> AvrResult<()>;
+    fn read_lock_bits(&self) -> AvrResult<u8>;
+
+    /// Send a raw 4 byte serial programming instruction, returning the byte
+    /// the target answers during the last transfer
+    fn spi_instruction(&self, instruction: [u8; 4]) -> AvrResult<u8>;
+    fn write_lock_bits(&self, bits: u8) -> AvrResult<()>;
+    fn flash_size(&self) -> usize;
+
+    /// Flash bytes written so far by the current (or last) upload
+    fn bytes_programmed(&self) -> usize;
+    fn set_preflight(&mut self, preflight: Preflight) -> AvrResult<()>;
+    fn set_progress_callback(&mut self, callback: ProgressCallback);
+
+    /// Only verify the pages covering `range` after programming, or the
+    /// whole image when `None`
+    fn set_verify_range(&mut self, range: Option<Range<usize>>);
+
+    /// Walk the pages of program_firmware without connecting to the target
+    /// or writing anything
+    fn set_dry_run(&mut self, enable: bool);
+
+    /// Fail any operation still waiting for the target `deadline` after it
+    /// connected, instead of waiting forever
+    fn set_deadline(&mut self, deadline: Duration);
+
+    /// Whether to toggle the reset line before syncing with the bootloader
+    fn set_auto_reset(&mut self, enable: bool);
+
+    /// Change the serial read timeout of the open device
+    fn set_timeout_ms(&self, timeout_ms: u64) -> AvrResult<()>;
     fn reset(&self) -> AvrResult<()>;
+
+    /// Stop the transport and release the device
+    fn close(&mut self);
+
+    /// Open the serial port again after close
+    fn reopen(&mut self) -> AvrResult<()>;
+}
+
+/// Error for reopening a programmer built on a caller supplied interface,
+/// which was dropped on close and can't be opened again
+pub(crate) fn not_reopenable() -> AvrError {
+    AvrError::ConfigurationError(String::from(
+        "Only programmers that opened their own serial port can be reopened",
+    ))
+}
+
+/// Byte range of a `image_len` byte image to verify: the whole image, or the
+/// requested range with its start rounded down to a page boundary so reads
+/// stay page aligned
+pub(crate) fn verify_span(
+    image_len: usize,
+    start: usize,
+    page_size: usize,
+    range: Option<&Range<usize>>,
+) -> AvrResult<Range<usize>> {
+    let Some(range) = range else {
+        return Ok(start..image_len);
+    };
+
+    if range.is_empty() || range.end > image_len {
+        return Err(AvrError::ConfigurationError(format!(
+            "Verify range {:#x}..{:#x} is outside the {} byte image",
+            range.start, range.end, image_len
+        )));
+    }
+
+    // Nothing below `start` was written
+    Ok((range.start / page_size * page_size).max(start)..range.end)
+}
+
+/// Flash is written a whole page at a time, so programming has to start on a
+/// page boundary within the flash
+pub(crate) fn check_start_address(
+    start: usize,
+    page_size: usize,
+    flash_size: usize,
+) -> AvrResult<()> {
+    if !start.is_multiple_of(page_size) || start >= flash_size {
+        return Err(AvrError::ConfigurationError(format!(
+            "Start address {:#x} is not the start of a {} byte page in the {} byte flash",
+            start, page_size, flash_size
+        )));
+    }
+    Ok(())
+}
+
+/// Refuse an EEPROM access of `len` bytes at `address` that runs past the
+/// end of the `eeprom_size` byte EEPROM, before anything is sent
+pub(crate) fn check_eeprom_range(address: usize, len: usize, eeprom_size: usize) -> AvrResult<()> {
+    if address.checked_add(len).is_none_or(|end| end > eeprom_size) {
+        return Err(AvrError::ConfigurationError(format!(
+            "{} bytes at EEPROM address {:#x} don't fit in the {} byte EEPROM",
+            len, address, eeprom_size
+        )));
+    }
+    Ok(())
+}
+
+/// Patch `bytes` into EEPROM at `address` one page at a time. Each page
+/// touched is read with `read_page`, has the cells in the update replaced,
+/// and is written back with `write_page`, so the cells around the update keep
+/// their contents. Both are given the byte address of the page
+pub(crate) fn update_eeprom_pages(
+    address: usize,
+    bytes: &[u8],
+    page_size: usize,
+    mut read_page: impl FnMut(usize) -> AvrResult<Vec<u8>>,
+    mut write_page: impl FnMut(usize, &[u8]) -> AvrResult<()>,
+) -> AvrResult<()> {
+    let end = address + bytes.len();
+    let mut page_start = address - address % page_size;
+
+    while page_start < end {
+        let mut page = read_page(page_start)?;
+        for (offset, cell) in page.iter_mut().enumerate() {
+            let cell_address = page_start + offset;
+            if (address..end).contains(&cell_address) {
+                *cell = bytes[cell_address - address];
+            }
+        }
+        write_page(page_start, &page)?;
+        page_start += page_size;
+    }
+    Ok(())
+}
+
+/// Refuse an image that doesn't fit in the `capacity` bytes of flash
+/// available to the application, before anything is written
+pub(crate) fn check_image_fits(image_len: usize, capacity: usize) -> AvrResult<()> {
+    if image_len > capacity {
+        return Err(AvrError::FirmwareError(format!(
+            "Firmware is {} bytes, but only {} bytes of flash are available",
+            image_len, capacity
+        )));
+    }
+    Ok(())
 }
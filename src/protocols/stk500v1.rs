@@ -1,15 +1,37 @@
-use indicatif::ProgressBar;
-use tracing::debug;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use crate::constants::{SERIAL_TIMEOUT_MS, TRANSPORT_THREAD_SLEEP_MICROS};
+use tracing::{debug, info, info_span, warn};
+
+use super::isp::{
+    CHIP_ERASE, EXTENDED_ADDRESS_FLASH_SIZE, LOAD_EXTENDED_ADDRESS, LOAD_PROGRAM_MEMORY_HIGH,
+    LOAD_PROGRAM_MEMORY_LOW, LOCK_BITS_MASK, POLL_RDY_BSY, READ_EXTENDED_FUSE, READ_HIGH_FUSE,
+    READ_LOCK_BITS, READ_LOW_FUSE, WRITE_EXTENDED_FUSE, WRITE_HIGH_FUSE, WRITE_LOCK_BITS,
+    WRITE_LOW_FUSE, WRITE_PROGRAM_MEMORY_PAGE,
+};
+use super::transport::Transport;
+use super::{
+    MemoryType, check_eeprom_range, check_image_fits, check_start_address, not_reopenable,
+    update_eeprom_pages, verify_span,
+};
+use crate::constants::{
+    CHIP_ERASE_POLL_ATTEMPTS, CHIP_ERASE_POLL_INTERVAL_MS, DEFAULT_STK500V1_EEPROM_PAGE_SIZE,
+    DEFAULT_STK500V1_EEPROM_SIZE, DEFAULT_SYNC_ATTEMPTS, DEFAULT_SYNC_RETRY_DELAY_MS,
+    LEAVE_PROGMODE_ATTEMPTS, LEAVE_PROGMODE_RETRY_DELAY_MS, PAGE_WRITE_POLL_ATTEMPTS,
+    PAGE_WRITE_POLL_INTERVAL_MS, SYNC_RESPONSE_TIMEOUT_MS,
+};
 use crate::error::AvrError;
+use crate::fuses::Fuses;
 use crate::interface::DeviceInterface;
-use crate::interface::serialport::SerialPortDevice;
-use crate::util::create_progress_bar;
+use crate::interface::serialport::{
+    DEFAULT_SERIAL_TIMEOUT_MS, ResetLine, ResetTiming, SerialPortDevice,
+};
+use crate::preflight::Preflight;
+use crate::progress::{Phase, ProgressCallback, ProgressReporter};
+use crate::report::ProgramReport;
+use crate::util::progress_bar;
 use crate::{ProgrammerTrait, error::AvrResult};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex, mpsc};
-use std::thread::JoinHandle;
 
 #[repr(u8)]
 pub enum Stk500v1Message {
@@ -17,13 +39,55 @@ pub enum Stk500v1Message {
     RespStkInSync = 0x14,
     SyncCrcEop = 0x20,
     CmndStkGetSync = 0x30,
+    CmndStkGetParameter = 0x41,
     CmndStkSetDevice = 0x42,
     CmndStkEnterProgMode = 0x50,
     CmndStkLeaveProgMode = 0x51,
     CmndStkLoadAddress = 0x55,
+    CmndStkUniversal = 0x56,
     CmndStkProgPage = 0x64,
     CmndStkReadPage = 0x74,
     CmndStkReadSign = 0x75,
+    ParmStkSwMajor = 0x81,
+    ParmStkSwMinor = 0x82,
+}
+
+/// Sync three times before programming, a known trick to get some finicky
+/// bootloaders to respond reliably. Use as `Stk500v1Params::sync_count`
+pub const TRIPLE_SYNC: u32 = 3;
+
+/// How hard to try to get in sync with the bootloader. Each attempt waits a
+/// short while for an answer, then backs off for `delay_ms` before retrying
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncConfig {
+    pub attempts: u32,
+    pub delay_ms: u64,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        SyncConfig {
+            attempts: DEFAULT_SYNC_ATTEMPTS,
+            delay_ms: DEFAULT_SYNC_RETRY_DELAY_MS,
+        }
+    }
+}
+
+/// How to wait for a page write to finish before sending the next command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PageWriteWait {
+    /// Rely on the page write being acknowledged. Bootloaders only answer
+    /// once the page is written
+    #[default]
+    Ack,
+
+    /// Sleep this many milliseconds after each page, for clone bootloaders
+    /// that acknowledge before they're ready for the next command
+    DelayMs(u64),
+
+    /// Poll RDY/BSY with the universal command until the chip is ready.
+    /// Only ISP programmers (eg: Arduino as ISP) answer the universal command
+    PollReady,
 }
 
 pub struct Stk500v1Params {
@@ -33,123 +97,177 @@ pub struct Stk500v1Params {
     pub page_size: u16,
     pub num_pages: u16,
     pub product_id: Vec<u16>,
+
+    /// Read the fuses before writing and refuse to program when they make ISP
+    /// programming impossible. Only applies in isp_mode: bootloaders answer
+    /// every universal command with 0x00, which would read as RSTDISBL set
+    pub safe_mode: bool,
+
+    /// Number of sync commands issued before programming. 1 is enough for
+    /// most boards, see TRIPLE_SYNC for finicky ones
+    pub sync_count: u32,
+
+    /// EEPROM page size in bytes (4 on the ATmega328p)
+    pub eeprom_page_size: u16,
+
+    /// EEPROM size in bytes (1024 on the ATmega328p)
+    pub eeprom_size: usize,
+
+    /// Control line(s) toggled to reset the board
+    pub reset_line: ResetLine,
+
+    /// Reset pulse width and bootloader start up delay
+    pub reset_timing: ResetTiming,
+
+    /// Delay between the bytes of a command, for slow bit-banged or
+    /// opto-isolated links. 0 writes each command in one go
+    pub inter_byte_delay_micros: u64,
+
+    /// Retries and backoff for each sync with the bootloader
+    pub sync_config: SyncConfig,
+
+    /// How long a single serial read waits for data. Raising it trades
+    /// throughput for reliability on high latency or flaky USB adapters.
+    /// DEFAULT_SERIAL_TIMEOUT_MS suits most boards
+    pub timeout_ms: u64,
+
+    /// Bytes at the top of flash reserved for the bootloader, which firmware
+    /// may not overlap. 0 makes the whole flash available
+    pub bootloader_size: usize,
+
+    /// Wait after each flash or EEPROM page write, on top of its acknowledge
+    pub page_write_wait: PageWriteWait,
+
+    /// Program flash with raw ISP instructions through the universal command
+    /// rather than CmndStkProgPage, for chips without a bootloader behind an
+    /// Arduino as ISP. The chip is erased before programming
+    pub isp_mode: bool,
+
+    /// Toggle the reset line before syncing. Turn off for boards without the
+    /// DTR/RTS auto-reset circuit, which are put into the bootloader by hand
+    pub auto_reset: bool,
 }
 
-pub(crate) struct Stk500v1 {
-    source: mpsc::Receiver<Vec<u8>>,
-    sink: mpsc::Sender<Vec<u8>>,
+/// An Optiboot ATmega328p (eg: Arduino Uno) with no port set. Fill in the
+/// fields that differ and take the rest with `..Default::default()`
+impl Default for Stk500v1Params {
+    fn default() -> Self {
+        Stk500v1Params {
+            port: String::new(),
+            baud: 115200,
+            device_signature: vec![0x1e, 0x95, 0x0f],
+            page_size: 128,
+            num_pages: 256,
+            product_id: vec![],
+            safe_mode: false,
+            sync_count: 1,
+            eeprom_page_size: DEFAULT_STK500V1_EEPROM_PAGE_SIZE,
+            eeprom_size: DEFAULT_STK500V1_EEPROM_SIZE,
+            reset_line: ResetLine::default(),
+            reset_timing: ResetTiming::default(),
+            inter_byte_delay_micros: 0,
+            sync_config: SyncConfig::default(),
+            timeout_ms: DEFAULT_SERIAL_TIMEOUT_MS,
+            bootloader_size: 0,
+            page_write_wait: PageWriteWait::default(),
+            isp_mode: false,
+            auto_reset: true,
+        }
+    }
+}
 
-    device_interface: Arc<Mutex<Box<dyn DeviceInterface + Send>>>,
+pub(crate) struct Stk500v1 {
+    transport: Transport,
     pub params: Stk500v1Params,
+    preflight: Option<Preflight>,
+    progress: Option<ProgressCallback>,
+    verify_range: Option<Range<usize>>,
+    bytes_programmed: AtomicUsize,
+    dry_run: bool,
 
-    shutdown: Arc<AtomicBool>,
-    thread_handles: Vec<JoinHandle<()>>,
+    /// Opened its own serial port from the params, so reopen can open it again
+    owns_port: bool,
 }
 
 impl Stk500v1 {
     pub fn new(params: Stk500v1Params) -> AvrResult<Self> {
-        let device_interface: Box<dyn DeviceInterface + Send> =
-            Box::new(SerialPortDevice::new(params.port.clone(), params.baud)?);
-        let (sink, sender_rx) = mpsc::channel();
-        let (receiver_tx, source) = mpsc::channel();
-
-        let device_interface = Arc::new(Mutex::new(device_interface));
-        let transport_sender = Arc::clone(&device_interface);
-        let transport_receiver = Arc::clone(&transport_sender);
-
-        let shutdown = Arc::new(AtomicBool::new(false));
-        let shutdown1 = Arc::clone(&shutdown);
-        let shutdown2 = Arc::clone(&shutdown);
-
-        // Sender thread
-        let send_handle = std::thread::spawn(move || {
-            while !shutdown1.load(Ordering::Relaxed) {
-                std::thread::sleep(std::time::Duration::from_micros(
-                    TRANSPORT_THREAD_SLEEP_MICROS,
-                ));
-                let recv_result =
-                    sender_rx.recv_timeout(std::time::Duration::from_millis(SERIAL_TIMEOUT_MS));
-                match recv_result {
-                    Ok(command) => {
-                        let mut device_interface = transport_sender
-                            .lock()
-                            .expect("Failed to lock device_interface (sender thread)");
-                        if let Err(e) = device_interface.send(command) {
-                            eprintln!("Error sending command: {:?}", e);
-                        }
-                    }
-                    Err(mpsc::RecvTimeoutError::Timeout) => {
-                        // Ignore timeout, continue running
-                    }
-                    Err(e) => {
-                        eprintln!("Sender thread terminated. {e}");
-                        break;
-                    }
-                }
-            }
-        });
-
-        // Receiver thread
-        let receive_handle = std::thread::spawn(move || {
-            while !shutdown2.load(Ordering::Relaxed) {
-                std::thread::sleep(std::time::Duration::from_micros(
-                    TRANSPORT_THREAD_SLEEP_MICROS,
-                ));
-                let mut device_interface = transport_receiver
-                    .lock()
-                    .expect("Failed to lock device_interface (receiver thread)");
-                match device_interface.receive() {
-                    Ok(response) => {
-                        if let Err(e) = receiver_tx.send(response) {
-                            eprintln!("Error sending response: {:?}", e);
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Error receiving response: {:?}", e);
-                        break;
-                    }
-                }
-            }
-        });
+        let device_interface = Self::open(&params)?;
+        Ok(Stk500v1 {
+            owns_port: true,
+            ..Self::with_interface(params, Box::new(device_interface))
+        })
+    }
 
+    /// Same as new, but commands are sent and answers read on the calling
+    /// thread, without the transport's sender and receiver threads
+    pub fn new_blocking(params: Stk500v1Params) -> AvrResult<Self> {
+        let device_interface = Self::open(&params)?;
         Ok(Stk500v1 {
-            source,
-            sink,
-            device_interface,
-            params,
-            shutdown,
-            thread_handles: vec![send_handle, receive_handle],
+            owns_port: true,
+            ..Self::blocking_with_interface(params, Box::new(device_interface))
         })
     }
 
-    pub(crate) fn send_command(&self, command: Vec<u8>) -> AvrResult<()> {
-        self.sink
-            .send(command)
-            .map_err(|e| AvrError::Communication(format!("Failed to send command: {:?}", e)))?;
-        Ok(())
+    fn open(params: &Stk500v1Params) -> AvrResult<SerialPortDevice> {
+        SerialPortDevice::open_with_retry(
+            params.port.clone(),
+            params.baud,
+            params.reset_line,
+            params.reset_timing,
+            params.inter_byte_delay_micros,
+            params.timeout_ms,
+            &params.product_id,
+        )
     }
 
-    pub(crate) fn receive_response_with_size(&self, expected_size: usize) -> AvrResult<Vec<u8>> {
-        let mut received = Vec::new();
+    pub(crate) fn with_interface(
+        params: Stk500v1Params,
+        device_interface: Box<dyn DeviceInterface + Send>,
+    ) -> Self {
+        Self::with_transport(params, Transport::new(device_interface))
+    }
 
-        while received.len() < expected_size {
-            let fresh_bytes = self.source.recv().map_err(|e| {
-                AvrError::Communication(format!("Failed to receive response: {:?}", e))
-            })?;
-            received.extend(fresh_bytes);
+    pub(crate) fn blocking_with_interface(
+        params: Stk500v1Params,
+        device_interface: Box<dyn DeviceInterface + Send>,
+    ) -> Self {
+        Self::with_transport(params, Transport::inline(device_interface))
+    }
+
+    fn with_transport(params: Stk500v1Params, transport: Transport) -> Self {
+        Stk500v1 {
+            transport,
+            params,
+            preflight: None,
+            progress: None,
+            verify_range: None,
+            bytes_programmed: AtomicUsize::new(0),
+            dry_run: false,
+            owns_port: false,
         }
-        Ok(received)
     }
 
+    /// Send a command and check that the response starts with the expected
+    /// bytes (which end in RESP_STK_OK). Anything after that is noise from the
+    /// line or a chatty bootloader, and is dropped
     fn send_command_and_verify_response(
         &self,
         cmd: Vec<u8>,
         expected_response: Vec<u8>,
     ) -> AvrResult<()> {
-        self.send_command(cmd.clone())?;
-        let response = self.receive_response_with_size(expected_response.len())?;
+        self.transport.send_command(cmd.clone())?;
+        let response = self
+            .transport
+            .receive_response_with_size(expected_response.len())?;
 
-        if response == expected_response {
+        if response.starts_with(&expected_response) {
+            if response.len() > expected_response.len() {
+                debug!(
+                    "Dropped {} trailing bytes after response to command {:?}",
+                    response.len() - expected_response.len(),
+                    cmd
+                );
+            }
             Ok(())
         } else {
             Err(AvrError::ProgrammerError(format!(
@@ -159,21 +277,43 @@ impl Stk500v1 {
         }
     }
 
+    /// Sync with the bootloader, retrying as configured in SyncConfig. Any
+    /// late answer to an earlier attempt is dropped before the next is sent
     pub(crate) fn sync(&self) -> AvrResult<()> {
-        debug!("Attempting to sync with target");
-        self.send_command_and_verify_response(
-            vec![
+        let expected_response = [
+            Stk500v1Message::RespStkInSync as u8,
+            Stk500v1Message::RespStkOk as u8,
+        ];
+        let attempts = self.params.sync_config.attempts.max(1);
+
+        for attempt in 1..=attempts {
+            debug!("Attempting to sync with target ({}/{})", attempt, attempts);
+            self.transport.send_command(vec![
                 Stk500v1Message::CmndStkGetSync as u8,
                 Stk500v1Message::SyncCrcEop as u8,
-            ],
-            vec![
-                Stk500v1Message::RespStkInSync as u8,
-                Stk500v1Message::RespStkOk as u8,
-            ],
-        )?;
+            ])?;
 
-        debug!("Synced with MCU");
-        Ok(())
+            match self.transport.receive_response_with_timeout(
+                expected_response.len(),
+                Duration::from_millis(SYNC_RESPONSE_TIMEOUT_MS),
+            ) {
+                Ok(response) if response.starts_with(&expected_response) => {
+                    debug!("Synced with MCU");
+                    return Ok(());
+                }
+                Ok(response) => debug!("Unexpected sync response {:?}", response),
+                Err(e) => debug!("No sync response: {}", e),
+            }
+
+            if attempt < attempts {
+                std::thread::sleep(Duration::from_millis(self.params.sync_config.delay_ms));
+            }
+        }
+
+        Err(AvrError::ProgrammerError(format!(
+            "Could not sync with target after {} attempts",
+            attempts
+        )))
     }
 
     fn verify_signature(&self) -> AvrResult<()> {
@@ -194,7 +334,303 @@ impl Stk500v1 {
         Ok(())
     }
 
+    /// Read the device signature without comparing it to the expected one
+    fn query_signature(&self) -> AvrResult<[u8; 3]> {
+        let cmd = vec![
+            Stk500v1Message::CmndStkReadSign as u8,
+            Stk500v1Message::SyncCrcEop as u8,
+        ];
+        self.transport.send_command(cmd.clone())?;
+        let response = self.transport.receive_response_with_size(5)?;
+
+        match response.as_slice() {
+            [in_sync, a, b, c, ok]
+                if *in_sync == Stk500v1Message::RespStkInSync as u8
+                    && *ok == Stk500v1Message::RespStkOk as u8 =>
+            {
+                Ok([*a, *b, *c])
+            }
+            _ => Err(AvrError::ProgrammerError(format!(
+                "Unexpected response {:?} to read signature command {:?}",
+                response, cmd
+            ))),
+        }
+    }
+
+    /// Read a single parameter byte from the programmer or bootloader
+    fn get_parameter(&self, parameter: Stk500v1Message) -> AvrResult<u8> {
+        let cmd = vec![
+            Stk500v1Message::CmndStkGetParameter as u8,
+            parameter as u8,
+            Stk500v1Message::SyncCrcEop as u8,
+        ];
+        self.transport.send_command(cmd.clone())?;
+        let response = self.transport.receive_response_with_size(3)?;
+
+        match response.as_slice() {
+            [in_sync, value, ok]
+                if *in_sync == Stk500v1Message::RespStkInSync as u8
+                    && *ok == Stk500v1Message::RespStkOk as u8 =>
+            {
+                Ok(*value)
+            }
+            _ => Err(AvrError::ProgrammerError(format!(
+                "Unexpected response {:?} to get parameter command {:?}",
+                response, cmd
+            ))),
+        }
+    }
+
+    /// Major and minor software version reported by the bootloader (eg:
+    /// (8, 0) for Optiboot 8.0). Resets the board to reach the bootloader
+    pub fn read_bootloader_version(&self) -> AvrResult<(u8, u8)> {
+        self.connect()?;
+
+        let major = self.get_parameter(Stk500v1Message::ParmStkSwMajor)?;
+        let minor = self.get_parameter(Stk500v1Message::ParmStkSwMinor)?;
+        debug!("Bootloader version {}.{}", major, minor);
+
+        Ok((major, minor))
+    }
+
+    /// Clock a raw 4 byte SPI instruction out to the target with
+    /// CmndStkUniversal and return the byte it answers with during the last
+    /// transfer. The programmer has to be in programming mode already
+    pub fn universal(&self, instruction: [u8; 4]) -> AvrResult<u8> {
+        let cmd = [
+            vec![Stk500v1Message::CmndStkUniversal as u8],
+            instruction.to_vec(),
+            vec![Stk500v1Message::SyncCrcEop as u8],
+        ]
+        .concat();
+        self.transport.send_command(cmd.clone())?;
+        let response = self.transport.receive_response_with_size(3)?;
+
+        match response.as_slice() {
+            [in_sync, value, ok]
+                if *in_sync == Stk500v1Message::RespStkInSync as u8
+                    && *ok == Stk500v1Message::RespStkOk as u8 =>
+            {
+                Ok(*value)
+            }
+            _ => Err(AvrError::ProgrammerError(format!(
+                "Unexpected response {:?} to universal command {:?}",
+                response, cmd
+            ))),
+        }
+    }
+
+    fn query_fuses(&self) -> AvrResult<Fuses> {
+        let low = self.universal(READ_LOW_FUSE)?;
+        let high = self.universal(READ_HIGH_FUSE)?;
+        let extended = if Fuses::has_extended(&self.params.device_signature) {
+            Some(self.universal(READ_EXTENDED_FUSE)?)
+        } else {
+            None
+        };
+
+        Ok(Fuses {
+            low,
+            high,
+            extended,
+        })
+    }
+
+    /// Write the fuses and read them back to make sure they took
+    fn store_fuses(&self, fuses: &Fuses) -> AvrResult<()> {
+        let write = |instruction: [u8; 3], value: u8| {
+            self.universal([instruction[0], instruction[1], instruction[2], value])
+        };
+
+        write(WRITE_LOW_FUSE, fuses.low)?;
+        write(WRITE_HIGH_FUSE, fuses.high)?;
+        if let Some(extended) = fuses.extended
+            && Fuses::has_extended(&self.params.device_signature)
+        {
+            write(WRITE_EXTENDED_FUSE, extended)?;
+        }
+
+        let written = self.query_fuses()?;
+        let matches = written.low == fuses.low
+            && written.high == fuses.high
+            && (fuses.extended.is_none() || written.extended == fuses.extended);
+        if !matches {
+            return Err(AvrError::ProgrammerError(format!(
+                "Fuses read back as {:?} after writing {:?}",
+                written, fuses
+            )));
+        }
+
+        debug!("Wrote fuses {:?}", fuses);
+        Ok(())
+    }
+
+    /// Write the lock bits and read them back to make sure they took
+    fn store_lock_bits(&self, bits: u8) -> AvrResult<()> {
+        self.universal([
+            WRITE_LOCK_BITS[0],
+            WRITE_LOCK_BITS[1],
+            WRITE_LOCK_BITS[2],
+            bits,
+        ])?;
+
+        let written = self.universal(READ_LOCK_BITS)?;
+        if written & LOCK_BITS_MASK != bits & LOCK_BITS_MASK {
+            return Err(AvrError::ProgrammerError(format!(
+                "Lock bits read back as {:#04x} after writing {:#04x}",
+                written, bits
+            )));
+        }
+
+        debug!("Wrote lock bits {:#04x}", bits);
+        Ok(())
+    }
+
+    /// Leave programming mode after `e` stopped an operation, and hand `e`
+    /// back. The failure is what the caller needs to hear about, not a second
+    /// failure leaving programming mode
+    fn abandon_programming_mode(&self, e: AvrError) -> AvrError {
+        if let Err(leave) = self.exit_programming_mode() {
+            warn!("Failed to leave programming mode: {}", leave);
+        }
+        e
+    }
+
+    /// Refuse to go any further if the fuses rule out ISP programming
+    fn check_fuses(&self) -> AvrResult<()> {
+        let fuses = self.query_fuses()?;
+        debug!("Read fuses {:?}", fuses);
+
+        fuses
+            .check_isp_programmable(&self.params.device_signature)
+            .map_err(|e| self.abandon_programming_mode(e))
+    }
+
+    /// Refuse to go any further if the chip doesn't match the expected
+    /// signature, fuses and lock bits
+    fn check_preflight(&self, preflight: &Preflight) -> AvrResult<()> {
+        let signature = self.query_signature()?;
+        let fuses = self.query_fuses()?;
+        let lock = self.universal(READ_LOCK_BITS)?;
+        debug!(
+            "Read signature {:02x?}, fuses {:?}, lock bits {:#04x}",
+            signature, fuses, lock
+        );
+
+        preflight
+            .check(&signature, &fuses, lock)
+            .map_err(|e| self.abandon_programming_mode(e))
+    }
+
+    /// Issue the SPI chip erase instruction and poll the target until it
+    /// reports that it is no longer busy
+    fn chip_erase(&self) -> AvrResult<()> {
+        self.universal(CHIP_ERASE).map_err(|e| {
+            AvrError::ProgrammerError(format!("Device did not acknowledge chip erase: {}", e))
+        })?;
+
+        let ready = self
+            .poll_until_ready(CHIP_ERASE_POLL_ATTEMPTS, CHIP_ERASE_POLL_INTERVAL_MS)
+            .map_err(|e| {
+                AvrError::ProgrammerError(format!("Device stopped responding during erase: {}", e))
+            })?;
+        if !ready {
+            return Err(AvrError::ProgrammerError(
+                "Device never reported chip erase as complete".to_string(),
+            ));
+        }
+
+        debug!("Chip erased");
+        Ok(())
+    }
+
+    /// Poll RDY/BSY until the chip reports ready, returning false if it was
+    /// still busy after `attempts` polls
+    fn poll_until_ready(&self, attempts: u32, interval_ms: u64) -> AvrResult<bool> {
+        for _ in 0..attempts {
+            if self.universal(POLL_RDY_BSY)? & 0x01 == 0 {
+                return Ok(true);
+            }
+            std::thread::sleep(Duration::from_millis(interval_ms));
+        }
+        Ok(false)
+    }
+
+    /// Wait for the page just written as configured in page_write_wait
+    fn wait_for_page_write(&self) -> AvrResult<()> {
+        match self.params.page_write_wait {
+            PageWriteWait::Ack => Ok(()),
+            PageWriteWait::DelayMs(delay_ms) => {
+                std::thread::sleep(Duration::from_millis(delay_ms));
+                Ok(())
+            }
+            PageWriteWait::PollReady => self.poll_page_write(),
+        }
+    }
+
+    /// Poll RDY/BSY until the page write in progress is done
+    fn poll_page_write(&self) -> AvrResult<()> {
+        let ready = self
+            .poll_until_ready(PAGE_WRITE_POLL_ATTEMPTS, PAGE_WRITE_POLL_INTERVAL_MS)
+            .map_err(|e| {
+                AvrError::ProgrammerError(format!(
+                    "Device stopped responding during page write: {}",
+                    e
+                ))
+            })?;
+        if ready {
+            Ok(())
+        } else {
+            Err(AvrError::ProgrammerError(
+                "Device never reported page write as complete".to_string(),
+            ))
+        }
+    }
+
+    /// Write a flash page with raw ISP instructions: fill the target's page
+    /// buffer a byte at a time, commit it at the page's word address and
+    /// wait for the write to finish
+    fn isp_write_page(
+        &self,
+        byte_address: u32,
+        bytes: &[u8],
+        extended: &mut Option<u8>,
+    ) -> AvrResult<()> {
+        let page_word = byte_address >> 1;
+        if self.flash_size() > EXTENDED_ADDRESS_FLASH_SIZE {
+            let high = (page_word >> 16) as u8;
+            if *extended != Some(high) {
+                self.universal([LOAD_EXTENDED_ADDRESS, 0x00, high, 0x00])?;
+                *extended = Some(high);
+            }
+        }
+
+        for (offset, byte) in bytes.iter().enumerate() {
+            let word = page_word + (offset / 2) as u32;
+            let instruction = if offset % 2 == 0 {
+                LOAD_PROGRAM_MEMORY_LOW
+            } else {
+                LOAD_PROGRAM_MEMORY_HIGH
+            };
+            self.universal([instruction, (word >> 8) as u8, word as u8, *byte])?;
+        }
+
+        self.universal([
+            WRITE_PROGRAM_MEMORY_PAGE,
+            (page_word >> 8) as u8,
+            page_word as u8,
+            0x00,
+        ])?;
+        self.poll_page_write()
+    }
+
+    /// Describe the part with CmndStkSetDevice. Bootloaders ignore it, but
+    /// ArduinoISP sizes its page writes from the page size given here
     fn set_options(&self) -> AvrResult<()> {
+        let page_size = self.params.page_size.to_be_bytes();
+        let eeprom_size = (self.params.eeprom_size.min(u16::MAX as usize) as u16).to_be_bytes();
+        let flash_size = (self.flash_size() as u32).to_be_bytes();
+
         self.send_command_and_verify_response(
             vec![
                 Stk500v1Message::CmndStkSetDevice as u8,
@@ -210,14 +646,14 @@ impl Stk500v1 {
                 0, // FlashPollVal2
                 0, // eepromPollVal1
                 0, // eepromPollVal2
-                0, // PageSizeHigh
-                0, // PageSizeLow
-                0, // eepromSizeHigh
-                0, // eepromSizeLow
-                0, // FlashSize4
-                0, // FlashSize3
-                0, // FlashSize2
-                0, // FlashSize1
+                page_size[0],
+                page_size[1],
+                eeprom_size[0],
+                eeprom_size[1],
+                flash_size[0],
+                flash_size[1],
+                flash_size[2],
+                flash_size[3],
                 Stk500v1Message::SyncCrcEop as u8,
             ],
             vec![
@@ -265,7 +701,45 @@ impl Stk500v1 {
         Ok(())
     }
 
-    fn load_page(&self, write_bytes: &[u8]) -> AvrResult<()> {
+    /// Load an EEPROM byte address. Optiboot and ArduinoISP double every
+    /// loaded address whatever the memory type, so EEPROM addresses are sent
+    /// halved like flash word addresses and have to be even
+    fn load_eeprom_address(&self, byte_address: usize) -> AvrResult<()> {
+        if !byte_address.is_multiple_of(2) {
+            return Err(AvrError::ConfigurationError(format!(
+                "EEPROM page at odd address {:#x} can't be addressed, the EEPROM page size \
+                has to be even",
+                byte_address
+            )));
+        }
+        let word_address = u16::try_from(byte_address / 2).map_err(|_| {
+            AvrError::ConfigurationError(format!(
+                "EEPROM address {:#x} is out of the reach of STK500v1",
+                byte_address
+            ))
+        })?;
+        self.load_address(word_address)
+    }
+
+    /// Load a flash byte address. Flash is word addressed, and on parts with
+    /// more than 128KB of flash the word address no longer fits in 16 bits, so
+    /// the extended address byte is loaded first whenever it changes.
+    /// `extended` is the extended address byte last loaded, if any
+    fn load_flash_address(&self, byte_address: u32, extended: &mut Option<u8>) -> AvrResult<()> {
+        let word_address = byte_address >> 1;
+
+        if self.flash_size() > EXTENDED_ADDRESS_FLASH_SIZE {
+            let high = (word_address >> 16) as u8;
+            if *extended != Some(high) {
+                self.universal([LOAD_EXTENDED_ADDRESS, 0x00, high, 0x00])?;
+                *extended = Some(high);
+            }
+        }
+
+        self.load_address((word_address & 0xFFFF) as u16)
+    }
+
+    fn load_page(&self, write_bytes: &[u8], memory: MemoryType) -> AvrResult<()> {
         let data_len = write_bytes.len() as u16;
         let bytes_high = ((data_len >> 8) & 0xFF) as u8;
         let bytes_low = (data_len & 0xFF) as u8;
@@ -276,7 +750,7 @@ impl Stk500v1 {
                     Stk500v1Message::CmndStkProgPage as u8,
                     bytes_high,
                     bytes_low,
-                    0x46,
+                    memory.as_byte(),
                 ],
                 write_bytes.to_vec(),
                 vec![Stk500v1Message::SyncCrcEop as u8],
@@ -291,79 +765,161 @@ impl Stk500v1 {
         Ok(())
     }
 
-    fn verify_page(&self, verify_bytes: &[u8]) -> AvrResult<()> {
-        let data_len = verify_bytes.len() as u16;
-        let size = if data_len > self.params.page_size {
-            self.params.page_size
-        } else {
-            data_len
-        };
+    /// Read back the page loaded at `address` and compare it with
+    /// `verify_bytes`, reporting the first byte that differs
+    fn verify_page(
+        &self,
+        address: usize,
+        verify_bytes: &[u8],
+        memory: MemoryType,
+    ) -> AvrResult<()> {
+        let read_back = self.read_page(verify_bytes.len() as u16, memory)?;
+
+        match verify_bytes
+            .iter()
+            .zip(&read_back)
+            .position(|(a, b)| a != b)
+        {
+            Some(offset) => Err(AvrError::ProgrammerError(format!(
+                "Verification failed at address {:#x} (page at {:#x}): wrote {:#04x}, read {:#04x}",
+                address + offset,
+                address,
+                verify_bytes[offset],
+                read_back[offset]
+            ))),
+            None => Ok(()),
+        }
+    }
 
+    /// Read `size` bytes of memory starting at the last loaded address,
+    /// stripping the in-sync/ok framing from the response
+    fn read_page(&self, size: u16, memory: MemoryType) -> AvrResult<Vec<u8>> {
         let byte_high = ((size >> 8) & 0xFF) as u8;
         let byte_low = (size & 0xFF) as u8;
 
-        self.send_command_and_verify_response(
-            vec![
-                Stk500v1Message::CmndStkReadPage as u8,
-                byte_high,
-                byte_low,
-                0x46,
-                Stk500v1Message::SyncCrcEop as u8,
-            ],
-            [
-                vec![Stk500v1Message::RespStkInSync as u8],
-                verify_bytes.to_vec(),
-                vec![Stk500v1Message::RespStkOk as u8],
-            ]
-            .concat(),
-        )?;
+        let cmd = vec![
+            Stk500v1Message::CmndStkReadPage as u8,
+            byte_high,
+            byte_low,
+            memory.as_byte(),
+            Stk500v1Message::SyncCrcEop as u8,
+        ];
+        self.transport.send_command(cmd.clone())?;
+        let response = self
+            .transport
+            .receive_response_with_size(size as usize + 2)?;
 
-        Ok(())
+        match (response.first(), response.last()) {
+            (Some(first), Some(last))
+                if *first == Stk500v1Message::RespStkInSync as u8
+                    && *last == Stk500v1Message::RespStkOk as u8
+                    && response.len() == size as usize + 2 =>
+            {
+                Ok(response[1..response.len() - 1].to_vec())
+            }
+            _ => Err(AvrError::ProgrammerError(format!(
+                "Unexpected response {:?} to read page command {:?}",
+                response, cmd
+            ))),
+        }
     }
 
+    /// Leave programming mode, retrying a few times since some bootloaders
+    /// occasionally NAK this and then stay in the bootloader until power cycled
     fn exit_programming_mode(&self) -> AvrResult<()> {
-        self.send_command_and_verify_response(
-            vec![
-                Stk500v1Message::CmndStkLeaveProgMode as u8,
-                Stk500v1Message::SyncCrcEop as u8,
-            ],
-            vec![
-                Stk500v1Message::RespStkInSync as u8,
-                Stk500v1Message::RespStkOk as u8,
-            ],
-        )?;
+        let mut attempt = 1;
+        loop {
+            let result = self.send_command_and_verify_response(
+                vec![
+                    Stk500v1Message::CmndStkLeaveProgMode as u8,
+                    Stk500v1Message::SyncCrcEop as u8,
+                ],
+                vec![
+                    Stk500v1Message::RespStkInSync as u8,
+                    Stk500v1Message::RespStkOk as u8,
+                ],
+            );
+
+            match result {
+                Err(e) if attempt < LEAVE_PROGMODE_ATTEMPTS => {
+                    warn!("Failed to leave programming mode, retrying: {}", e);
+                    std::thread::sleep(std::time::Duration::from_millis(
+                        LEAVE_PROGMODE_RETRY_DELAY_MS,
+                    ));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Reset the target and sync with it. Whatever the bootloader sent while
+    /// starting up is flushed first, so it can't be taken for the answer to
+    /// the first sync
+    fn connect(&self) -> AvrResult<()> {
+        self.transport.start_operation()?;
+        if self.params.auto_reset {
+            info_span!("reset").in_scope(|| self.reset())?;
+        }
+        self.transport.flush()?;
+
+        let count = self.params.sync_count.max(1);
+        let _span = info_span!("sync", count).entered();
+        for _ in 0..count {
+            self.sync()?;
+        }
         Ok(())
     }
 
-    fn upload(&self, bin: Vec<u8>, enable_progress_bar: bool) -> AvrResult<()> {
-        let mut pb: Option<ProgressBar> = None;
-        let total_steps = bin.len().div_ceil(self.params.page_size as usize);
+    /// Reset the target and bring it into programming mode
+    fn begin(&self) -> AvrResult<()> {
+        self.connect()?;
+
+        info_span!("verify_signature").in_scope(|| self.verify_signature())?;
+        self.set_options()?;
+        self.enter_programming_mode()
+    }
+
+    /// Write `bin` from flash address `start` on. `bin` is laid out from
+    /// address 0
+    fn upload(&self, bin: Vec<u8>, start: usize, enable_progress_bar: bool) -> AvrResult<()> {
+        let total_steps = (bin.len() - start).div_ceil(self.params.page_size as usize);
         let mut current_step = 0;
-        if enable_progress_bar {
-            pb = Some(create_progress_bar(total_steps as u64, "Programming.."));
-        }
+        let pb = progress_bar(enable_progress_bar, total_steps as u64, "Programming..");
+        let mut reporter =
+            ProgressReporter::new(self.progress.as_ref(), Phase::Programming, total_steps);
 
+        let _span = info_span!("upload", pages = total_steps, bytes = bin.len()).entered();
         debug!("Started programming");
-        let page_size = self.params.page_size;
-        let mut page_addr: u16 = 0;
-        let mut use_addr: u16;
+        self.bytes_programmed.store(0, Ordering::Relaxed);
+        let page_size = self.params.page_size as u32;
+        let mut page_addr = start as u32;
+        let mut extended = None;
 
-        while page_addr < bin.len() as u16 {
-            use_addr = page_addr >> 1;
-
-            self.load_address(use_addr)?;
-            let end = if bin.len() as u16 > (page_addr + page_size) {
-                page_addr + page_size
-            } else {
-                bin.len() as u16 - 1
-            };
+        while page_addr < bin.len() as u32 {
+            let end = (page_addr + page_size).min(bin.len() as u32);
             let slice = &bin[(page_addr as usize)..(end as usize)];
             if slice.is_empty() {
                 break;
             }
 
-            self.load_page(slice)?;
-            page_addr += slice.len() as u16;
+            if self.dry_run {
+                debug!(
+                    "Dry run, not writing {} bytes at {:#x}",
+                    slice.len(),
+                    page_addr
+                );
+            } else if self.params.isp_mode {
+                self.isp_write_page(page_addr, slice, &mut extended)?;
+            } else {
+                self.load_flash_address(page_addr, &mut extended)?;
+                self.load_page(slice, MemoryType::Flash)?;
+                self.wait_for_page_write()?;
+            }
+            page_addr += slice.len() as u32;
+            self.bytes_programmed
+                .fetch_add(slice.len(), Ordering::Relaxed);
+            reporter.page_done(slice.len());
 
             if let Some(progress_bar) = &pb {
                 progress_bar.set_position(current_step);
@@ -377,36 +933,38 @@ impl Stk500v1 {
         Ok(())
     }
 
-    fn verify(&self, bin: Vec<u8>, enable_progress_bar: bool) -> AvrResult<()> {
-        let mut pb: Option<ProgressBar> = None;
-        let total_steps = bin.len().div_ceil(self.params.page_size as usize);
+    fn verify(&self, bin: Vec<u8>, start: usize, enable_progress_bar: bool) -> AvrResult<()> {
+        let span = verify_span(
+            bin.len(),
+            start,
+            self.params.page_size as usize,
+            self.verify_range.as_ref(),
+        )?;
+        let total_steps = span.len().div_ceil(self.params.page_size as usize);
         let mut current_step = 0;
-        if enable_progress_bar {
-            pb = Some(create_progress_bar(total_steps as u64, "Verifying..."));
-        }
+        let pb = progress_bar(enable_progress_bar, total_steps as u64, "Verifying...");
+        let mut reporter =
+            ProgressReporter::new(self.progress.as_ref(), Phase::Verifying, total_steps);
 
-        debug!("Started verifying");
-        let mut page_addr: u16 = 0;
-        let mut use_addr;
-        let page_size = self.params.page_size;
+        let _span = info_span!("verify", pages = total_steps, bytes = span.len()).entered();
+        debug!("Started verifying {:#x}..{:#x}", span.start, span.end);
+        let mut page_addr = span.start as u32;
+        let mut extended = None;
+        let page_size = self.params.page_size as u32;
 
-        while page_addr < bin.len() as u16 {
-            use_addr = page_addr >> 1;
-            self.load_address(use_addr)?;
+        while page_addr < span.end as u32 {
+            self.load_flash_address(page_addr, &mut extended)?;
 
-            let end = if bin.len() as u16 > (page_addr + page_size) {
-                page_addr + page_size
-            } else {
-                bin.len() as u16 - 1
-            };
+            let end = (page_addr + page_size).min(span.end as u32);
 
             let slice = &bin[(page_addr as usize)..(end as usize)];
             if slice.is_empty() {
                 break;
             }
-            self.verify_page(slice)?;
+            self.verify_page(page_addr as usize, slice, MemoryType::Flash)?;
 
-            page_addr += slice.len() as u16;
+            page_addr += slice.len() as u32;
+            reporter.page_done(slice.len());
 
             if let Some(progress_bar) = &pb {
                 progress_bar.set_position(current_step);
@@ -418,50 +976,1306 @@ impl Stk500v1 {
         }
         Ok(())
     }
-}
 
-impl Drop for Stk500v1 {
-    fn drop(&mut self) {
-        self.shutdown.store(true, Ordering::Relaxed);
-        for thread in self.thread_handles.drain(..) {
-            thread
-                .join()
-                .unwrap_or_else(|e| eprintln!("Thread join failed: {:?}", e));
+    fn read(&self, num_bytes: usize, enable_progress_bar: bool) -> AvrResult<Vec<u8>> {
+        let page_size = self.params.page_size as usize;
+        let pb = progress_bar(
+            enable_progress_bar,
+            num_bytes.div_ceil(page_size) as u64,
+            "Reading...",
+        );
+
+        debug!("Started reading flash");
+        let mut flash = Vec::with_capacity(num_bytes);
+        let mut extended = None;
+        while flash.len() < num_bytes {
+            self.load_flash_address(flash.len() as u32, &mut extended)?;
+
+            let size = (num_bytes - flash.len()).min(page_size);
+            flash.extend(self.read_page(size as u16, MemoryType::Flash)?);
+
+            if let Some(progress_bar) = &pb {
+                progress_bar.inc(1);
+            }
+        }
+        if let Some(progress_bar) = &pb {
+            progress_bar.finish_with_message("Read.");
+        }
+
+        Ok(flash)
+    }
+
+    /// Write `data` to EEPROM from address 0, one EEPROM page at a time
+    fn upload_eeprom(&self, data: &[u8]) -> AvrResult<()> {
+        debug!("Started programming EEPROM");
+        let page_size = self.params.eeprom_page_size.max(1) as usize;
+
+        for (index, page) in data.chunks(page_size).enumerate() {
+            self.load_eeprom_address(index * page_size)?;
+            self.load_page(page, MemoryType::Eeprom)?;
+            self.wait_for_page_write()?;
         }
+        Ok(())
+    }
+
+    fn verify_eeprom(&self, data: &[u8]) -> AvrResult<()> {
+        debug!("Started verifying EEPROM");
+        let page_size = self.params.eeprom_page_size.max(1) as usize;
+
+        for (index, page) in data.chunks(page_size).enumerate() {
+            self.load_eeprom_address(index * page_size)?;
+            self.verify_page(index * page_size, page, MemoryType::Eeprom)?;
+        }
+        Ok(())
+    }
+
+    /// Offset one past the last non-0xFF byte of flash page `page`, if any
+    fn used_in_page(&self, page: usize) -> AvrResult<Option<usize>> {
+        let page_size = self.params.page_size as usize;
+        self.load_flash_address((page * page_size) as u32, &mut None)?;
+        let bytes = self.read_page(page_size as u16, MemoryType::Flash)?;
+
+        Ok(bytes
+            .iter()
+            .rposition(|byte| *byte != 0xFF)
+            .map(|last| last + 1))
+    }
+
+    /// Binary search over the flash pages for the first page of the erased
+    /// region at the top of flash. This assumes the program is one contiguous
+    /// block starting at address 0; a fully erased page in the middle of it
+    /// hides everything above it
+    fn find_end(&self) -> AvrResult<usize> {
+        let page_size = self.params.page_size as usize;
+
+        // Every page at or above `high` is erased, every page below `low` is used
+        let mut low = 0;
+        let mut high = self.params.num_pages as usize;
+        let mut last_used = None;
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.used_in_page(mid)? {
+                Some(end) => {
+                    last_used = Some(mid * page_size + end);
+                    low = mid + 1;
+                }
+                None => high = mid,
+            }
+        }
+
+        // The last page probed as used is the one just below the erased region
+        let end = match last_used {
+            Some(end) if end.div_ceil(page_size) == high => end,
+            _ if high == 0 => 0,
+            _ => (high - 1) * page_size + self.used_in_page(high - 1)?.unwrap_or(0),
+        };
+        debug!("Program ends at {:#x}", end);
+
+        Ok(end)
     }
 }
 
 impl ProgrammerTrait for Stk500v1 {
-    fn program_firmware(
+    fn program_firmware_at(
         &self,
         firmware: Vec<u8>,
+        start: usize,
         verify: bool,
         enable_progress_bar: bool,
-    ) -> AvrResult<()> {
-        self.reset()?;
-        self.sync()?;
+    ) -> AvrResult<ProgramReport> {
+        let _span = info_span!("program", bytes = firmware.len(), start).entered();
+        let started = Instant::now();
+        let page_size = self.params.page_size as usize;
 
-        self.verify_signature()?;
-        self.set_options()?;
-        self.enter_programming_mode()?;
+        check_start_address(start, page_size, self.flash_size())?;
+        if start > 0 && self.params.isp_mode {
+            return Err(AvrError::ConfigurationError(String::from(
+                "ISP mode erases the whole chip, it can't program at an offset",
+            )));
+        }
+        // Laid out from address 0, the pages below `start` are skipped
+        let image = [vec![0xFF; start], firmware].concat();
 
-        self.upload(firmware.clone(), enable_progress_bar)?;
+        check_image_fits(
+            image.len(),
+            self.flash_size()
+                .saturating_sub(self.params.bootloader_size),
+        )?;
 
-        if verify {
-            self.verify(firmware, enable_progress_bar)?;
-        }
-        self.exit_programming_mode()?;
-        println!("Done! ✨ 🍰 ✨");
+        // Refuse a bad verify range before anything is written
+        let verify_bytes = if verify {
+            verify_span(image.len(), start, page_size, self.verify_range.as_ref())?.len()
+        } else {
+            0
+        };
+        if !self.dry_run {
+            self.begin()?;
 
-        Ok(())
-    }
+            if self.params.safe_mode && self.params.isp_mode {
+                self.check_fuses()?;
+            }
+
+            if let Some(preflight) = &self.preflight {
+                self.check_preflight(preflight)?;
+            }
+
+            // ISP page writes can only clear bits, bootloaders erase each
+            // page themselves
+            if self.params.isp_mode {
+                self.chip_erase()?;
+            }
+        }
+
+        let upload_started = Instant::now();
+        self.upload(image.clone(), start, enable_progress_bar)?;
+        let mut report = ProgramReport {
+            bytes_written: self.bytes_programmed(),
+            pages_written: (image.len() - start).div_ceil(page_size),
+            upload_duration: upload_started.elapsed(),
+            ..ProgramReport::default()
+        };
+        if self.dry_run {
+            report.duration = started.elapsed();
+            return Ok(report);
+        }
+
+        if verify {
+            let verify_started = Instant::now();
+            self.verify(image, start, enable_progress_bar)?;
+            report.verify_bytes = verify_bytes;
+            report.verify_duration = verify_started.elapsed();
+        }
+        self.exit_programming_mode()?;
+        info!("Done! ✨ 🍰 ✨");
+
+        report.duration = started.elapsed();
+        Ok(report)
+    }
+
+    fn verify_firmware(&self, firmware: Vec<u8>, enable_progress_bar: bool) -> AvrResult<()> {
+        check_image_fits(
+            firmware.len(),
+            self.flash_size()
+                .saturating_sub(self.params.bootloader_size),
+        )?;
+        self.begin()?;
+
+        self.verify(firmware, 0, enable_progress_bar)?;
+        self.exit_programming_mode()
+    }
+
+    fn read_flash(&self, num_bytes: usize, enable_progress_bar: bool) -> AvrResult<Vec<u8>> {
+        self.begin()?;
+
+        let flash = self.read(num_bytes, enable_progress_bar)?;
+        self.exit_programming_mode()?;
+
+        Ok(flash)
+    }
+
+    fn find_program_end(&self) -> AvrResult<usize> {
+        self.begin()?;
+
+        let end = self.find_end()?;
+        self.exit_programming_mode()?;
+
+        Ok(end)
+    }
+
+    fn program_eeprom(&self, data: Vec<u8>, verify: bool) -> AvrResult<()> {
+        check_eeprom_range(0, data.len(), self.params.eeprom_size)?;
+        self.begin()?;
+
+        self.upload_eeprom(&data)?;
+        if verify {
+            self.verify_eeprom(&data)?;
+        }
+
+        self.exit_programming_mode()
+    }
+
+    fn read_eeprom(&self, num_bytes: usize) -> AvrResult<Vec<u8>> {
+        check_eeprom_range(0, num_bytes, self.params.eeprom_size)?;
+        self.begin()?;
+
+        let page_size = self.params.eeprom_page_size.max(1) as usize;
+        let mut eeprom = Vec::with_capacity(num_bytes);
+        for page_start in (0..num_bytes).step_by(page_size) {
+            let len = page_size.min(num_bytes - page_start);
+            self.load_eeprom_address(page_start)?;
+            eeprom.extend(self.read_page(len as u16, MemoryType::Eeprom)?);
+        }
+        self.exit_programming_mode()?;
+
+        Ok(eeprom)
+    }
+
+    fn update_eeprom(&self, address: usize, bytes: &[u8]) -> AvrResult<()> {
+        check_eeprom_range(address, bytes.len(), self.params.eeprom_size)?;
+        self.begin()?;
+
+        let page_size = self.params.eeprom_page_size.max(1) as usize;
+        update_eeprom_pages(
+            address,
+            bytes,
+            page_size,
+            |page_start| {
+                self.load_eeprom_address(page_start)?;
+                self.read_page(page_size as u16, MemoryType::Eeprom)
+            },
+            |page_start, page| {
+                self.load_eeprom_address(page_start)?;
+                self.load_page(page, MemoryType::Eeprom)?;
+                self.wait_for_page_write()
+            },
+        )?;
+
+        self.exit_programming_mode()
+    }
+
+    fn erase_chip(&self) -> AvrResult<()> {
+        self.begin()?;
+        self.chip_erase()?;
+        self.exit_programming_mode()
+    }
+
+    fn read_signature(&self) -> AvrResult<[u8; 3]> {
+        self.connect()?;
+        self.set_options()?;
+        self.enter_programming_mode()?;
+
+        let signature = self.query_signature()?;
+        self.exit_programming_mode()?;
+
+        Ok(signature)
+    }
+
+    fn bootloader_version(&self) -> AvrResult<(u8, u8)> {
+        self.read_bootloader_version()
+    }
+
+    fn read_fuses(&self) -> AvrResult<Fuses> {
+        self.begin()?;
+
+        let fuses = self.query_fuses()?;
+        self.exit_programming_mode()?;
+
+        Ok(fuses)
+    }
+
+    fn write_fuses(&self, fuses: Fuses, allow_dangerous: bool) -> AvrResult<()> {
+        if !allow_dangerous {
+            fuses.check_safe_to_write(&self.params.device_signature)?;
+        }
+
+        self.begin()?;
+        let result = self.store_fuses(&fuses);
+        self.exit_programming_mode()?;
+
+        result
+    }
+
+    fn read_lock_bits(&self) -> AvrResult<u8> {
+        self.begin()?;
+
+        let lock = self.universal(READ_LOCK_BITS)?;
+        self.exit_programming_mode()?;
+
+        Ok(lock)
+    }
+
+    fn spi_instruction(&self, instruction: [u8; 4]) -> AvrResult<u8> {
+        self.begin()?;
+
+        let value = self.universal(instruction)?;
+        self.exit_programming_mode()?;
+
+        Ok(value)
+    }
+
+    fn write_lock_bits(&self, bits: u8) -> AvrResult<()> {
+        self.begin()?;
+        let result = self.store_lock_bits(bits);
+        self.exit_programming_mode()?;
+
+        result
+    }
+
+    fn set_preflight(&mut self, preflight: Preflight) -> AvrResult<()> {
+        self.preflight = Some(preflight);
+        Ok(())
+    }
+
+    fn set_progress_callback(&mut self, callback: ProgressCallback) {
+        self.progress = Some(callback);
+    }
+
+    fn set_verify_range(&mut self, range: Option<Range<usize>>) {
+        self.verify_range = range;
+    }
+
+    fn set_dry_run(&mut self, enable: bool) {
+        self.dry_run = enable;
+    }
+
+    fn set_deadline(&mut self, deadline: Duration) {
+        self.transport.set_operation_timeout(Some(deadline));
+    }
+
+    fn set_auto_reset(&mut self, enable: bool) {
+        self.params.auto_reset = enable;
+    }
+
+    fn bytes_programmed(&self) -> usize {
+        self.bytes_programmed.load(Ordering::Relaxed)
+    }
+
+    fn set_timeout_ms(&self, timeout_ms: u64) -> AvrResult<()> {
+        self.transport
+            .set_timeout(Duration::from_millis(timeout_ms))
+    }
+
+    fn flash_size(&self) -> usize {
+        self.params.page_size as usize * self.params.num_pages as usize
+    }
 
     fn reset(&self) -> AvrResult<()> {
-        self.device_interface
-            .lock()
-            .map_err(|_| AvrError::Communication("Failed to lock device_interface".to_string()))?
-            .reset()
-            .map_err(|e| AvrError::Communication(format!("Failed to reset: {:?}", e)))?;
+        self.transport.reset()
+    }
+
+    fn close(&mut self) {
+        self.transport.close();
+    }
+
+    fn reopen(&mut self) -> AvrResult<()> {
+        if !self.owns_port {
+            return Err(not_reopenable());
+        }
+
+        let device_interface = Self::open(&self.params)?;
+        self.transport.reopen(Box::new(device_interface));
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::loopback::LoopbackInterface;
+    use crate::interface::mock::MockDeviceInterface;
+    use crate::protocols::isp::{CHIP_ERASE, POLL_RDY_BSY};
+    use std::sync::{Arc, Mutex};
+    use tracing::span::{Attributes, Id};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::{Layer, Registry};
+
+    const IN_SYNC: u8 = Stk500v1Message::RespStkInSync as u8;
+    const OK: u8 = Stk500v1Message::RespStkOk as u8;
+    const EOP: u8 = Stk500v1Message::SyncCrcEop as u8;
+
+    fn params() -> Stk500v1Params {
+        Stk500v1Params {
+            port: String::from("mock"),
+            baud: 115200,
+            device_signature: vec![0x1e, 0x95, 0x0f],
+            page_size: 128,
+            num_pages: 256,
+            reset_line: ResetLine::Both,
+            ..Default::default()
+        }
+    }
+
+    /// Script for the exchange up to and including entering programming mode
+    fn handshake() -> Vec<(Vec<u8>, Vec<u8>)> {
+        // 128 byte pages, 1KB of EEPROM and 32KB of flash
+        let mut set_device = vec![Stk500v1Message::CmndStkSetDevice as u8];
+        set_device.extend([0; 12]);
+        set_device.extend([0x00, 0x80, 0x04, 0x00, 0x00, 0x00, 0x80, 0x00]);
+        set_device.push(EOP);
+
+        vec![
+            (
+                vec![Stk500v1Message::CmndStkGetSync as u8, EOP],
+                vec![IN_SYNC, OK],
+            ),
+            (
+                vec![Stk500v1Message::CmndStkReadSign as u8, EOP],
+                vec![IN_SYNC, 0x1e, 0x95, 0x0f, OK],
+            ),
+            (set_device, vec![IN_SYNC, OK]),
+            (
+                vec![Stk500v1Message::CmndStkEnterProgMode as u8, EOP],
+                vec![IN_SYNC, OK],
+            ),
+        ]
+    }
+
+    fn universal(instruction: [u8; 4], value: u8) -> (Vec<u8>, Vec<u8>) {
+        (
+            [
+                vec![Stk500v1Message::CmndStkUniversal as u8],
+                instruction.to_vec(),
+                vec![EOP],
+            ]
+            .concat(),
+            vec![IN_SYNC, value, OK],
+        )
+    }
+
+    #[test]
+    fn test_safe_mode_refuses_when_rstdisbl_is_programmed() {
+        let mut script = handshake();
+        script.push(universal(READ_LOW_FUSE, 0xff));
+        // RSTDISBL (bit 7) programmed, SPIEN (bit 5) programmed
+        script.push(universal(READ_HIGH_FUSE, 0x5e));
+        script.push(universal(READ_EXTENDED_FUSE, 0xfd));
+        script.push((
+            vec![Stk500v1Message::CmndStkLeaveProgMode as u8, EOP],
+            vec![IN_SYNC, OK],
+        ));
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v1::with_interface(
+            Stk500v1Params {
+                safe_mode: true,
+                isp_mode: true,
+                ..params()
+            },
+            Box::new(mock),
+        );
+
+        let result = programmer.program_firmware_at(vec![0x0c, 0x94], 0, false, false);
+        match result {
+            Err(AvrError::FuseError(message)) => assert!(message.contains("RSTDISBL")),
+            _ => panic!("Expected a fuse error, got {:?}", result),
+        }
+
+        // Nothing was written to the part
+        let log = log.lock().unwrap();
+        assert!(log.unexpected.is_empty());
+        assert!(
+            !log.sent
+                .iter()
+                .any(|cmd| cmd[0] == Stk500v1Message::CmndStkProgPage as u8)
+        );
+    }
+
+    #[test]
+    fn test_bootloader_version_is_read_after_sync() {
+        let (mock, log) = MockDeviceInterface::new(vec![
+            (
+                vec![Stk500v1Message::CmndStkGetSync as u8, EOP],
+                vec![IN_SYNC, OK],
+            ),
+            (
+                vec![Stk500v1Message::CmndStkGetParameter as u8, 0x81, EOP],
+                vec![IN_SYNC, 8, OK],
+            ),
+            (
+                vec![Stk500v1Message::CmndStkGetParameter as u8, 0x82, EOP],
+                vec![IN_SYNC, 3, OK],
+            ),
+        ]);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        assert_eq!(programmer.read_bootloader_version().unwrap(), (8, 3));
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_signature_mismatch_is_refused() {
+        let (mock, log) = MockDeviceInterface::new(vec![(
+            vec![Stk500v1Message::CmndStkReadSign as u8, EOP],
+            vec![IN_SYNC, 0x1e, 0x98, 0x01, OK],
+        )]);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        assert!(matches!(
+            programmer.verify_signature(),
+            Err(AvrError::ProgrammerError(_))
+        ));
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_flash_address_is_sent_as_little_endian_word_address() {
+        let (mock, log) = MockDeviceInterface::new(vec![(
+            vec![Stk500v1Message::CmndStkLoadAddress as u8, 0x34, 0x12, EOP],
+            vec![IN_SYNC, OK],
+        )]);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        programmer
+            .load_flash_address(0x1234 * 2, &mut None)
+            .unwrap();
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_signature_is_returned_even_when_it_does_not_match() {
+        let mut script = handshake();
+        // No signature check during the handshake
+        script.remove(1);
+        script.push((
+            vec![Stk500v1Message::CmndStkReadSign as u8, EOP],
+            vec![IN_SYNC, 0x1e, 0x98, 0x01, OK],
+        ));
+        script.push((
+            vec![Stk500v1Message::CmndStkLeaveProgMode as u8, EOP],
+            vec![IN_SYNC, OK],
+        ));
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        assert_eq!(programmer.read_signature().unwrap(), [0x1e, 0x98, 0x01]);
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_extended_fuse_is_skipped_on_parts_without_one() {
+        let atmega8 = vec![0x1e, 0x93, 0x07];
+        let mut script = handshake();
+        script[1].1 = vec![IN_SYNC, 0x1e, 0x93, 0x07, OK];
+        script.push(universal(READ_LOW_FUSE, 0xe1));
+        script.push(universal(READ_HIGH_FUSE, 0xd9));
+        script.push((
+            vec![Stk500v1Message::CmndStkLeaveProgMode as u8, EOP],
+            vec![IN_SYNC, OK],
+        ));
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v1::with_interface(
+            Stk500v1Params {
+                device_signature: atmega8,
+                ..params()
+            },
+            Box::new(mock),
+        );
+
+        let fuses = programmer.read_fuses().unwrap();
+        assert_eq!(
+            fuses,
+            Fuses {
+                low: 0xe1,
+                high: 0xd9,
+                extended: None,
+            }
+        );
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_fuse_write_is_read_back() {
+        let mut script = handshake();
+        script.push(universal([0xAC, 0xA0, 0x00, 0xf7], 0x00));
+        script.push(universal([0xAC, 0xA8, 0x00, 0xd9], 0x00));
+        script.push(universal([0xAC, 0xA4, 0x00, 0xfd], 0x00));
+        script.push(universal(READ_LOW_FUSE, 0xf7));
+        script.push(universal(READ_HIGH_FUSE, 0xd9));
+        script.push(universal(READ_EXTENDED_FUSE, 0xfd));
+        script.push((
+            vec![Stk500v1Message::CmndStkLeaveProgMode as u8, EOP],
+            vec![IN_SYNC, OK],
+        ));
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        let fuses = Fuses {
+            low: 0xf7,
+            high: 0xd9,
+            extended: Some(0xfd),
+        };
+        programmer.write_fuses(fuses, false).unwrap();
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_lock_bits_write_is_read_back() {
+        let mut script = handshake();
+        script.push(universal([0xAC, 0xE0, 0x00, 0xfc], 0x00));
+        script.push(universal(READ_LOCK_BITS, 0xfc));
+        script.push((
+            vec![Stk500v1Message::CmndStkLeaveProgMode as u8, EOP],
+            vec![IN_SYNC, OK],
+        ));
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        programmer.write_lock_bits(0xfc).unwrap();
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_spi_instruction_returns_last_byte() {
+        let mut script = handshake();
+        script.push(universal([0x38, 0x00, 0x00, 0x00], 0x9a));
+        script.push((
+            vec![Stk500v1Message::CmndStkLeaveProgMode as u8, EOP],
+            vec![IN_SYNC, OK],
+        ));
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        assert_eq!(
+            programmer
+                .spi_instruction([0x38, 0x00, 0x00, 0x00])
+                .unwrap(),
+            0x9a
+        );
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_dangerous_fuse_write_is_refused() {
+        let (mock, log) = MockDeviceInterface::new(vec![]);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        // RSTDISBL programmed
+        let result = programmer.write_fuses(
+            Fuses {
+                low: 0xff,
+                high: 0x5e,
+                extended: Some(0xfd),
+            },
+            false,
+        );
+        match result {
+            Err(AvrError::ConfigurationError(message)) => assert!(message.contains("RSTDISBL")),
+            _ => panic!("Expected a configuration error, got {:?}", result),
+        }
+
+        // Reserved clock source
+        let result = programmer.write_fuses(
+            Fuses {
+                low: 0xf1,
+                high: 0xde,
+                extended: Some(0xfd),
+            },
+            false,
+        );
+        match result {
+            Err(AvrError::ConfigurationError(message)) => assert!(message.contains("CKSEL")),
+            _ => panic!("Expected a configuration error, got {:?}", result),
+        }
+
+        // Nothing was sent to the part
+        assert!(log.lock().unwrap().sent.is_empty());
+    }
+
+    #[test]
+    fn test_preflight_refuses_when_fuses_mismatch() {
+        let mut script = handshake();
+        script.push((
+            vec![Stk500v1Message::CmndStkReadSign as u8, EOP],
+            vec![IN_SYNC, 0x1e, 0x95, 0x0f, OK],
+        ));
+        script.push(universal(READ_LOW_FUSE, 0xff));
+        script.push(universal(READ_HIGH_FUSE, 0xda));
+        script.push(universal(READ_EXTENDED_FUSE, 0xfd));
+        script.push(universal(READ_LOCK_BITS, 0xff));
+        script.push((
+            vec![Stk500v1Message::CmndStkLeaveProgMode as u8, EOP],
+            vec![IN_SYNC, OK],
+        ));
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let mut programmer = Stk500v1::with_interface(params(), Box::new(mock));
+        programmer
+            .set_preflight(Preflight {
+                signature: vec![0x1e, 0x95, 0x0f],
+                fuses: Some(Fuses {
+                    low: 0xff,
+                    high: 0xde,
+                    extended: Some(0xfd),
+                }),
+                lock: Some(0xff),
+            })
+            .unwrap();
+
+        let result = programmer.program_firmware_at(vec![0x0c, 0x94], 0, false, false);
+        match result {
+            Err(AvrError::PreflightError(message)) => {
+                assert!(message.contains("high fuse 0xda (expected 0xde)"));
+                assert!(!message.contains("low fuse"));
+            }
+            _ => panic!("Expected a preflight error, got {:?}", result),
+        }
+
+        let log = log.lock().unwrap();
+        assert!(log.unexpected.is_empty());
+        assert!(
+            !log.sent
+                .iter()
+                .any(|cmd| cmd[0] == Stk500v1Message::CmndStkProgPage as u8)
+        );
+    }
+
+    #[test]
+    fn test_configured_sync_count_is_issued_before_signature() {
+        let mut script = handshake();
+        let sync = script[0].clone();
+        script.insert(0, sync.clone());
+        script.insert(0, sync);
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v1::with_interface(
+            Stk500v1Params {
+                sync_count: TRIPLE_SYNC,
+                ..params()
+            },
+            Box::new(mock),
+        );
+        assert!(
+            programmer
+                .program_firmware_at(vec![0x0c, 0x94], 0, false, false)
+                .is_err()
+        );
+
+        let log = log.lock().unwrap();
+        let get_sync = vec![Stk500v1Message::CmndStkGetSync as u8, EOP];
+        assert_eq!(
+            log.sent[..3],
+            [get_sync.clone(), get_sync.clone(), get_sync]
+        );
+        assert_eq!(log.sent[3][0], Stk500v1Message::CmndStkReadSign as u8);
+    }
+
+    #[test]
+    fn test_custom_memory_type_flows_through_to_commands() {
+        let memory = MemoryType::Custom(0x53);
+        let script = vec![
+            (
+                vec![
+                    Stk500v1Message::CmndStkProgPage as u8,
+                    0,
+                    2,
+                    0x53,
+                    0xAB,
+                    0xCD,
+                    EOP,
+                ],
+                vec![IN_SYNC, OK],
+            ),
+            (
+                vec![Stk500v1Message::CmndStkReadPage as u8, 0, 2, 0x53, EOP],
+                vec![IN_SYNC, 0xAB, 0xCD, OK],
+            ),
+            (
+                vec![Stk500v1Message::CmndStkReadPage as u8, 0, 2, 0x53, EOP],
+                vec![IN_SYNC, 0xAB, 0xCD, OK],
+            ),
+        ];
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        programmer.load_page(&[0xAB, 0xCD], memory).unwrap();
+        programmer.verify_page(2, &[0xAB, 0xCD], memory).unwrap();
+        assert_eq!(programmer.read_page(2, memory).unwrap(), vec![0xAB, 0xCD]);
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_trailing_bytes_after_response_are_ignored() {
+        let (mock, log) = MockDeviceInterface::new(vec![(
+            vec![Stk500v1Message::CmndStkGetSync as u8, EOP],
+            vec![IN_SYNC, OK, 0xAA, 0x55],
+        )]);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        programmer.sync().unwrap();
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_sync_is_retried_after_a_bad_response() {
+        let get_sync = vec![Stk500v1Message::CmndStkGetSync as u8, EOP];
+        let (mock, log) = MockDeviceInterface::new(vec![
+            (get_sync.clone(), vec![0x00, 0x00]),
+            (get_sync, vec![IN_SYNC, OK]),
+        ]);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        programmer.sync().unwrap();
+        assert_eq!(log.lock().unwrap().sent.len(), 2);
+    }
+
+    #[test]
+    fn test_bootloader_banner_is_flushed_before_sync() {
+        let (mut mock, log) = MockDeviceInterface::new(vec![(
+            vec![Stk500v1Message::CmndStkGetSync as u8, EOP],
+            vec![IN_SYNC, OK],
+        )]);
+        mock.emit_on_reset(b"Optiboot\r\n".to_vec());
+        let programmer = Stk500v1::blocking_with_interface(
+            Stk500v1Params {
+                sync_config: SyncConfig {
+                    attempts: 1,
+                    delay_ms: 0,
+                },
+                ..params()
+            },
+            Box::new(mock),
+        );
+
+        programmer.connect().unwrap();
+        assert_eq!(log.lock().unwrap().sent.len(), 1);
+    }
+
+    /// Name of a span, and of its parent if any
+    type SpanName = (&'static str, Option<&'static str>);
+
+    /// Subscriber layer that keeps the name of every span and its parent's
+    struct SpanLayer {
+        spans: Arc<Mutex<Vec<SpanName>>>,
+    }
+
+    impl<S> Layer<S> for SpanLayer
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            let parent = span.parent().map(|parent| parent.name());
+            self.spans.lock().unwrap().push((span.name(), parent));
+        }
+    }
+
+    #[test]
+    fn test_programming_phases_are_nested_in_spans() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = Registry::default().with(SpanLayer {
+            spans: Arc::clone(&spans),
+        });
+
+        tracing::subscriber::with_default(subscriber, || {
+            let device = LoopbackInterface::new(vec![0x1e, 0x95, 0x0f], 32 * 1024);
+            let programmer = Stk500v1::blocking_with_interface(params(), Box::new(device));
+            programmer
+                .program_firmware_at(vec![0x5A; 300], 0, true, false)
+                .unwrap();
+        });
+
+        let spans = spans.lock().unwrap();
+        for phase in ["reset", "sync", "verify_signature", "upload", "verify"] {
+            assert!(
+                spans.contains(&(phase, Some("program"))),
+                "No {} span inside program: {:?}",
+                phase,
+                spans
+            );
+        }
+    }
+
+    #[test]
+    fn test_sync_without_auto_reset_leaves_reset_line_alone() {
+        let (mock, log) = MockDeviceInterface::new(vec![(
+            vec![Stk500v1Message::CmndStkGetSync as u8, EOP],
+            vec![IN_SYNC, OK],
+        )]);
+        let mut programmer = Stk500v1::blocking_with_interface(params(), Box::new(mock));
+        programmer.set_auto_reset(false);
+
+        programmer.connect().unwrap();
+        let log = log.lock().unwrap();
+        assert_eq!(log.resets, 0);
+        assert!(log.unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_sync_gives_up_after_configured_attempts() {
+        let (mock, log) = MockDeviceInterface::new(vec![]);
+        let programmer = Stk500v1::with_interface(
+            Stk500v1Params {
+                sync_config: SyncConfig {
+                    attempts: 3,
+                    delay_ms: 0,
+                },
+                ..params()
+            },
+            Box::new(mock),
+        );
+
+        assert!(matches!(
+            programmer.sync(),
+            Err(AvrError::ProgrammerError(_))
+        ));
+        assert_eq!(log.lock().unwrap().sent.len(), 3);
+    }
+
+    #[test]
+    fn test_leave_progmode_is_retried_after_a_nak() {
+        let leave = vec![Stk500v1Message::CmndStkLeaveProgMode as u8, EOP];
+        let mut script = handshake();
+        script.push((leave.clone(), vec![IN_SYNC, 0x11]));
+        script.push((leave.clone(), vec![IN_SYNC, OK]));
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        programmer.begin().unwrap();
+        programmer.exit_programming_mode().unwrap();
+
+        let log = log.lock().unwrap();
+        assert!(log.unexpected.is_empty());
+        assert_eq!(log.sent.iter().filter(|cmd| **cmd == leave).count(), 2);
+    }
+
+    #[test]
+    fn test_erase_polls_until_ready() {
+        let mut script = handshake();
+        script.push(universal(CHIP_ERASE, 0x00));
+        script.push(universal(POLL_RDY_BSY, 0x01));
+        script.push(universal(POLL_RDY_BSY, 0x00));
+        script.push((
+            vec![Stk500v1Message::CmndStkLeaveProgMode as u8, EOP],
+            vec![IN_SYNC, OK],
+        ));
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        programmer.erase_chip().unwrap();
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_erase_without_acknowledge_is_a_programmer_error() {
+        // The script ends before the chip erase command
+        let (mock, _log) = MockDeviceInterface::new(handshake());
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        match programmer.erase_chip() {
+            Err(AvrError::ProgrammerError(message)) => assert!(message.contains("chip erase")),
+            result => panic!("Expected a programmer error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_eeprom_pages_are_loaded_at_halved_addresses() {
+        let load_address = |word_address: u8| {
+            (
+                vec![
+                    Stk500v1Message::CmndStkLoadAddress as u8,
+                    word_address,
+                    0,
+                    EOP,
+                ],
+                vec![IN_SYNC, OK],
+            )
+        };
+        let prog_page = |bytes: &[u8]| {
+            (
+                [
+                    vec![
+                        Stk500v1Message::CmndStkProgPage as u8,
+                        0,
+                        bytes.len() as u8,
+                        MemoryType::Eeprom.as_byte(),
+                    ],
+                    bytes.to_vec(),
+                    vec![EOP],
+                ]
+                .concat(),
+                vec![IN_SYNC, OK],
+            )
+        };
+        // Optiboot doubles the loaded address, so the page at byte 4 is
+        // loaded as 2
+        let script = vec![
+            load_address(0),
+            prog_page(&[1, 2, 3, 4]),
+            load_address(2),
+            prog_page(&[5, 6]),
+        ];
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        programmer.upload_eeprom(&[1, 2, 3, 4, 5, 6]).unwrap();
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_final_partial_page_includes_last_byte() {
+        let load_address = |word_address: u8| {
+            (
+                vec![
+                    Stk500v1Message::CmndStkLoadAddress as u8,
+                    word_address,
+                    0,
+                    EOP,
+                ],
+                vec![IN_SYNC, OK],
+            )
+        };
+        let prog_page = |bytes: &[u8]| {
+            (
+                [
+                    vec![
+                        Stk500v1Message::CmndStkProgPage as u8,
+                        0,
+                        bytes.len() as u8,
+                        MemoryType::Flash.as_byte(),
+                    ],
+                    bytes.to_vec(),
+                    vec![EOP],
+                ]
+                .concat(),
+                vec![IN_SYNC, OK],
+            )
+        };
+        let script = vec![
+            load_address(0),
+            prog_page(&[1, 2, 3, 4]),
+            load_address(2),
+            prog_page(&[5, 6, 7]),
+        ];
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v1::with_interface(
+            Stk500v1Params {
+                page_size: 4,
+                ..params()
+            },
+            Box::new(mock),
+        );
+
+        programmer
+            .upload(vec![1, 2, 3, 4, 5, 6, 7], 0, false)
+            .unwrap();
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_page_write_polls_until_ready() {
+        let page = [
+            vec![
+                Stk500v1Message::CmndStkProgPage as u8,
+                0,
+                4,
+                MemoryType::Flash.as_byte(),
+            ],
+            vec![1, 2, 3, 4],
+            vec![EOP],
+        ]
+        .concat();
+        let script = vec![
+            (
+                vec![Stk500v1Message::CmndStkLoadAddress as u8, 0, 0, EOP],
+                vec![IN_SYNC, OK],
+            ),
+            (page, vec![IN_SYNC, OK]),
+            universal(POLL_RDY_BSY, 0x01),
+            universal(POLL_RDY_BSY, 0x00),
+        ];
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v1::with_interface(
+            Stk500v1Params {
+                page_size: 4,
+                page_write_wait: PageWriteWait::PollReady,
+                ..params()
+            },
+            Box::new(mock),
+        );
+
+        programmer.upload(vec![1, 2, 3, 4], 0, false).unwrap();
+        let log = log.lock().unwrap();
+        assert!(log.unexpected.is_empty());
+        assert_eq!(log.sent.len(), 4);
+    }
+
+    #[test]
+    fn test_isp_mode_writes_page_through_universal_command() {
+        let script = vec![
+            universal([LOAD_PROGRAM_MEMORY_LOW, 0x00, 0x02, 0x11], 0x00),
+            universal([LOAD_PROGRAM_MEMORY_HIGH, 0x00, 0x02, 0x22], 0x00),
+            universal([LOAD_PROGRAM_MEMORY_LOW, 0x00, 0x03, 0x33], 0x00),
+            universal([WRITE_PROGRAM_MEMORY_PAGE, 0x00, 0x02, 0x00], 0x00),
+            universal(POLL_RDY_BSY, 0x00),
+        ];
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v1::with_interface(
+            Stk500v1Params {
+                page_size: 4,
+                isp_mode: true,
+                ..params()
+            },
+            Box::new(mock),
+        );
+
+        // Second page of a 4 byte page part, so word address 2
+        programmer
+            .isp_write_page(4, &[0x11, 0x22, 0x33], &mut None)
+            .unwrap();
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_verify_range_only_reads_pages_in_range() {
+        let read_page = Stk500v1Message::CmndStkReadPage as u8;
+        let script = vec![
+            (
+                vec![Stk500v1Message::CmndStkLoadAddress as u8, 2, 0, EOP],
+                vec![IN_SYNC, OK],
+            ),
+            (
+                vec![read_page, 0, 3, MemoryType::Flash.as_byte(), EOP],
+                vec![IN_SYNC, 4, 5, 6, OK],
+            ),
+        ];
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let mut programmer = Stk500v1::with_interface(
+            Stk500v1Params {
+                page_size: 4,
+                ..params()
+            },
+            Box::new(mock),
+        );
+        let image: Vec<u8> = (0..12).collect();
+
+        // Starts mid-page, so the whole page from 4 is read back
+        programmer.set_verify_range(Some(5..7));
+        programmer.verify(image.clone(), 0, false).unwrap();
+
+        let log = log.lock().unwrap();
+        assert!(log.unexpected.is_empty());
+        assert_eq!(log.sent.iter().filter(|cmd| cmd[0] == read_page).count(), 1);
+        drop(log);
+
+        programmer.set_verify_range(Some(8..16));
+        assert!(matches!(
+            programmer.verify(image, 0, false),
+            Err(AvrError::ConfigurationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_dry_run_walks_pages_without_sending_anything() {
+        let (mock, log) = MockDeviceInterface::new(vec![]);
+        let mut programmer = Stk500v1::with_interface(params(), Box::new(mock));
+        programmer.set_dry_run(true);
+
+        let report = programmer
+            .program_firmware_at(vec![0x5A; 300], 0, true, false)
+            .unwrap();
+
+        assert_eq!(report.bytes_written, 300);
+        assert_eq!(report.pages_written, 3);
+        assert_eq!(report.verify_bytes, 0);
+        let log = log.lock().unwrap();
+        assert!(log.sent.is_empty());
+        assert_eq!(log.resets, 0);
+    }
+
+    #[test]
+    fn test_firmware_larger_than_flash_is_refused() {
+        let (mock, log) = MockDeviceInterface::new(vec![]);
+        let programmer = Stk500v1::with_interface(
+            Stk500v1Params {
+                page_size: 4,
+                num_pages: 4,
+                bootloader_size: 4,
+                ..params()
+            },
+            Box::new(mock),
+        );
+
+        // Fits in the 16 byte flash, but overlaps the bootloader
+        match programmer.program_firmware_at(vec![0x5A; 13], 0, false, false) {
+            Err(AvrError::FirmwareError(message)) => {
+                assert!(message.contains("13") && message.contains("12"));
+            }
+            result => panic!("Expected a firmware error, got {:?}", result),
+        }
+        assert!(log.lock().unwrap().sent.is_empty());
+    }
+
+    #[test]
+    fn test_verify_mismatch_reports_address_and_bytes() {
+        let flash = MemoryType::Flash.as_byte();
+        let (mock, _log) = MockDeviceInterface::new(vec![(
+            vec![Stk500v1Message::CmndStkReadPage as u8, 0, 4, flash, EOP],
+            vec![IN_SYNC, 0x01, 0x02, 0x33, 0x04, OK],
+        )]);
+        let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+
+        match programmer.verify_page(0x100, &[0x01, 0x02, 0x03, 0x04], MemoryType::Flash) {
+            Err(AvrError::ProgrammerError(message)) => {
+                assert!(message.contains("address 0x102"));
+                assert!(message.contains("wrote 0x03, read 0x33"));
+            }
+            result => panic!("Expected a programmer error, got {:?}", result),
+        }
+    }
+
+    /// Simulated bootloader whose port goes away after `pages` page writes
+    struct UnpluggedAfter {
+        inner: LoopbackInterface,
+        pages: usize,
+    }
+
+    impl DeviceInterface for UnpluggedAfter {
+        fn send(&mut self, command: Vec<u8>) -> AvrResult<()> {
+            if command.first() == Some(&(Stk500v1Message::CmndStkProgPage as u8)) {
+                self.pages = self.pages.saturating_sub(1);
+            }
+            self.inner.send(command)
+        }
+
+        fn receive(&mut self) -> AvrResult<Vec<u8>> {
+            if self.pages == 0 {
+                return Err(AvrError::Communication(String::from(
+                    "Device disconnected: Broken pipe",
+                )));
+            }
+            self.inner.receive()
+        }
+
+        fn reset(&mut self) -> AvrResult<()> {
+            self.inner.reset()
+        }
+    }
+
+    #[test]
+    fn test_disconnect_during_upload_fails_programming() {
+        let device = UnpluggedAfter {
+            inner: LoopbackInterface::new(vec![0x1e, 0x95, 0x0f], 32 * 1024),
+            pages: 3,
+        };
+        let programmer = Stk500v1::with_interface(params(), Box::new(device));
+
+        let start = Instant::now();
+        let result = programmer.program_firmware_at(vec![0x5A; 128 * 8], 0, true, false);
+        assert!(
+            matches!(&result, Err(AvrError::Communication(e)) if e.starts_with("Device disconnected")),
+            "{:?}",
+            result
+        );
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_transport_threads_stop_when_dropped() {
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (mock, _log) = MockDeviceInterface::new(handshake());
+            let programmer = Stk500v1::with_interface(params(), Box::new(mock));
+            programmer.connect().unwrap();
+            drop(programmer);
+            done_tx.send(()).unwrap();
+        });
+
+        // Drop joins both transport threads
+        assert!(done_rx.recv_timeout(Duration::from_secs(2)).is_ok());
+    }
+}
@@ -1,372 +1,625 @@
-use indicatif::ProgressBar;
-use tracing::debug;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use crate::constants::{SERIAL_TIMEOUT_MS, TRANSPORT_THREAD_SLEEP_MICROS};
+use tracing::{debug, info, info_span, warn};
+
+use super::isp::{
+    CHIP_ERASE, EXTENDED_ADDRESS_FLASH_SIZE, LOCK_BITS_MASK, READ_EXTENDED_FUSE, READ_HIGH_FUSE,
+    READ_LOCK_BITS, READ_LOW_FUSE, WRITE_EXTENDED_FUSE, WRITE_HIGH_FUSE, WRITE_LOCK_BITS,
+    WRITE_LOW_FUSE,
+};
+use super::transport::Transport;
+use super::{
+    MemoryType, check_eeprom_range, check_image_fits, check_start_address, not_reopenable,
+    update_eeprom_pages, verify_span,
+};
+use crate::constants::{DEFAULT_STK500V2_EEPROM_PAGE_SIZE, DEFAULT_STK500V2_EEPROM_SIZE};
 use crate::error::AvrError;
+use crate::fuses::Fuses;
 use crate::interface::DeviceInterface;
-use crate::interface::serialport::SerialPortDevice;
-use crate::util::create_progress_bar;
+use crate::interface::serialport::{
+    DEFAULT_SERIAL_TIMEOUT_MS, ResetLine, ResetTiming, SerialPortDevice,
+};
+use crate::preflight::Preflight;
+use crate::progress::{Phase, ProgressCallback, ProgressReporter};
+use crate::report::ProgramReport;
+use crate::util::progress_bar;
 use crate::{ProgrammerTrait, error::AvrResult};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex, mpsc};
-use std::thread::JoinHandle;
 
+/// Every message is framed as MESSAGE_START, sequence number, body size
+/// (big endian), TOKEN, body and an XOR checksum over all preceding bytes
+pub const MESSAGE_START: u8 = 0x1B;
+pub const TOKEN: u8 = 0x0E;
+const FRAME_HEADER_SIZE: usize = 5;
+
+/// Command and status bytes, named as in Atmel's AVR068 application note
 #[repr(u8)]
 pub enum Stk500v2Message {
-    RespStkOk = 0x10,
-    RespStkInSync = 0x14,
-    SyncCrcEop = 0x20,
-    CmndStkGetSync = 0x30,
-    CmndStkSetDevice = 0x42,
-    CmndStkEnterProgMode = 0x50,
-    CmndStkLeaveProgMode = 0x51,
-    CmndStkLoadAddress = 0x55,
-    CmndStkProgPage = 0x64,
-    CmndStkReadPage = 0x74,
-    CmndStkReadSign = 0x75,
+    StatusCmdOk = 0x00,
+    CmdSignOn = 0x01,
+    CmdGetParameter = 0x03,
+    CmdLoadAddress = 0x06,
+    CmdEnterProgmodeIsp = 0x10,
+    CmdLeaveProgmodeIsp = 0x11,
+    CmdChipEraseIsp = 0x12,
+    CmdProgramFlashIsp = 0x13,
+    CmdReadFlashIsp = 0x14,
+    CmdProgramEepromIsp = 0x15,
+    CmdReadEepromIsp = 0x16,
+    CmdProgramFuseIsp = 0x17,
+    CmdReadFuseIsp = 0x18,
+    CmdProgramLockIsp = 0x19,
+    CmdReadLockIsp = 0x1A,
+    CmdReadSignatureIsp = 0x1B,
+    CmdSpiMulti = 0x1D,
+    ParamSwMajor = 0x91,
+    ParamSwMinor = 0x92,
 }
 
+/// Timing and polling parameters for entering ISP programming mode, as
+/// avrdude sends them for the ATmega2560
+const ENTER_PROGMODE_PARAMS: [u8; 11] = [
+    200,  // timeout
+    100,  // stabilization delay
+    25,   // command execution delay
+    32,   // synchronization loops
+    0,    // byte delay
+    0x53, // poll value
+    3,    // poll index
+    0xAC, 0x53, 0x00, 0x00, // programming enable instruction
+];
+const LEAVE_PROGMODE_PARAMS: [u8; 2] = [1, 1];
+const CHIP_ERASE_DELAY_MS: u8 = 55;
+
+/// Page mode write with value polling, followed by the load page, write page
+/// and read instructions and the two poll values
+const PROGRAM_FLASH_PARAMS: [u8; 7] = [0xC1, 10, 0x40, 0x4C, 0x20, 0x00, 0x00];
+const PROGRAM_EEPROM_PARAMS: [u8; 7] = [0xC1, 10, 0xC1, 0xC2, 0xA0, 0xFF, 0xFF];
+const READ_FLASH_INSTRUCTION: u8 = 0x20;
+const READ_EEPROM_INSTRUCTION: u8 = 0xA0;
+const READ_SIGNATURE_INSTRUCTION: u8 = 0x30;
+
+/// Position of the returned byte in the 4 byte ISP instruction
+const ISP_RETURN_ADDRESS: u8 = 4;
+
+/// Flash above 128KB needs the extended address byte, which the bootloader
+/// loads when bit 31 of the address is set
+const LOAD_EXTENDED_ADDRESS_BIT: u32 = 0x8000_0000;
+
 pub struct Stk500v2Params {
     pub port: String,
     pub baud: u32,
     pub device_signature: Vec<u8>,
     pub page_size: u16,
+    pub num_pages: u16,
     pub product_id: Vec<u16>,
+
+    /// EEPROM page size in bytes (8 on the ATmega2560)
+    pub eeprom_page_size: u16,
+
+    /// EEPROM size in bytes (4096 on the ATmega2560)
+    pub eeprom_size: usize,
+
+    /// Control line(s) toggled to reset the board
+    pub reset_line: ResetLine,
+
+    /// Reset pulse width and bootloader start up delay
+    pub reset_timing: ResetTiming,
+
+    /// Delay between the bytes of a command, for slow bit-banged or
+    /// opto-isolated links. 0 writes each command in one go
+    pub inter_byte_delay_micros: u64,
 }
 
-pub(crate) struct Stk500v2 {
-    source: mpsc::Receiver<Vec<u8>>,
-    sink: mpsc::Sender<Vec<u8>>,
+/// An ATmega2560 (eg: Arduino Mega) with no port set. Fill in the fields that
+/// differ and take the rest with `..Default::default()`
+impl Default for Stk500v2Params {
+    fn default() -> Self {
+        Stk500v2Params {
+            port: String::new(),
+            baud: 115200,
+            device_signature: vec![0x1e, 0x98, 0x01],
+            page_size: 256,
+            num_pages: 1024,
+            product_id: vec![],
+            eeprom_page_size: DEFAULT_STK500V2_EEPROM_PAGE_SIZE,
+            eeprom_size: DEFAULT_STK500V2_EEPROM_SIZE,
+            reset_line: ResetLine::default(),
+            reset_timing: ResetTiming::default(),
+            inter_byte_delay_micros: 0,
+        }
+    }
+}
+
+/// Wrap a message body in an STK500v2 frame
+pub(crate) fn frame(sequence: u8, body: &[u8]) -> Vec<u8> {
+    let size = body.len() as u16;
+    let mut frame = vec![
+        MESSAGE_START,
+        sequence,
+        (size >> 8) as u8,
+        (size & 0xFF) as u8,
+        TOKEN,
+    ];
+    frame.extend_from_slice(body);
+    frame.push(checksum(&frame));
+    frame
+}
 
-    device_interface: Arc<Mutex<Box<dyn DeviceInterface + Send>>>,
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+pub(crate) struct Stk500v2 {
+    transport: Transport,
     pub params: Stk500v2Params,
+    sequence: AtomicU8,
+    preflight: Option<Preflight>,
+    progress: Option<ProgressCallback>,
+    verify_range: Option<Range<usize>>,
+    bytes_programmed: AtomicUsize,
+    dry_run: bool,
+    auto_reset: bool,
 
-    shutdown: Arc<AtomicBool>,
-    thread_handles: Vec<JoinHandle<()>>,
+    /// Opened its own serial port from the params, so reopen can open it again
+    owns_port: bool,
 }
 
 impl Stk500v2 {
     pub fn new(params: Stk500v2Params) -> AvrResult<Self> {
-        let device_interface: Box<dyn DeviceInterface + Send> =
-            Box::new(SerialPortDevice::new(params.port.clone(), params.baud)?);
-        let (sink, sender_rx) = mpsc::channel();
-        let (receiver_tx, source) = mpsc::channel();
-
-        let device_interface = Arc::new(Mutex::new(device_interface));
-        let transport_sender = Arc::clone(&device_interface);
-        let transport_receiver = Arc::clone(&transport_sender);
-
-        let shutdown = Arc::new(AtomicBool::new(false));
-        let shutdown1 = Arc::clone(&shutdown);
-        let shutdown2 = Arc::clone(&shutdown);
-
-        // Sender thread
-        let send_handle = std::thread::spawn(move || {
-            while !shutdown1.load(Ordering::Relaxed) {
-                std::thread::sleep(std::time::Duration::from_micros(
-                    TRANSPORT_THREAD_SLEEP_MICROS,
-                ));
-                let recv_result =
-                    sender_rx.recv_timeout(std::time::Duration::from_millis(SERIAL_TIMEOUT_MS));
-                match recv_result {
-                    Ok(command) => {
-                        let mut device_interface = transport_sender
-                            .lock()
-                            .expect("Failed to lock device_interface (sender thread)");
-                        if let Err(e) = device_interface.send(command) {
-                            eprintln!("Error sending command: {:?}", e);
-                        }
-                    }
-                    Err(mpsc::RecvTimeoutError::Timeout) => {
-                        // Ignore timeout, continue running
-                    }
-                    Err(e) => {
-                        eprintln!("Sender thread terminated. {e}");
-                        break;
-                    }
-                }
-            }
-        });
-
-        // Receiver thread
-        let receive_handle = std::thread::spawn(move || {
-            while !shutdown2.load(Ordering::Relaxed) {
-                std::thread::sleep(std::time::Duration::from_micros(
-                    TRANSPORT_THREAD_SLEEP_MICROS,
-                ));
-                let mut device_interface = transport_receiver
-                    .lock()
-                    .expect("Failed to lock device_interface (receiver thread)");
-                match device_interface.receive() {
-                    Ok(response) => {
-                        if let Err(e) = receiver_tx.send(response) {
-                            eprintln!("Error sending response: {:?}", e);
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Error receiving response: {:?}", e);
-                        break;
-                    }
-                }
-            }
-        });
-
+        let device_interface = Self::open(&params)?;
         Ok(Stk500v2 {
-            source,
-            sink,
-            device_interface,
-            params,
-            shutdown,
-            thread_handles: vec![send_handle, receive_handle],
+            owns_port: true,
+            ..Self::with_interface(params, Box::new(device_interface))
         })
     }
 
-    pub(crate) fn send_command(&self, command: Vec<u8>) -> AvrResult<()> {
-        self.sink
-            .send(command)
-            .map_err(|e| AvrError::Communication(format!("Failed to send command: {:?}", e)))?;
-        Ok(())
+    fn open(params: &Stk500v2Params) -> AvrResult<SerialPortDevice> {
+        SerialPortDevice::open_with_retry(
+            params.port.clone(),
+            params.baud,
+            params.reset_line,
+            params.reset_timing,
+            params.inter_byte_delay_micros,
+            DEFAULT_SERIAL_TIMEOUT_MS,
+            &params.product_id,
+        )
     }
 
-    pub(crate) fn receive_response_with_size(&self, expected_size: usize) -> AvrResult<Vec<u8>> {
-        let mut received = Vec::new();
+    pub(crate) fn with_interface(
+        params: Stk500v2Params,
+        device_interface: Box<dyn DeviceInterface + Send>,
+    ) -> Self {
+        Stk500v2 {
+            transport: Transport::new(device_interface),
+            params,
+            sequence: AtomicU8::new(0),
+            preflight: None,
+            progress: None,
+            verify_range: None,
+            bytes_programmed: AtomicUsize::new(0),
+            dry_run: false,
+            auto_reset: true,
+            owns_port: false,
+        }
+    }
+
+    /// Read one frame with the given sequence number and return its body
+    fn receive_frame(&self, sequence: u8) -> AvrResult<Vec<u8>> {
+        let mut response = self
+            .transport
+            .receive_response_with_size(FRAME_HEADER_SIZE)?;
+
+        if response[0] != MESSAGE_START || response[4] != TOKEN {
+            return Err(AvrError::ProgrammerError(format!(
+                "Malformed frame header {:02x?}",
+                &response[..FRAME_HEADER_SIZE]
+            )));
+        }
+
+        let size = (response[2] as usize) << 8 | response[3] as usize;
+        let frame_size = FRAME_HEADER_SIZE + size + 1;
+        if response.len() < frame_size {
+            response.extend(
+                self.transport
+                    .receive_response_with_size(frame_size - response.len())?,
+            );
+        }
 
-        while received.len() < expected_size {
-            let fresh_bytes = self.source.recv().map_err(|e| {
-                AvrError::Communication(format!("Failed to receive response: {:?}", e))
-            })?;
-            received.extend(fresh_bytes);
+        let frame = &response[..frame_size];
+        if checksum(frame) != 0 {
+            return Err(AvrError::ProgrammerError(format!(
+                "Checksum mismatch in frame {:02x?}",
+                frame
+            )));
         }
-        Ok(received)
+        if frame[1] != sequence {
+            return Err(AvrError::ProgrammerError(format!(
+                "Expected sequence number {}, got {}",
+                sequence, frame[1]
+            )));
+        }
+
+        Ok(frame[FRAME_HEADER_SIZE..frame_size - 1].to_vec())
     }
 
-    fn send_command_and_verify_response(
-        &self,
-        cmd: Vec<u8>,
-        expected_response: Vec<u8>,
-    ) -> AvrResult<()> {
-        self.send_command(cmd.clone())?;
-        let response = self.receive_response_with_size(expected_response.len())?;
-
-        if response == expected_response {
-            Ok(())
-        } else {
-            Err(AvrError::ProgrammerError(format!(
-                "Did not receive expected response {:?} for command {:?}",
-                expected_response, cmd
-            )))
-        }
-    }
-
-    pub(crate) fn sync(&self) -> AvrResult<()> {
-        debug!("Attempting to sync with target");
-        self.send_command_and_verify_response(
-            vec![
-                Stk500v2Message::CmndStkGetSync as u8,
-                Stk500v2Message::SyncCrcEop as u8,
-            ],
-            vec![
-                Stk500v2Message::RespStkInSync as u8,
-                Stk500v2Message::RespStkOk as u8,
-            ],
-        )?;
+    /// Send a message body and return the answer body, which has to echo the
+    /// command byte followed by STATUS_CMD_OK
+    fn command(&self, body: Vec<u8>) -> AvrResult<Vec<u8>> {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        self.transport.send_command(frame(sequence, &body))?;
+        let answer = self.receive_frame(sequence)?;
+
+        match answer.as_slice() {
+            [command, status, ..]
+                if *command == body[0] && *status == Stk500v2Message::StatusCmdOk as u8 =>
+            {
+                Ok(answer)
+            }
+            _ => Err(AvrError::ProgrammerError(format!(
+                "Command {:#04x} failed with answer {:02x?}",
+                body[0], answer
+            ))),
+        }
+    }
 
-        debug!("Synced with MCU");
+    pub(crate) fn sign_on(&self) -> AvrResult<()> {
+        debug!("Attempting to sign on to programmer");
+        let answer = self.command(vec![Stk500v2Message::CmdSignOn as u8])?;
+
+        debug!(
+            "Signed on to {}",
+            String::from_utf8_lossy(answer.get(3..).unwrap_or_default())
+        );
         Ok(())
     }
 
-    fn verify_signature(&self) -> AvrResult<()> {
-        self.send_command_and_verify_response(
-            vec![
-                Stk500v2Message::CmndStkReadSign as u8,
-                Stk500v2Message::SyncCrcEop as u8,
-            ],
+    /// Read a single parameter byte from the programmer or bootloader
+    fn get_parameter(&self, parameter: Stk500v2Message) -> AvrResult<u8> {
+        let answer = self.command(vec![
+            Stk500v2Message::CmdGetParameter as u8,
+            parameter as u8,
+        ])?;
+
+        answer.get(2).copied().ok_or_else(|| {
+            AvrError::ProgrammerError(format!(
+                "Get parameter answer {:02x?} carries no value",
+                answer
+            ))
+        })
+    }
+
+    /// Reset the target and sign on to its bootloader
+    fn connect(&self) -> AvrResult<()> {
+        self.transport.start_operation()?;
+        if self.auto_reset {
+            info_span!("reset").in_scope(|| self.reset())?;
+        }
+        self.transport.flush()?;
+        info_span!("sync").in_scope(|| self.sign_on())
+    }
+
+    fn enter_programming_mode(&self) -> AvrResult<()> {
+        self.command(
             [
-                vec![Stk500v2Message::RespStkInSync as u8],
-                self.params.device_signature.clone(),
-                vec![Stk500v2Message::RespStkOk as u8],
+                vec![Stk500v2Message::CmdEnterProgmodeIsp as u8],
+                ENTER_PROGMODE_PARAMS.to_vec(),
             ]
             .concat(),
         )?;
 
-        debug!("Verified board signature");
+        debug!("Entered programming mode!");
         Ok(())
     }
 
-    fn set_options(&self) -> AvrResult<()> {
-        self.send_command_and_verify_response(
-            vec![
-                Stk500v2Message::CmndStkSetDevice as u8,
-                0, // Device code
-                0, // Revision
-                0, // ProgType
-                0, // ParMode
-                0, // Polling
-                0, // SelfTimed
-                0, // LockBytes
-                0, // FuseBytes
-                0, // FlashPollVal1
-                0, // FlashPollVal2
-                0, // eepromPollVal1
-                0, // eepromPollVal2
-                0, // PageSizeHigh
-                0, // PageSizeLow
-                0, // eepromSizeHigh
-                0, // eepromSizeLow
-                0, // FlashSize4
-                0, // FlashSize3
-                0, // FlashSize2
-                0, // FlashSize1
-                Stk500v2Message::SyncCrcEop as u8,
-            ],
-            vec![
-                Stk500v2Message::RespStkInSync as u8,
-                Stk500v2Message::RespStkOk as u8,
-            ],
+    fn exit_programming_mode(&self) -> AvrResult<()> {
+        self.command(
+            [
+                vec![Stk500v2Message::CmdLeaveProgmodeIsp as u8],
+                LEAVE_PROGMODE_PARAMS.to_vec(),
+            ]
+            .concat(),
         )?;
-        debug!("Set options");
         Ok(())
     }
 
-    fn enter_programming_mode(&self) -> AvrResult<()> {
-        self.send_command_and_verify_response(
-            vec![
-                Stk500v2Message::CmndStkEnterProgMode as u8,
-                Stk500v2Message::SyncCrcEop as u8,
-            ],
-            vec![
-                Stk500v2Message::RespStkInSync as u8,
-                Stk500v2Message::RespStkOk as u8,
-            ],
+    /// Reset the target and bring it into programming mode
+    fn begin(&self) -> AvrResult<()> {
+        self.connect()?;
+        self.enter_programming_mode()?;
+        info_span!("verify_signature").in_scope(|| self.verify_signature())
+    }
+
+    /// Clock a 4 byte ISP read instruction out through `command` and return
+    /// the byte the target answers with
+    fn isp_read(&self, command: Stk500v2Message, instruction: [u8; 4]) -> AvrResult<u8> {
+        let answer = self.command(
+            [
+                vec![command as u8, ISP_RETURN_ADDRESS],
+                instruction.to_vec(),
+            ]
+            .concat(),
         )?;
 
-        debug!("Entered programming mode!");
-        Ok(())
+        answer.get(2).copied().ok_or_else(|| {
+            AvrError::ProgrammerError(format!("Short answer {:02x?} to ISP read", answer))
+        })
     }
 
-    fn load_address(&self, use_addr: u16) -> AvrResult<()> {
-        let high_addr: u8 = ((use_addr >> 8) & 0xFF) as u8;
-        let low_addr: u8 = (use_addr & 0xFF) as u8;
-
-        self.send_command_and_verify_response(
-            vec![
-                Stk500v2Message::CmndStkLoadAddress as u8,
-                low_addr,
-                high_addr,
-                Stk500v2Message::SyncCrcEop as u8,
-            ],
-            vec![
-                Stk500v2Message::RespStkInSync as u8,
-                Stk500v2Message::RespStkOk as u8,
-            ],
+    /// Clock a raw 4 byte SPI instruction out with CMD_SPI_MULTI and return
+    /// the byte received during the last transfer
+    fn spi_multi(&self, instruction: [u8; 4]) -> AvrResult<u8> {
+        let answer = self.command(
+            [
+                vec![Stk500v2Message::CmdSpiMulti as u8, 4, 4, 0],
+                instruction.to_vec(),
+            ]
+            .concat(),
         )?;
 
+        // Command, status, the four received bytes and a second status
+        answer.get(5).copied().ok_or_else(|| {
+            AvrError::ProgrammerError(format!("Short answer {:02x?} to SPI multi", answer))
+        })
+    }
+
+    /// Read the device signature without comparing it to the expected one
+    fn query_signature(&self) -> AvrResult<[u8; 3]> {
+        let mut signature = [0; 3];
+        for (index, byte) in signature.iter_mut().enumerate() {
+            *byte = self.isp_read(
+                Stk500v2Message::CmdReadSignatureIsp,
+                [READ_SIGNATURE_INSTRUCTION, 0x00, index as u8, 0x00],
+            )?;
+        }
+        Ok(signature)
+    }
+
+    fn verify_signature(&self) -> AvrResult<()> {
+        let signature = self.query_signature()?;
+        if signature[..] != self.params.device_signature[..] {
+            return Err(AvrError::ProgrammerError(format!(
+                "Device signature {:02x?} does not match expected {:02x?}",
+                signature, self.params.device_signature
+            )));
+        }
+
+        debug!("Verified board signature");
+        Ok(())
+    }
+
+    fn query_fuses(&self) -> AvrResult<Fuses> {
+        let low = self.isp_read(Stk500v2Message::CmdReadFuseIsp, READ_LOW_FUSE)?;
+        let high = self.isp_read(Stk500v2Message::CmdReadFuseIsp, READ_HIGH_FUSE)?;
+        let extended = if Fuses::has_extended(&self.params.device_signature) {
+            Some(self.isp_read(Stk500v2Message::CmdReadFuseIsp, READ_EXTENDED_FUSE)?)
+        } else {
+            None
+        };
+
+        Ok(Fuses {
+            low,
+            high,
+            extended,
+        })
+    }
+
+    /// Write the fuses and read them back to make sure they took
+    fn store_fuses(&self, fuses: &Fuses) -> AvrResult<()> {
+        let write = |instruction: [u8; 3], value: u8| {
+            self.command(vec![
+                Stk500v2Message::CmdProgramFuseIsp as u8,
+                instruction[0],
+                instruction[1],
+                instruction[2],
+                value,
+            ])
+        };
+
+        write(WRITE_LOW_FUSE, fuses.low)?;
+        write(WRITE_HIGH_FUSE, fuses.high)?;
+        if let Some(extended) = fuses.extended
+            && Fuses::has_extended(&self.params.device_signature)
+        {
+            write(WRITE_EXTENDED_FUSE, extended)?;
+        }
+
+        let written = self.query_fuses()?;
+        let matches = written.low == fuses.low
+            && written.high == fuses.high
+            && (fuses.extended.is_none() || written.extended == fuses.extended);
+        if !matches {
+            return Err(AvrError::ProgrammerError(format!(
+                "Fuses read back as {:?} after writing {:?}",
+                written, fuses
+            )));
+        }
+
+        debug!("Wrote fuses {:?}", fuses);
+        Ok(())
+    }
+
+    /// Write the lock bits and read them back to make sure they took
+    fn store_lock_bits(&self, bits: u8) -> AvrResult<()> {
+        self.command(vec![
+            Stk500v2Message::CmdProgramLockIsp as u8,
+            WRITE_LOCK_BITS[0],
+            WRITE_LOCK_BITS[1],
+            WRITE_LOCK_BITS[2],
+            bits,
+        ])?;
+
+        let written = self.isp_read(Stk500v2Message::CmdReadLockIsp, READ_LOCK_BITS)?;
+        if written & LOCK_BITS_MASK != bits & LOCK_BITS_MASK {
+            return Err(AvrError::ProgrammerError(format!(
+                "Lock bits read back as {:#04x} after writing {:#04x}",
+                written, bits
+            )));
+        }
+
+        debug!("Wrote lock bits {:#04x}", bits);
         Ok(())
     }
 
-    fn load_page(&self, write_bytes: &[u8]) -> AvrResult<()> {
-        let data_len = write_bytes.len() as u16;
-        let bytes_high = ((data_len >> 8) & 0xFF) as u8;
-        let bytes_low = (data_len & 0xFF) as u8;
+    /// Sign off from programming mode on the way out of a failed check. `e`
+    /// is returned as is, a failure to leave is only logged
+    fn abandon_programming_mode(&self, e: AvrError) -> AvrError {
+        if let Err(leave) = self.exit_programming_mode() {
+            warn!("Failed to leave programming mode: {}", leave);
+        }
+        e
+    }
 
-        self.send_command_and_verify_response(
+    /// Refuse to go any further if the chip doesn't match the expected
+    /// signature, fuses and lock bits
+    fn check_preflight(&self, preflight: &Preflight) -> AvrResult<()> {
+        let signature = self.query_signature()?;
+        let fuses = self.query_fuses()?;
+        let lock = self.isp_read(Stk500v2Message::CmdReadLockIsp, READ_LOCK_BITS)?;
+        debug!(
+            "Read signature {:02x?}, fuses {:?}, lock bits {:#04x}",
+            signature, fuses, lock
+        );
+
+        preflight
+            .check(&signature, &fuses, lock)
+            .map_err(|e| self.abandon_programming_mode(e))
+    }
+
+    fn chip_erase(&self) -> AvrResult<()> {
+        self.command(
             [
                 vec![
-                    Stk500v2Message::CmndStkProgPage as u8,
-                    bytes_high,
-                    bytes_low,
-                    0x46,
+                    Stk500v2Message::CmdChipEraseIsp as u8,
+                    CHIP_ERASE_DELAY_MS,
+                    0, // wait out the delay instead of polling RDY/BSY
                 ],
-                write_bytes.to_vec(),
-                vec![Stk500v2Message::SyncCrcEop as u8],
+                CHIP_ERASE.to_vec(),
             ]
             .concat(),
-            vec![
-                Stk500v2Message::RespStkInSync as u8,
-                Stk500v2Message::RespStkOk as u8,
-            ],
-        )?;
+        )
+        .map_err(|e| {
+            AvrError::ProgrammerError(format!("Device did not acknowledge chip erase: {}", e))
+        })?;
 
+        debug!("Chip erased");
         Ok(())
     }
 
-    fn verify_page(&self, verify_bytes: &[u8]) -> AvrResult<()> {
-        let data_len = verify_bytes.len() as u16;
-        let size = if data_len > self.params.page_size {
-            self.params.page_size
-        } else {
-            data_len
-        };
+    /// Load a flash byte address. Flash is word addressed, unlike EEPROM
+    fn load_flash_address(&self, byte_address: usize) -> AvrResult<()> {
+        let mut address = (byte_address >> 1) as u32;
+        if self.flash_size() > EXTENDED_ADDRESS_FLASH_SIZE {
+            address |= LOAD_EXTENDED_ADDRESS_BIT;
+        }
+        self.load_address(address)
+    }
+
+    /// Load an EEPROM byte address. EEPROM is byte addressed, but the top
+    /// bit of the address would be taken for the extended address flag
+    fn load_eeprom_address(&self, byte_address: usize) -> AvrResult<()> {
+        match u32::try_from(byte_address) {
+            Ok(address) if address & LOAD_EXTENDED_ADDRESS_BIT == 0 => self.load_address(address),
+            _ => Err(AvrError::ConfigurationError(format!(
+                "EEPROM address {:#x} is out of the reach of STK500v2",
+                byte_address
+            ))),
+        }
+    }
 
-        let byte_high = ((size >> 8) & 0xFF) as u8;
-        let byte_low = (size & 0xFF) as u8;
-
-        self.send_command_and_verify_response(
-            vec![
-                Stk500v2Message::CmndStkReadPage as u8,
-                byte_high,
-                byte_low,
-                0x46,
-                Stk500v2Message::SyncCrcEop as u8,
-            ],
+    fn load_address(&self, address: u32) -> AvrResult<()> {
+        self.command(
             [
-                vec![Stk500v2Message::RespStkInSync as u8],
-                verify_bytes.to_vec(),
-                vec![Stk500v2Message::RespStkOk as u8],
+                vec![Stk500v2Message::CmdLoadAddress as u8],
+                address.to_be_bytes().to_vec(),
             ]
             .concat(),
         )?;
-
         Ok(())
     }
 
-    fn exit_programming_mode(&self) -> AvrResult<()> {
-        self.send_command_and_verify_response(
-            vec![
-                Stk500v2Message::CmndStkLeaveProgMode as u8,
-                Stk500v2Message::SyncCrcEop as u8,
-            ],
-            vec![
-                Stk500v2Message::RespStkInSync as u8,
-                Stk500v2Message::RespStkOk as u8,
-            ],
+    /// Write `write_bytes` at the last loaded address
+    fn load_page(&self, write_bytes: &[u8], memory: MemoryType) -> AvrResult<()> {
+        let (command, params) = match memory {
+            MemoryType::Flash => (Stk500v2Message::CmdProgramFlashIsp, PROGRAM_FLASH_PARAMS),
+            MemoryType::Eeprom => (Stk500v2Message::CmdProgramEepromIsp, PROGRAM_EEPROM_PARAMS),
+            MemoryType::Custom(byte) => return Err(unsupported_memory(byte)),
+        };
+        let size = write_bytes.len() as u16;
+
+        self.command(
+            [
+                vec![command as u8, (size >> 8) as u8, (size & 0xFF) as u8],
+                params.to_vec(),
+                write_bytes.to_vec(),
+            ]
+            .concat(),
         )?;
         Ok(())
     }
 
-    fn upload(&self, bin: Vec<u8>, enable_progress_bar: bool) -> AvrResult<()> {
-        let mut pb: Option<ProgressBar> = None;
-        let total_steps = bin.len().div_ceil(self.params.page_size as usize);
-        let mut current_step = 0;
-        if enable_progress_bar {
-            pb = Some(create_progress_bar(total_steps as u64, "Programming.."));
+    /// Read `size` bytes of memory starting at the last loaded address,
+    /// stripping the command and status bytes from the answer
+    fn read_page(&self, size: u16, memory: MemoryType) -> AvrResult<Vec<u8>> {
+        let (command, instruction) = match memory {
+            MemoryType::Flash => (Stk500v2Message::CmdReadFlashIsp, READ_FLASH_INSTRUCTION),
+            MemoryType::Eeprom => (Stk500v2Message::CmdReadEepromIsp, READ_EEPROM_INSTRUCTION),
+            MemoryType::Custom(byte) => return Err(unsupported_memory(byte)),
+        };
+
+        let answer = self.command(vec![
+            command as u8,
+            (size >> 8) as u8,
+            (size & 0xFF) as u8,
+            instruction,
+        ])?;
+
+        // Command, status, data and a trailing status byte
+        match answer.get(2..2 + size as usize) {
+            Some(data) if answer.len() == size as usize + 3 => Ok(data.to_vec()),
+            _ => Err(AvrError::ProgrammerError(format!(
+                "Expected {} bytes in answer to read page, got {}",
+                size,
+                answer.len().saturating_sub(3)
+            ))),
         }
+    }
 
-        debug!("Started programming");
-        let page_size = self.params.page_size;
-        let mut page_addr: u16 = 0;
-        let mut use_addr: u16;
+    /// Write `bin` from flash address `start` on. `bin` is laid out from
+    /// address 0
+    fn upload(&self, bin: Vec<u8>, start: usize, enable_progress_bar: bool) -> AvrResult<()> {
+        let page_size = self.params.page_size as usize;
+        let total_steps = (bin.len() - start).div_ceil(page_size);
+        let pb = progress_bar(enable_progress_bar, total_steps as u64, "Programming..");
+        let mut reporter =
+            ProgressReporter::new(self.progress.as_ref(), Phase::Programming, total_steps);
 
-        while page_addr < bin.len() as u16 {
-            use_addr = page_addr >> 1;
+        let _span = info_span!("upload", pages = total_steps, bytes = bin.len()).entered();
+        debug!("Started programming");
+        self.bytes_programmed.store(0, Ordering::Relaxed);
+        for (index, chunk) in bin[start..].chunks(page_size).enumerate() {
+            let address = start + index * page_size;
+            // Always write whole pages, padding the last one with erased bytes
+            let mut page = chunk.to_vec();
+            page.resize(page_size, 0xFF);
 
-            self.load_address(use_addr)?;
-            let end = if bin.len() as u16 > (page_addr + page_size) {
-                page_addr + page_size
+            if self.dry_run {
+                debug!(
+                    "Dry run, not writing {} bytes at {:#x}",
+                    chunk.len(),
+                    address
+                );
             } else {
-                bin.len() as u16 - 1
-            };
-            let slice = &bin[(page_addr as usize)..(end as usize)];
-            if slice.is_empty() {
-                break;
+                self.load_flash_address(address)?;
+                self.load_page(&page, MemoryType::Flash)?;
             }
-
-            self.load_page(slice)?;
-            page_addr += slice.len() as u16;
+            self.bytes_programmed
+                .fetch_add(chunk.len(), Ordering::Relaxed);
+            reporter.page_done(chunk.len());
 
             if let Some(progress_bar) = &pb {
-                progress_bar.set_position(current_step);
-                current_step += 1;
+                progress_bar.inc(1);
             }
         }
         if let Some(progress_bar) = &pb {
@@ -376,40 +629,33 @@ impl Stk500v2 {
         Ok(())
     }
 
-    fn verify(&self, bin: Vec<u8>, enable_progress_bar: bool) -> AvrResult<()> {
-        let mut pb: Option<ProgressBar> = None;
-        let total_steps = bin.len().div_ceil(self.params.page_size as usize);
-        let mut current_step = 0;
-        if enable_progress_bar {
-            pb = Some(create_progress_bar(total_steps as u64, "Verifying..."));
-        }
-
-        debug!("Started verifying");
-        let mut page_addr: u16 = 0;
-        let mut use_addr;
-        let page_size = self.params.page_size;
+    fn verify(&self, bin: Vec<u8>, start: usize, enable_progress_bar: bool) -> AvrResult<()> {
+        let page_size = self.params.page_size as usize;
+        let span = verify_span(bin.len(), start, page_size, self.verify_range.as_ref())?;
+        let total_steps = span.len().div_ceil(page_size);
+        let pb = progress_bar(enable_progress_bar, total_steps as u64, "Verifying...");
+        let mut reporter =
+            ProgressReporter::new(self.progress.as_ref(), Phase::Verifying, total_steps);
 
-        while page_addr < bin.len() as u16 {
-            use_addr = page_addr >> 1;
-            self.load_address(use_addr)?;
-
-            let end = if bin.len() as u16 > (page_addr + page_size) {
-                page_addr + page_size
-            } else {
-                bin.len() as u16 - 1
-            };
+        let _span = info_span!("verify", pages = total_steps, bytes = span.len()).entered();
+        debug!("Started verifying {:#x}..{:#x}", span.start, span.end);
+        for page_address in span.clone().step_by(page_size) {
+            let chunk = &bin[page_address..(page_address + page_size).min(span.end)];
+            self.load_flash_address(page_address)?;
+            let read_back = self.read_page(chunk.len() as u16, MemoryType::Flash)?;
 
-            let slice = &bin[(page_addr as usize)..(end as usize)];
-            if slice.is_empty() {
-                break;
+            if let Some(offset) = chunk.iter().zip(&read_back).position(|(a, b)| a != b) {
+                return Err(AvrError::ProgrammerError(format!(
+                    "Verification failed at address {:#x}: wrote {:#04x}, read {:#04x}",
+                    page_address + offset,
+                    chunk[offset],
+                    read_back[offset]
+                )));
             }
-            self.verify_page(slice)?;
-
-            page_addr += slice.len() as u16;
+            reporter.page_done(chunk.len());
 
             if let Some(progress_bar) = &pb {
-                progress_bar.set_position(current_step);
-                current_step += 1;
+                progress_bar.inc(1);
             }
         }
         if let Some(progress_bar) = &pb {
@@ -417,50 +663,454 @@ impl Stk500v2 {
         }
         Ok(())
     }
-}
 
-impl Drop for Stk500v2 {
-    fn drop(&mut self) {
-        self.shutdown.store(true, Ordering::Relaxed);
-        for thread in self.thread_handles.drain(..) {
-            thread
-                .join()
-                .unwrap_or_else(|e| eprintln!("Thread join failed: {:?}", e));
+    fn read(&self, num_bytes: usize, enable_progress_bar: bool) -> AvrResult<Vec<u8>> {
+        let page_size = self.params.page_size as usize;
+        let pb = progress_bar(
+            enable_progress_bar,
+            num_bytes.div_ceil(page_size) as u64,
+            "Reading...",
+        );
+
+        debug!("Started reading flash");
+        let mut flash = Vec::with_capacity(num_bytes);
+        while flash.len() < num_bytes {
+            self.load_flash_address(flash.len())?;
+
+            let size = (num_bytes - flash.len()).min(page_size);
+            flash.extend(self.read_page(size as u16, MemoryType::Flash)?);
+
+            if let Some(progress_bar) = &pb {
+                progress_bar.inc(1);
+            }
+        }
+        if let Some(progress_bar) = &pb {
+            progress_bar.finish_with_message("Read.");
         }
+
+        Ok(flash)
     }
 }
 
+fn unsupported_memory(byte: u8) -> AvrError {
+    AvrError::ProgrammerError(format!(
+        "Memory type {:#04x} is not supported over STK500v2",
+        byte
+    ))
+}
+
 impl ProgrammerTrait for Stk500v2 {
-    fn program_firmware(
+    fn program_firmware_at(
         &self,
         firmware: Vec<u8>,
+        start: usize,
         verify: bool,
         enable_progress_bar: bool,
-    ) -> AvrResult<()> {
-        self.reset()?;
-        self.sync()?;
+    ) -> AvrResult<ProgramReport> {
+        let _span = info_span!("program", bytes = firmware.len(), start).entered();
+        let started = Instant::now();
+        let page_size = self.params.page_size as usize;
 
-        self.verify_signature()?;
-        self.set_options()?;
-        self.enter_programming_mode()?;
+        check_start_address(start, page_size, self.flash_size())?;
+        // Laid out from address 0, the pages below `start` are skipped
+        let image = [vec![0xFF; start], firmware].concat();
+        check_image_fits(image.len(), self.flash_size())?;
 
-        self.upload(firmware.clone(), enable_progress_bar)?;
+        // Refuse a bad verify range before anything is written
+        let verify_bytes = if verify {
+            verify_span(image.len(), start, page_size, self.verify_range.as_ref())?.len()
+        } else {
+            0
+        };
+        if !self.dry_run {
+            self.begin()?;
+
+            if let Some(preflight) = &self.preflight {
+                self.check_preflight(preflight)?;
+            }
+        }
+
+        let upload_started = Instant::now();
+        self.upload(image.clone(), start, enable_progress_bar)?;
+        let mut report = ProgramReport {
+            bytes_written: self.bytes_programmed(),
+            pages_written: (image.len() - start).div_ceil(page_size),
+            upload_duration: upload_started.elapsed(),
+            ..ProgramReport::default()
+        };
+        if self.dry_run {
+            report.duration = started.elapsed();
+            return Ok(report);
+        }
 
         if verify {
-            self.verify(firmware, enable_progress_bar)?;
+            let verify_started = Instant::now();
+            self.verify(image, start, enable_progress_bar)?;
+            report.verify_bytes = verify_bytes;
+            report.verify_duration = verify_started.elapsed();
         }
         self.exit_programming_mode()?;
-        println!("Done! ✨ 🍰 ✨");
+        info!("Done! ✨ 🍰 ✨");
+
+        report.duration = started.elapsed();
+        Ok(report)
+    }
+
+    fn verify_firmware(&self, firmware: Vec<u8>, enable_progress_bar: bool) -> AvrResult<()> {
+        check_image_fits(firmware.len(), self.flash_size())?;
+        self.begin()?;
+
+        self.verify(firmware, 0, enable_progress_bar)?;
+        self.exit_programming_mode()
+    }
+
+    fn read_flash(&self, num_bytes: usize, enable_progress_bar: bool) -> AvrResult<Vec<u8>> {
+        self.begin()?;
 
+        let flash = self.read(num_bytes, enable_progress_bar)?;
+        self.exit_programming_mode()?;
+
+        Ok(flash)
+    }
+
+    fn program_eeprom(&self, data: Vec<u8>, verify: bool) -> AvrResult<()> {
+        check_eeprom_range(0, data.len(), self.params.eeprom_size)?;
+        self.begin()?;
+
+        debug!("Started programming EEPROM");
+        let page_size = self.params.eeprom_page_size.max(1) as usize;
+        for (index, page) in data.chunks(page_size).enumerate() {
+            // EEPROM is byte addressed, unlike flash
+            self.load_eeprom_address(index * page_size)?;
+            self.load_page(page, MemoryType::Eeprom)?;
+        }
+
+        if verify {
+            debug!("Started verifying EEPROM");
+            for (index, page) in data.chunks(page_size).enumerate() {
+                self.load_eeprom_address(index * page_size)?;
+                if self.read_page(page.len() as u16, MemoryType::Eeprom)? != page {
+                    return Err(AvrError::ProgrammerError(format!(
+                        "EEPROM verification failed in page at {:#x}",
+                        index * page_size
+                    )));
+                }
+            }
+        }
+
+        self.exit_programming_mode()
+    }
+
+    fn read_eeprom(&self, num_bytes: usize) -> AvrResult<Vec<u8>> {
+        check_eeprom_range(0, num_bytes, self.params.eeprom_size)?;
+        self.begin()?;
+
+        let page_size = self.params.eeprom_page_size.max(1) as usize;
+        let mut eeprom = Vec::with_capacity(num_bytes);
+        for page_start in (0..num_bytes).step_by(page_size) {
+            let len = page_size.min(num_bytes - page_start);
+            self.load_eeprom_address(page_start)?;
+            eeprom.extend(self.read_page(len as u16, MemoryType::Eeprom)?);
+        }
+        self.exit_programming_mode()?;
+
+        Ok(eeprom)
+    }
+
+    fn update_eeprom(&self, address: usize, bytes: &[u8]) -> AvrResult<()> {
+        check_eeprom_range(address, bytes.len(), self.params.eeprom_size)?;
+        self.begin()?;
+
+        // The programmer only answers PROGRAM_EEPROM_ISP once the page is
+        // written, so there's nothing further to wait for between pages
+        let page_size = self.params.eeprom_page_size.max(1) as usize;
+        update_eeprom_pages(
+            address,
+            bytes,
+            page_size,
+            |page_start| {
+                self.load_eeprom_address(page_start)?;
+                self.read_page(page_size as u16, MemoryType::Eeprom)
+            },
+            |page_start, page| {
+                self.load_eeprom_address(page_start)?;
+                self.load_page(page, MemoryType::Eeprom)
+            },
+        )?;
+
+        self.exit_programming_mode()
+    }
+
+    fn erase_chip(&self) -> AvrResult<()> {
+        self.begin()?;
+        self.chip_erase()?;
+        self.exit_programming_mode()
+    }
+
+    fn read_signature(&self) -> AvrResult<[u8; 3]> {
+        self.connect()?;
+        self.enter_programming_mode()?;
+
+        let signature = self.query_signature()?;
+        self.exit_programming_mode()?;
+
+        Ok(signature)
+    }
+
+    fn bootloader_version(&self) -> AvrResult<(u8, u8)> {
+        self.connect()?;
+
+        let major = self.get_parameter(Stk500v2Message::ParamSwMajor)?;
+        let minor = self.get_parameter(Stk500v2Message::ParamSwMinor)?;
+        Ok((major, minor))
+    }
+
+    fn read_fuses(&self) -> AvrResult<Fuses> {
+        self.begin()?;
+
+        let fuses = self.query_fuses()?;
+        self.exit_programming_mode()?;
+
+        Ok(fuses)
+    }
+
+    fn write_fuses(&self, fuses: Fuses, allow_dangerous: bool) -> AvrResult<()> {
+        if !allow_dangerous {
+            fuses.check_safe_to_write(&self.params.device_signature)?;
+        }
+
+        self.begin()?;
+        let result = self.store_fuses(&fuses);
+        self.exit_programming_mode()?;
+
+        result
+    }
+
+    fn read_lock_bits(&self) -> AvrResult<u8> {
+        self.begin()?;
+
+        let lock = self.isp_read(Stk500v2Message::CmdReadLockIsp, READ_LOCK_BITS)?;
+        self.exit_programming_mode()?;
+
+        Ok(lock)
+    }
+
+    fn spi_instruction(&self, instruction: [u8; 4]) -> AvrResult<u8> {
+        self.begin()?;
+
+        let value = self.spi_multi(instruction)?;
+        self.exit_programming_mode()?;
+
+        Ok(value)
+    }
+
+    fn write_lock_bits(&self, bits: u8) -> AvrResult<()> {
+        self.begin()?;
+        let result = self.store_lock_bits(bits);
+        self.exit_programming_mode()?;
+
+        result
+    }
+
+    fn set_preflight(&mut self, preflight: Preflight) -> AvrResult<()> {
+        self.preflight = Some(preflight);
         Ok(())
     }
 
+    fn set_progress_callback(&mut self, callback: ProgressCallback) {
+        self.progress = Some(callback);
+    }
+
+    fn set_verify_range(&mut self, range: Option<Range<usize>>) {
+        self.verify_range = range;
+    }
+
+    fn set_dry_run(&mut self, enable: bool) {
+        self.dry_run = enable;
+    }
+
+    fn set_deadline(&mut self, deadline: Duration) {
+        self.transport.set_operation_timeout(Some(deadline));
+    }
+
+    fn set_auto_reset(&mut self, enable: bool) {
+        self.auto_reset = enable;
+    }
+
+    fn bytes_programmed(&self) -> usize {
+        self.bytes_programmed.load(Ordering::Relaxed)
+    }
+
+    fn set_timeout_ms(&self, timeout_ms: u64) -> AvrResult<()> {
+        self.transport
+            .set_timeout(Duration::from_millis(timeout_ms))
+    }
+
+    fn flash_size(&self) -> usize {
+        self.params.page_size as usize * self.params.num_pages as usize
+    }
+
     fn reset(&self) -> AvrResult<()> {
-        self.device_interface
-            .lock()
-            .map_err(|_| AvrError::Communication("Failed to lock device_interface".to_string()))?
-            .reset()
-            .map_err(|e| AvrError::Communication(format!("Failed to reset: {:?}", e)))?;
+        self.transport.reset()
+    }
+
+    fn close(&mut self) {
+        self.transport.close();
+    }
+
+    fn reopen(&mut self) -> AvrResult<()> {
+        if !self.owns_port {
+            return Err(not_reopenable());
+        }
+
+        let device_interface = Self::open(&self.params)?;
+        self.transport.reopen(Box::new(device_interface));
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::mock::MockDeviceInterface;
+
+    const OK: u8 = Stk500v2Message::StatusCmdOk as u8;
+    const SIGNATURE: [u8; 3] = [0x1e, 0x98, 0x01];
+
+    fn params() -> Stk500v2Params {
+        Stk500v2Params {
+            port: String::from("mock"),
+            baud: 115200,
+            device_signature: SIGNATURE.to_vec(),
+            page_size: 8,
+            num_pages: 4,
+            eeprom_page_size: 8,
+            reset_line: ResetLine::Both,
+            ..Default::default()
+        }
+    }
+
+    /// A framed request and the framed answer to it
+    fn exchange(sequence: u8, request: Vec<u8>, answer: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
+        (frame(sequence, &request), frame(sequence, &answer))
+    }
+
+    /// Script for signing on, entering programming mode and checking the
+    /// signature, using sequence numbers 0 to 4
+    fn handshake() -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut script = vec![
+            exchange(
+                0,
+                vec![Stk500v2Message::CmdSignOn as u8],
+                [vec![0x01, OK, 8], b"AVRISP_2".to_vec()].concat(),
+            ),
+            exchange(
+                1,
+                [vec![0x10], ENTER_PROGMODE_PARAMS.to_vec()].concat(),
+                vec![0x10, OK],
+            ),
+        ];
+        for (index, byte) in SIGNATURE.iter().enumerate() {
+            script.push(exchange(
+                2 + index as u8,
+                vec![0x1B, 4, 0x30, 0x00, index as u8, 0x00],
+                vec![0x1B, OK, *byte, OK],
+            ));
+        }
+        script
+    }
+
+    #[test]
+    fn test_frame_layout_and_checksum() {
+        let framed = frame(7, &[0x01]);
+        assert_eq!(framed, vec![0x1B, 0x07, 0x00, 0x01, 0x0E, 0x01, 0x12]);
+        assert_eq!(checksum(&framed), 0);
+    }
+
+    #[test]
+    fn test_program_and_verify_pads_last_page() {
+        let firmware = vec![0x0c, 0x94, 0x5c, 0x00, 0x0c, 0x94, 0x6e, 0x00, 0x01, 0x02];
+        let second_page = [vec![0x01, 0x02], vec![0xFF; 6]].concat();
+
+        let mut script = handshake();
+        script.extend([
+            exchange(5, vec![0x06, 0, 0, 0, 0], vec![0x06, OK]),
+            exchange(
+                6,
+                [
+                    vec![0x13, 0, 8],
+                    PROGRAM_FLASH_PARAMS.to_vec(),
+                    firmware[..8].to_vec(),
+                ]
+                .concat(),
+                vec![0x13, OK],
+            ),
+            exchange(7, vec![0x06, 0, 0, 0, 4], vec![0x06, OK]),
+            exchange(
+                8,
+                [vec![0x13, 0, 8], PROGRAM_FLASH_PARAMS.to_vec(), second_page].concat(),
+                vec![0x13, OK],
+            ),
+            exchange(9, vec![0x06, 0, 0, 0, 0], vec![0x06, OK]),
+            exchange(
+                10,
+                vec![0x14, 0, 8, 0x20],
+                [vec![0x14, OK], firmware[..8].to_vec(), vec![OK]].concat(),
+            ),
+            exchange(11, vec![0x06, 0, 0, 0, 4], vec![0x06, OK]),
+            exchange(12, vec![0x14, 0, 2, 0x20], vec![0x14, OK, 0x01, 0x02, OK]),
+            exchange(13, vec![0x11, 1, 1], vec![0x11, OK]),
+        ]);
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v2::with_interface(params(), Box::new(mock));
+
+        programmer
+            .program_firmware_at(firmware, 0, true, false)
+            .unwrap();
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_spi_instruction_uses_spi_multi() {
+        let mut script = handshake();
+        script.extend([
+            exchange(
+                5,
+                vec![0x1D, 4, 4, 0, 0x38, 0x00, 0x00, 0x00],
+                vec![0x1D, OK, 0x00, 0x38, 0x00, 0x9a, OK],
+            ),
+            exchange(6, vec![0x11, 1, 1], vec![0x11, OK]),
+        ]);
+
+        let (mock, log) = MockDeviceInterface::new(script);
+        let programmer = Stk500v2::with_interface(params(), Box::new(mock));
+
+        assert_eq!(
+            programmer
+                .spi_instruction([0x38, 0x00, 0x00, 0x00])
+                .unwrap(),
+            0x9a
+        );
+        assert!(log.lock().unwrap().unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_corrupted_frame_is_rejected() {
+        let (request, mut answer) = exchange(
+            0,
+            vec![Stk500v2Message::CmdSignOn as u8],
+            [vec![0x01, OK, 8], b"AVRISP_2".to_vec()].concat(),
+        );
+        let last = answer.len() - 1;
+        answer[last] ^= 0x01;
+
+        let (mock, _log) = MockDeviceInterface::new(vec![(request, answer)]);
+        let programmer = Stk500v2::with_interface(params(), Box::new(mock));
+
+        match programmer.sign_on() {
+            Err(AvrError::ProgrammerError(message)) => assert!(message.contains("Checksum")),
+            result => panic!("Expected a checksum error, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,852 @@
+use crate::constants::{
+    COMMAND_CHANNEL_CAPACITY, DEFAULT_RESPONSE_TIMEOUT_MS, RESPONSE_CHANNEL_CAPACITY,
+    SERIAL_TIMEOUT_MS, THREAD_ERROR_POLL_MS, TRANSPORT_IDLE_SPINS, TRANSPORT_THREAD_SLEEP_MICROS,
+};
+use crate::error::{AvrError, AvrResult};
+use crate::interface::DeviceInterface;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, mpsc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span, error, warn};
+
+type SharedDevice = Arc<Mutex<Box<dyn DeviceInterface + Send>>>;
+
+/// First error either thread ran into, handed to the next caller
+type ThreadError = Arc<Mutex<Option<AvrError>>>;
+
+/// Moves bytes between a protocol and its device interface using a sender
+/// and a receiver thread. Both channels are bounded; when the protocol stops
+/// consuming responses, the receiver thread stops reading from the device
+/// instead of queueing stale chunks without limit. When the interface can
+/// clone a sending handle, the sender thread writes through it and never
+/// waits for a read in progress
+///
+/// An inline transport has no threads or channels, and sends and reads on
+/// the calling thread instead
+pub(crate) struct Transport {
+    device_interface: SharedDevice,
+
+    /// Callers other than the receiver thread waiting for the device. A read
+    /// holds the device for up to a whole serial timeout, so the receiver
+    /// thread stays off the lock while anyone else wants it
+    waiting: Arc<AtomicUsize>,
+
+    /// None for an inline transport, or once closed
+    threads: Option<TransportThreads>,
+    inline: bool,
+
+    /// How long a whole operation may take, and when the one in progress
+    /// runs out. Without one, each response gets DEFAULT_RESPONSE_TIMEOUT_MS
+    operation_timeout: Option<Duration>,
+    deadline: Mutex<Option<Instant>>,
+}
+
+/// The sender and receiver threads and the channels to them. Dropping this
+/// stops and joins both threads
+struct TransportThreads {
+    // Behind a mutex so the transport, and the protocols owning it, are Sync
+    source: Mutex<mpsc::Receiver<Vec<u8>>>,
+    sink: mpsc::SyncSender<Vec<u8>>,
+    error: ThreadError,
+
+    shutdown: Arc<AtomicBool>,
+    thread_handles: Vec<JoinHandle<()>>,
+}
+
+impl Transport {
+    pub fn new(device_interface: Box<dyn DeviceInterface + Send>) -> Self {
+        let mut own_sender = device_interface.try_clone_sender();
+        let (sink, sender_rx) = mpsc::sync_channel::<Vec<u8>>(COMMAND_CHANNEL_CAPACITY);
+        let (receiver_tx, source) = mpsc::sync_channel(RESPONSE_CHANNEL_CAPACITY);
+
+        let device_interface = Arc::new(Mutex::new(device_interface));
+        let transport_sender = Arc::clone(&device_interface);
+        let transport_receiver = Arc::clone(&device_interface);
+
+        let waiting = Arc::new(AtomicUsize::new(0));
+        let sender_waiting = Arc::clone(&waiting);
+        let receiver_waiting = Arc::clone(&waiting);
+
+        let error: ThreadError = Arc::new(Mutex::new(None));
+        let sender_error = Arc::clone(&error);
+        let receiver_error = Arc::clone(&error);
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let shutdown1 = Arc::clone(&shutdown);
+        let shutdown2 = Arc::clone(&shutdown);
+
+        // Run the threads under the subscriber of whoever created the transport
+        let dispatch1 = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+        let dispatch2 = dispatch1.clone();
+
+        // Sender thread
+        let send_handle = std::thread::spawn(move || {
+            let _dispatch = tracing::dispatcher::set_default(&dispatch1);
+            let _span = debug_span!("transport", thread = "sender").entered();
+            debug!("Transport thread started");
+
+            loop {
+                if shutdown1.load(Ordering::Relaxed) {
+                    debug!("Shutdown signal received");
+                    break;
+                }
+
+                // Blocks until a command arrives, no need to sleep in between
+                let recv_result =
+                    sender_rx.recv_timeout(std::time::Duration::from_millis(SERIAL_TIMEOUT_MS));
+                match recv_result {
+                    Ok(command) => {
+                        debug!(bytes = command.len(), "Sending command");
+                        let result = match own_sender.as_mut() {
+                            Some(sender) => sender.send(command),
+                            None => lock_device(&transport_sender, &sender_waiting)
+                                .and_then(|mut device_interface| device_interface.send(command)),
+                        };
+                        if let Err(e) = result {
+                            error!("Error sending command: {:?}", e);
+                            record_error(&sender_error, e);
+                        }
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        // Ignore timeout, continue running
+                    }
+                    Err(e) => {
+                        warn!("Sender thread terminated. {e}");
+                        break;
+                    }
+                }
+            }
+            debug!("Transport thread exited");
+        });
+
+        // Receiver thread
+        let receive_handle = std::thread::spawn(move || {
+            let _dispatch = tracing::dispatcher::set_default(&dispatch2);
+            let _span = debug_span!("transport", thread = "receiver").entered();
+            debug!("Transport thread started");
+
+            // Chunk that could not be forwarded because the channel was full
+            let mut pending: Option<Vec<u8>> = None;
+            // Empty reads in a row. The thread only starts sleeping between
+            // reads once the device has been quiet for a while, so an answer
+            // to the command just sent is picked up without delay
+            let mut idle_reads = 0;
+
+            loop {
+                if shutdown2.load(Ordering::Relaxed) {
+                    debug!("Shutdown signal received");
+                    break;
+                }
+
+                if idle_reads >= TRANSPORT_IDLE_SPINS || pending.is_some() {
+                    std::thread::sleep(std::time::Duration::from_micros(
+                        TRANSPORT_THREAD_SLEEP_MICROS,
+                    ));
+                }
+
+                let response = match pending.take() {
+                    Some(response) => response,
+                    None => {
+                        // Let the sender, reset or set_timeout in first
+                        if receiver_waiting.load(Ordering::SeqCst) > 0 {
+                            std::thread::yield_now();
+                            continue;
+                        }
+
+                        // Held for this read only, so shutdown is seen within
+                        // one serial timeout
+                        let received = transport_receiver
+                            .lock()
+                            .map_err(|_| {
+                                AvrError::Communication(
+                                    "Failed to lock device_interface (receiver thread)".to_string(),
+                                )
+                            })
+                            .and_then(|mut device_interface| device_interface.receive());
+                        match received {
+                            Ok(response) => response,
+                            Err(e) => {
+                                error!("Error receiving response: {:?}", e);
+                                record_error(&receiver_error, e);
+                                break;
+                            }
+                        }
+                    }
+                };
+
+                // Nothing arrived before the read timed out
+                if response.is_empty() {
+                    idle_reads += 1;
+                    std::thread::yield_now();
+                    continue;
+                }
+                idle_reads = 0;
+                debug!(bytes = response.len(), "Received response");
+
+                match receiver_tx.try_send(response) {
+                    Ok(()) => {}
+                    Err(mpsc::TrySendError::Full(response)) => {
+                        // Apply backpressure: hold on to this chunk and stop
+                        // reading from the device until there is room again
+                        pending = Some(response);
+                    }
+                    Err(mpsc::TrySendError::Disconnected(_)) => {
+                        warn!("Receiver thread terminated. Response channel closed");
+                        break;
+                    }
+                }
+            }
+            debug!("Transport thread exited");
+        });
+
+        Transport {
+            device_interface,
+            waiting,
+            threads: Some(TransportThreads {
+                source: Mutex::new(source),
+                sink,
+                error,
+                shutdown,
+                thread_handles: vec![send_handle, receive_handle],
+            }),
+            inline: false,
+            operation_timeout: None,
+            deadline: Mutex::new(None),
+        }
+    }
+
+    /// Transport that sends and reads on the calling thread, for hosts where
+    /// two threads per programmer are more than they can spare
+    pub fn inline(device_interface: Box<dyn DeviceInterface + Send>) -> Self {
+        Transport {
+            device_interface: Arc::new(Mutex::new(device_interface)),
+            waiting: Arc::new(AtomicUsize::new(0)),
+            threads: None,
+            inline: true,
+            operation_timeout: None,
+            deadline: Mutex::new(None),
+        }
+    }
+
+    /// Stop the threads and drop the device interface, releasing the serial
+    /// port. Everything but reopen fails from here on
+    pub fn close(&mut self) {
+        self.threads = None;
+        self.device_interface = Arc::new(Mutex::new(Box::new(ClosedDevice)));
+    }
+
+    /// Start over on a freshly opened device, in the same mode as before
+    pub fn reopen(&mut self, device_interface: Box<dyn DeviceInterface + Send>) {
+        let operation_timeout = self.operation_timeout;
+        *self = if self.inline {
+            Transport::inline(device_interface)
+        } else {
+            Transport::new(device_interface)
+        };
+        self.operation_timeout = operation_timeout;
+    }
+
+    /// Limit how long an operation, from start_operation on, may spend
+    /// waiting for responses. None leaves each response its own
+    /// DEFAULT_RESPONSE_TIMEOUT_MS
+    pub fn set_operation_timeout(&mut self, timeout: Option<Duration>) {
+        self.operation_timeout = timeout;
+    }
+
+    /// Start the clock on the operation timeout, if there is one
+    pub fn start_operation(&self) -> AvrResult<()> {
+        *self.deadline()? = self
+            .operation_timeout
+            .map(|timeout| Instant::now() + timeout);
+        Ok(())
+    }
+
+    fn deadline(&self) -> AvrResult<MutexGuard<'_, Option<Instant>>> {
+        self.deadline
+            .lock()
+            .map_err(|_| AvrError::Communication("Failed to lock deadline".to_string()))
+    }
+
+    /// Send a command to the device. Any response bytes still queued at this
+    /// point belong to an earlier exchange and are dropped as stale
+    pub fn send_command(&self, command: Vec<u8>) -> AvrResult<()> {
+        let Some(threads) = &self.threads else {
+            return lock_device(&self.device_interface, &self.waiting)?.send(command);
+        };
+
+        threads.check_error()?;
+        let source = threads.source()?;
+        while source.try_recv().is_ok() {}
+
+        threads
+            .sink
+            .send(command)
+            .map_err(|e| AvrError::Communication(format!("Failed to send command: {:?}", e)))?;
+        Ok(())
+    }
+
+    /// Wait for `expected_size` bytes, for as long as the operation timeout
+    /// allows, or DEFAULT_RESPONSE_TIMEOUT_MS when there is none
+    pub fn receive_response_with_size(&self, expected_size: usize) -> AvrResult<Vec<u8>> {
+        let (deadline, timeout_message) = match *self.deadline()? {
+            Some(deadline) => (deadline, "Operation timed out"),
+            None => (
+                Instant::now() + Duration::from_millis(DEFAULT_RESPONSE_TIMEOUT_MS),
+                "No response from the device",
+            ),
+        };
+        let Some(threads) = &self.threads else {
+            return self.receive_inline(expected_size, Some(deadline), timeout_message);
+        };
+
+        threads.receive(expected_size, Some(deadline), timeout_message)
+    }
+
+    /// Same as receive_response_with_size, but gives up with a communication
+    /// error when no complete response arrived within `timeout`
+    pub fn receive_response_with_timeout(
+        &self,
+        expected_size: usize,
+        timeout: Duration,
+    ) -> AvrResult<Vec<u8>> {
+        let deadline = Instant::now() + timeout;
+        let Some(threads) = &self.threads else {
+            return self.receive_inline(
+                expected_size,
+                Some(deadline),
+                "Failed to receive response: timed out",
+            );
+        };
+
+        threads.receive(
+            expected_size,
+            Some(deadline),
+            "Failed to receive response: timed out",
+        )
+    }
+
+    /// Read from the device on the calling thread until `expected_size`
+    /// bytes arrived, or `deadline` passed. Backs off like the receiver
+    /// thread once the device stays quiet, so a device that answers reads
+    /// straight away with nothing doesn't keep a core busy
+    fn receive_inline(
+        &self,
+        expected_size: usize,
+        deadline: Option<Instant>,
+        timeout_message: &str,
+    ) -> AvrResult<Vec<u8>> {
+        let mut device_interface = lock_device(&self.device_interface, &self.waiting)?;
+        let mut received = Vec::new();
+        let mut idle_reads = 0;
+
+        while received.len() < expected_size {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(timed_out(timeout_message, received.len(), expected_size));
+            }
+            if idle_reads >= TRANSPORT_IDLE_SPINS {
+                std::thread::sleep(Duration::from_micros(TRANSPORT_THREAD_SLEEP_MICROS));
+            }
+
+            let fresh_bytes = device_interface.receive()?;
+            if fresh_bytes.is_empty() {
+                idle_reads += 1;
+            } else {
+                idle_reads = 0;
+                received.extend(fresh_bytes);
+            }
+        }
+        Ok(received)
+    }
+
+    pub fn reset(&self) -> AvrResult<()> {
+        lock_device(&self.device_interface, &self.waiting)?.reset()
+    }
+
+    /// Drop everything buffered on the way to and from the device, then any
+    /// responses the receiver thread already queued
+    pub fn flush(&self) -> AvrResult<()> {
+        lock_device(&self.device_interface, &self.waiting)?.flush()?;
+
+        if let Some(threads) = &self.threads {
+            let source = threads.source()?;
+            while source.try_recv().is_ok() {}
+        }
+        Ok(())
+    }
+
+    pub fn set_timeout(&self, timeout: Duration) -> AvrResult<()> {
+        lock_device(&self.device_interface, &self.waiting)?.set_timeout(timeout)
+    }
+}
+
+fn timed_out(message: &str, received: usize, expected_size: usize) -> AvrError {
+    AvrError::Communication(format!(
+        "{} after {} of {} bytes",
+        message, received, expected_size
+    ))
+}
+
+/// Stands in for the device interface of a closed transport
+struct ClosedDevice;
+
+impl ClosedDevice {
+    fn closed<T>() -> AvrResult<T> {
+        Err(AvrError::Communication(String::from(
+            "Device is closed, reopen the programmer first",
+        )))
+    }
+}
+
+impl DeviceInterface for ClosedDevice {
+    fn send(&mut self, _command: Vec<u8>) -> AvrResult<()> {
+        Self::closed()
+    }
+
+    fn receive(&mut self) -> AvrResult<Vec<u8>> {
+        Self::closed()
+    }
+
+    fn reset(&mut self) -> AvrResult<()> {
+        Self::closed()
+    }
+
+    fn set_timeout(&mut self, _timeout: Duration) -> AvrResult<()> {
+        Self::closed()
+    }
+
+    fn flush(&mut self) -> AvrResult<()> {
+        Self::closed()
+    }
+}
+
+/// Lock the device ahead of the receiver thread, which backs off while
+/// `waiting` is non-zero
+fn lock_device<'a>(
+    device_interface: &'a SharedDevice,
+    waiting: &AtomicUsize,
+) -> AvrResult<MutexGuard<'a, Box<dyn DeviceInterface + Send>>> {
+    waiting.fetch_add(1, Ordering::SeqCst);
+    let device = device_interface.lock();
+    waiting.fetch_sub(1, Ordering::SeqCst);
+
+    device.map_err(|_| AvrError::Communication("Failed to lock device_interface".to_string()))
+}
+
+/// Keep `e` for the caller, unless an earlier error is still waiting
+fn record_error(slot: &Mutex<Option<AvrError>>, e: AvrError) {
+    if let Ok(mut slot) = slot.lock() {
+        slot.get_or_insert(e);
+    }
+}
+
+impl TransportThreads {
+    fn source(&self) -> AvrResult<MutexGuard<'_, mpsc::Receiver<Vec<u8>>>> {
+        self.source
+            .lock()
+            .map_err(|_| AvrError::Communication("Failed to lock response channel".to_string()))
+    }
+
+    /// Hand over the error a thread ran into since the last call, if any
+    fn check_error(&self) -> AvrResult<()> {
+        let mut error = self
+            .error
+            .lock()
+            .map_err(|_| AvrError::Communication("Failed to lock thread error".to_string()))?;
+        match error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Collect `expected_size` bytes from the receiver thread, until
+    /// `deadline` passes. Waits in short steps, so an error in either thread
+    /// is returned instead of waiting for a response that can't come
+    fn receive(
+        &self,
+        expected_size: usize,
+        deadline: Option<Instant>,
+        timeout_message: &str,
+    ) -> AvrResult<Vec<u8>> {
+        let source = self.source()?;
+        let mut received = Vec::new();
+        let step = Duration::from_millis(THREAD_ERROR_POLL_MS);
+
+        while received.len() < expected_size {
+            self.check_error()?;
+            let wait = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()).min(step),
+                None => step,
+            };
+
+            match source.recv_timeout(wait) {
+                Ok(fresh_bytes) => received.extend(fresh_bytes),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Err(timed_out(timeout_message, received.len(), expected_size));
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    self.check_error()?;
+                    return Err(AvrError::Communication(String::from(
+                        "Failed to receive response: receiver thread stopped",
+                    )));
+                }
+            }
+        }
+        Ok(received)
+    }
+}
+
+impl Drop for TransportThreads {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        for thread in self.thread_handles.drain(..) {
+            thread
+                .join()
+                .unwrap_or_else(|e| error!("Thread join failed: {:?}", e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::{Layer, Registry};
+
+    /// Subscriber layer that keeps the message of every event
+    struct CaptureLayer {
+        messages: Arc<Mutex<Vec<String>>>,
+    }
+
+    struct MessageVisitor(Option<String>);
+
+    impl Visit for MessageVisitor {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = Some(format!("{:?}", value));
+            }
+        }
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for CaptureLayer {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+            let mut visitor = MessageVisitor(None);
+            event.record(&mut visitor);
+            if let Some(message) = visitor.0 {
+                self.messages.lock().unwrap().push(message);
+            }
+        }
+    }
+
+    /// Device that never has anything to say
+    struct SilentDevice;
+
+    impl DeviceInterface for SilentDevice {
+        fn send(&mut self, _command: Vec<u8>) -> AvrResult<()> {
+            Ok(())
+        }
+
+        fn receive(&mut self) -> AvrResult<Vec<u8>> {
+            Ok(vec![])
+        }
+
+        fn reset(&mut self) -> AvrResult<()> {
+            Ok(())
+        }
+    }
+
+    /// Device that never has anything to say, counting how often it was asked
+    struct CountingSilentDevice {
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl DeviceInterface for CountingSilentDevice {
+        fn send(&mut self, _command: Vec<u8>) -> AvrResult<()> {
+            Ok(())
+        }
+
+        fn receive(&mut self) -> AvrResult<Vec<u8>> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            Ok(vec![])
+        }
+
+        fn reset(&mut self) -> AvrResult<()> {
+            Ok(())
+        }
+    }
+
+    /// Device that answers every read with a fresh chunk of noise
+    struct FloodingDevice {
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl DeviceInterface for FloodingDevice {
+        fn send(&mut self, _command: Vec<u8>) -> AvrResult<()> {
+            Ok(())
+        }
+
+        fn receive(&mut self) -> AvrResult<Vec<u8>> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            Ok(vec![0xAA; 64])
+        }
+
+        fn reset(&mut self) -> AvrResult<()> {
+            Ok(())
+        }
+    }
+
+    /// Device whose every read blocks for a while, like a serial port with
+    /// nothing to say waiting out its timeout
+    struct SlowDevice;
+
+    impl DeviceInterface for SlowDevice {
+        fn send(&mut self, _command: Vec<u8>) -> AvrResult<()> {
+            Ok(())
+        }
+
+        fn receive(&mut self) -> AvrResult<Vec<u8>> {
+            std::thread::sleep(Duration::from_millis(50));
+            Ok(vec![])
+        }
+
+        fn reset(&mut self) -> AvrResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reset_is_not_starved_by_blocking_reads() {
+        let transport = Transport::new(Box::new(SlowDevice));
+        std::thread::sleep(Duration::from_millis(20));
+
+        // Each reset waits for at most the read in progress
+        let start = Instant::now();
+        for _ in 0..10 {
+            transport.reset().unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(10 * 50 + 250));
+    }
+
+    /// Slow device that hands out a sending handle, counting what each
+    /// handle sent
+    struct CloneableDevice {
+        sent_by_clone: Arc<AtomicUsize>,
+        is_clone: bool,
+    }
+
+    impl DeviceInterface for CloneableDevice {
+        fn send(&mut self, _command: Vec<u8>) -> AvrResult<()> {
+            assert!(self.is_clone, "Command sent through the reading handle");
+            self.sent_by_clone.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn receive(&mut self) -> AvrResult<Vec<u8>> {
+            std::thread::sleep(Duration::from_millis(50));
+            Ok(vec![])
+        }
+
+        fn reset(&mut self) -> AvrResult<()> {
+            Ok(())
+        }
+
+        fn try_clone_sender(&self) -> Option<Box<dyn DeviceInterface + Send>> {
+            Some(Box::new(CloneableDevice {
+                sent_by_clone: Arc::clone(&self.sent_by_clone),
+                is_clone: true,
+            }))
+        }
+    }
+
+    #[test]
+    fn test_commands_go_out_through_cloned_handle_during_reads() {
+        let sent_by_clone = Arc::new(AtomicUsize::new(0));
+        let transport = Transport::new(Box::new(CloneableDevice {
+            sent_by_clone: Arc::clone(&sent_by_clone),
+            is_clone: false,
+        }));
+
+        for _ in 0..5 {
+            transport.send_command(vec![0x30, 0x20]).unwrap();
+        }
+
+        // Well within a single blocking read
+        let start = Instant::now();
+        while sent_by_clone.load(Ordering::Relaxed) < 5 {
+            assert!(start.elapsed() < Duration::from_millis(40));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_silent_device_fails_once_operation_times_out() {
+        for mut transport in [
+            Transport::new(Box::new(SilentDevice)),
+            Transport::inline(Box::new(SilentDevice)),
+        ] {
+            transport.set_operation_timeout(Some(Duration::from_millis(50)));
+            transport.start_operation().unwrap();
+
+            let start = Instant::now();
+            let result = transport.receive_response_with_size(2);
+            assert!(
+                matches!(result, Err(AvrError::Communication(e)) if e.starts_with("Operation timed out"))
+            );
+            assert!(start.elapsed() < Duration::from_millis(500));
+        }
+    }
+
+    #[test]
+    fn test_silent_device_fails_without_operation_timeout() {
+        for transport in [
+            Transport::new(Box::new(SilentDevice)),
+            Transport::inline(Box::new(SilentDevice)),
+        ] {
+            transport.start_operation().unwrap();
+
+            let start = Instant::now();
+            let result = transport.receive_response_with_size(2);
+            assert!(
+                matches!(result, Err(AvrError::Communication(e)) if e.starts_with("No response"))
+            );
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(DEFAULT_RESPONSE_TIMEOUT_MS));
+            assert!(elapsed < Duration::from_millis(DEFAULT_RESPONSE_TIMEOUT_MS + 500));
+        }
+    }
+
+    #[test]
+    fn test_inline_reads_back_off_while_device_is_quiet() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let mut transport = Transport::inline(Box::new(CountingSilentDevice {
+            reads: Arc::clone(&reads),
+        }));
+        transport.set_operation_timeout(Some(Duration::from_millis(100)));
+        transport.start_operation().unwrap();
+
+        assert!(transport.receive_response_with_size(2).is_err());
+
+        // The idle spins, then at most one read per sleep. A busy loop makes
+        // millions of reads in the same time
+        let max_reads =
+            TRANSPORT_IDLE_SPINS as usize + (100_000 / TRANSPORT_THREAD_SLEEP_MICROS) as usize + 1;
+        assert!(reads.load(Ordering::Relaxed) <= max_reads);
+    }
+
+    /// Device that was unplugged: writes fail, and reads too when `reads_fail`
+    struct UnpluggedDevice {
+        reads_fail: bool,
+    }
+
+    impl DeviceInterface for UnpluggedDevice {
+        fn send(&mut self, _command: Vec<u8>) -> AvrResult<()> {
+            Err(AvrError::Communication(String::from("Port disconnected")))
+        }
+
+        fn receive(&mut self) -> AvrResult<Vec<u8>> {
+            if self.reads_fail {
+                return Err(AvrError::Communication(String::from("Port disconnected")));
+            }
+            Ok(vec![])
+        }
+
+        fn reset(&mut self) -> AvrResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_send_error_is_returned_to_waiting_caller() {
+        let transport = Transport::new(Box::new(UnpluggedDevice { reads_fail: false }));
+
+        // Queued fine, the sender thread fails to write it later
+        transport.send_command(vec![0x30, 0x20]).unwrap();
+        let result = transport.receive_response_with_size(2);
+        assert!(matches!(result, Err(AvrError::Communication(e)) if e == "Port disconnected"));
+    }
+
+    #[test]
+    fn test_receive_error_is_returned_to_caller() {
+        let transport = Transport::new(Box::new(UnpluggedDevice { reads_fail: true }));
+
+        let result = transport.receive_response_with_size(2);
+        assert!(matches!(result, Err(AvrError::Communication(e)) if e == "Port disconnected"));
+
+        // The receiver thread is gone, later reads fail straight away
+        assert!(transport.receive_response_with_size(2).is_err());
+    }
+
+    #[test]
+    fn test_closed_transport_refuses_until_reopened() {
+        let mut transport = Transport::new(Box::new(SilentDevice));
+
+        transport.close();
+        assert!(transport.threads.is_none());
+        assert!(matches!(transport.reset(), Err(AvrError::Communication(_))));
+        assert!(matches!(
+            transport.send_command(vec![0x30, 0x20]),
+            Err(AvrError::Communication(_))
+        ));
+
+        transport.reopen(Box::new(SilentDevice));
+        assert!(transport.threads.is_some());
+        transport.reset().unwrap();
+        transport.send_command(vec![0x30, 0x20]).unwrap();
+    }
+
+    #[test]
+    fn test_flooding_device_does_not_grow_queue_without_bound() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let transport = Transport::new(Box::new(FloodingDevice {
+            reads: Arc::clone(&reads),
+        }));
+
+        // Nobody consumes responses while the device keeps producing them
+        std::thread::sleep(Duration::from_millis(200));
+
+        // Reading stops once the channel is full: one read per queued chunk
+        // plus the one held back by the receiver thread
+        assert!(reads.load(Ordering::Relaxed) <= RESPONSE_CHANNEL_CAPACITY + 1);
+
+        let mut queued = 0;
+        let threads = transport.threads.as_ref().unwrap();
+        while threads.source.lock().unwrap().try_recv().is_ok() {
+            queued += 1;
+        }
+        assert!(queued <= RESPONSE_CHANNEL_CAPACITY);
+    }
+
+    #[test]
+    fn test_thread_lifecycle_events_are_emitted() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = Registry::default().with(CaptureLayer {
+            messages: Arc::clone(&messages),
+        });
+
+        tracing::subscriber::with_default(subscriber, || {
+            let transport = Transport::new(Box::new(SilentDevice));
+            std::thread::sleep(Duration::from_millis(20));
+            drop(transport);
+        });
+
+        let messages = messages.lock().unwrap();
+        let count = |message: &str| messages.iter().filter(|m| *m == message).count();
+        assert_eq!(count("Transport thread started"), 2);
+        assert_eq!(count("Shutdown signal received"), 2);
+        assert_eq!(count("Transport thread exited"), 2);
+
+        // Every thread starts before anything exits
+        let last_start = messages
+            .iter()
+            .rposition(|m| m == "Transport thread started")
+            .unwrap();
+        let first_exit = messages
+            .iter()
+            .position(|m| m == "Transport thread exited")
+            .unwrap();
+        assert!(last_start < first_exit);
+    }
+}
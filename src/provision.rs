@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+
+use crate::error::{AvrError, AvrResult};
+use crate::fuses::{EepromOnErase, Fuses};
+use crate::report::ProgramReport;
+use crate::util::{intel_hex_to_binary, srec::srec_to_binary};
+
+/// Firmware to program as part of a provisioning run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FirmwareSource {
+    HexFile(PathBuf),
+    SrecFile(PathBuf),
+
+    /// Raw image starting at flash address 0
+    Binary(Vec<u8>),
+}
+
+impl FirmwareSource {
+    /// Load the firmware as a binary image starting at address 0
+    pub(crate) fn to_binary(&self) -> AvrResult<Vec<u8>> {
+        let read = |path: &PathBuf| {
+            std::fs::read_to_string(path).map_err(|e| {
+                AvrError::FirmwareError(format!("Failed to read {}: {}", path.display(), e))
+            })
+        };
+
+        let bin = match self {
+            FirmwareSource::HexFile(path) => intel_hex_to_binary(&read(path)?)?,
+            FirmwareSource::SrecFile(path) => srec_to_binary(&read(path)?)?,
+            FirmwareSource::Binary(bin) => bin.clone(),
+        };
+        if bin.is_empty() {
+            return Err(AvrError::FirmwareError(String::from("Firmware is empty")));
+        }
+        Ok(bin)
+    }
+}
+
+/// Everything to do to a fresh chip on a production line. Steps run in the
+/// order erase, fuses, firmware (with verification unless disabled on the
+/// programmer), lock bits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProvisionPlan {
+    pub erase: bool,
+
+    /// EEPROM handling for the erase step, see Programmer::erase_with
+    pub eeprom: EepromOnErase,
+
+    /// Written with the usual safety checks, see Programmer::write_fuses
+    pub fuses: Option<Fuses>,
+    pub firmware: FirmwareSource,
+
+    /// Written last, as locking stops the firmware being verified
+    pub lock_bits: Option<u8>,
+}
+
+/// What a provisioning run did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProvisionReport {
+    pub erased: bool,
+    pub fuses_written: Option<Fuses>,
+    pub program: ProgramReport,
+    pub lock_bits_written: Option<u8>,
+}
+
+/// Name the provisioning step an error came from
+pub(crate) fn step_failed(step: &str, error: AvrError) -> AvrError {
+    AvrError::ProgrammerError(format!("Provisioning stopped at {}: {}", step, error))
+}
@@ -0,0 +1,18 @@
+use std::time::Duration;
+
+/// What a programming run did and how long each phase took
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProgramReport {
+    /// Flash bytes sent to the target, including any padding of the last page
+    pub bytes_written: usize,
+    pub pages_written: usize,
+
+    /// Bytes read back and compared, 0 when verification was skipped
+    pub verify_bytes: usize,
+
+    pub upload_duration: Duration,
+    pub verify_duration: Duration,
+
+    /// Whole run, including connecting to the bootloader
+    pub duration: Duration,
+}
@@ -1,6 +1,56 @@
-use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::BTreeMap;
 
-pub(crate) fn create_progress_bar(total_steps: u64, msg: &str) -> ProgressBar {
+use ihex::{Reader, Record};
+#[cfg(feature = "progress")]
+pub(crate) use indicatif::ProgressBar;
+#[cfg(feature = "progress")]
+use indicatif::ProgressStyle;
+
+use crate::constants::MAX_FLASH_SIZE;
+use crate::error::{AvrError, AvrResult};
+
+pub(crate) mod srec;
+
+/// Number of data bytes emitted per Intel hex data record
+const HEX_RECORD_SIZE: usize = 16;
+
+/// Stand-in for indicatif's ProgressBar when the `progress` feature is off.
+/// It has no values, so code holding an `Option<ProgressBar>` compiles
+/// unchanged and always sees `None`
+#[cfg(not(feature = "progress"))]
+pub(crate) enum ProgressBar {}
+
+#[cfg(not(feature = "progress"))]
+impl ProgressBar {
+    pub(crate) fn set_position(&self, _position: u64) {
+        match *self {}
+    }
+
+    pub(crate) fn inc(&self, _delta: u64) {
+        match *self {}
+    }
+
+    pub(crate) fn finish_with_message(&self, _msg: &'static str) {
+        match *self {}
+    }
+}
+
+/// Terminal progress bar for `total_steps` steps, or `None` when disabled or
+/// built without the `progress` feature
+pub(crate) fn progress_bar(enable: bool, total_steps: u64, msg: &str) -> Option<ProgressBar> {
+    #[cfg(feature = "progress")]
+    {
+        enable.then(|| create_progress_bar(total_steps, msg))
+    }
+    #[cfg(not(feature = "progress"))]
+    {
+        let _ = (enable, total_steps, msg);
+        None
+    }
+}
+
+#[cfg(feature = "progress")]
+fn create_progress_bar(total_steps: u64, msg: &str) -> ProgressBar {
     let pb = ProgressBar::new(total_steps);
 
     pb.set_style(
@@ -13,3 +63,295 @@ pub(crate) fn create_progress_bar(total_steps: u64, msg: &str) -> ProgressBar {
 
     pb
 }
+
+/// Decode pairs of hex digits into bytes. None when there is an odd number of
+/// digits or anything that isn't a hex digit, non-ASCII characters included
+pub(crate) fn decode_hex(digits: &[u8]) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let high = (pair[0] as char).to_digit(16)?;
+            let low = (pair[1] as char).to_digit(16)?;
+            Some((high << 4 | low) as u8)
+        })
+        .collect()
+}
+
+/// Strip the trailing erased (0xFF) region of a flash image. Runs of 0xFF shorter
+/// than `threshold` bytes are assumed to be part of the program and kept
+pub(crate) fn trim_erased_tail(bin: &[u8], threshold: usize) -> &[u8] {
+    let used = bin
+        .iter()
+        .rposition(|byte| *byte != 0xFF)
+        .map_or(0, |last| last + 1);
+
+    if bin.len() - used >= threshold.max(1) {
+        &bin[..used]
+    } else {
+        bin
+    }
+}
+
+/// Split a flat binary starting at address 0 into Intel hex data records,
+/// with an extended linear address record before every 64KB boundary crossed
+pub(crate) fn binary_to_records(bin: &[u8]) -> Vec<Record> {
+    let mut records = Vec::new();
+    let mut upper_address = 0;
+
+    for (index, chunk) in bin.chunks(HEX_RECORD_SIZE).enumerate() {
+        let address = index * HEX_RECORD_SIZE;
+        if (address >> 16) != upper_address {
+            upper_address = address >> 16;
+            records.push(Record::ExtendedLinearAddress(upper_address as u16));
+        }
+        records.push(Record::Data {
+            offset: (address & 0xFFFF) as u16,
+            value: chunk.to_vec(),
+        });
+    }
+    records
+}
+
+/// Convert a flat binary starting at address 0 into an Intel hex string
+pub(crate) fn binary_to_intel_hex(bin: &[u8]) -> AvrResult<String> {
+    let mut records = binary_to_records(bin);
+    records.push(Record::EndOfFile);
+
+    ihex::create_object_file_representation(&records)
+        .map_err(|e| AvrError::FirmwareError(format!("Failed to write hex records {:?}", e)))
+}
+
+/// Data records collected by load address, in whatever order the file lists
+/// them, before being flattened into a binary image
+#[derive(Default)]
+pub(crate) struct MemoryMap {
+    segments: BTreeMap<usize, Vec<u8>>,
+}
+
+impl MemoryMap {
+    /// Place `data` at `address`, refusing it if it overlaps data already placed
+    /// or lies beyond the largest AVR flash. Without the bound, a record at
+    /// eg: 0x810000 (EEPROM data in avr-gcc's address space) would be padded
+    /// out to an image of megabytes
+    pub fn insert(&mut self, address: usize, data: &[u8]) -> AvrResult<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = match address.checked_add(data.len()) {
+            Some(end) if end <= MAX_FLASH_SIZE as usize => end,
+            _ => {
+                return Err(AvrError::FirmwareError(format!(
+                    "Data at {:#07x} runs past the {} KB of the largest AVR flash",
+                    address,
+                    MAX_FLASH_SIZE / 1024
+                )));
+            }
+        };
+
+        let before = self.segments.range(..=address).next_back();
+        let after = self.segments.range(address..end).next();
+        for (start, segment) in before.into_iter().chain(after) {
+            if *start < end && address < start + segment.len() {
+                return Err(AvrError::FirmwareError(format!(
+                    "Data at {:#07x}..{:#07x} overlaps data already loaded at {:#07x}..{:#07x}",
+                    address,
+                    end,
+                    start,
+                    start + segment.len()
+                )));
+            }
+        }
+
+        self.segments.insert(address, data.to_vec());
+        Ok(())
+    }
+
+    /// Binary image starting at address 0, with every gap filled with 0xFF
+    /// (erased flash)
+    pub fn into_binary(self) -> Vec<u8> {
+        let mut bin = Vec::new();
+        for (address, segment) in self.segments {
+            bin.resize(address, 0xFF);
+            bin.extend_from_slice(&segment);
+        }
+        bin
+    }
+}
+
+/// Flatten an Intel hex file into a binary starting at address 0. Data records
+/// are placed at the address given by the preceding extended linear or
+/// extended segment address record, gaps are filled with 0xFF and records
+/// that overlap are refused
+pub(crate) fn intel_hex_to_binary(hex_content: &str) -> AvrResult<Vec<u8>> {
+    let mut memory = MemoryMap::default();
+    let mut base_address = 0;
+
+    for record in Reader::new(hex_content) {
+        let record = record.map_err(|e| {
+            AvrError::FirmwareError(format!("Failed parsing record in hex file {:?}", e))
+        })?;
+
+        match record {
+            Record::Data { offset, value } => {
+                memory.insert(base_address + offset as usize, &value)?;
+            }
+            Record::ExtendedLinearAddress(upper) => base_address = (upper as usize) << 16,
+            Record::ExtendedSegmentAddress(segment) => base_address = (segment as usize) << 4,
+            Record::EndOfFile => break,
+            _ => {}
+        }
+    }
+
+    Ok(memory.into_binary())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trailing_erased_bytes_are_not_emitted() {
+        let mut flash = vec![0x0c, 0x94, 0x5c, 0x00];
+        flash.extend(vec![0xFF; 64]);
+
+        let hex = binary_to_intel_hex(trim_erased_tail(&flash, 1)).unwrap();
+
+        let mut emitted = Vec::new();
+        for record in ihex::Reader::new(&hex) {
+            if let Record::Data { value, .. } = record.unwrap() {
+                emitted.extend(value);
+            }
+        }
+        assert_eq!(emitted, vec![0x0c, 0x94, 0x5c, 0x00]);
+    }
+
+    #[test]
+    fn test_trim_threshold_keeps_short_erased_runs() {
+        let flash = [0x01, 0x02, 0xFF, 0xFF];
+
+        assert_eq!(trim_erased_tail(&flash, 4), &flash[..]);
+        assert_eq!(trim_erased_tail(&flash, 2), &flash[..2]);
+        assert_eq!(trim_erased_tail(&[0xFF; 8], 1), &[] as &[u8]);
+    }
+
+    #[test]
+    fn test_extended_address_records_above_64k() {
+        let flash = vec![0x00; 0x10010];
+        let hex = binary_to_intel_hex(&flash).unwrap();
+
+        let records: Vec<Record> = ihex::Reader::new(&hex).map(|r| r.unwrap()).collect();
+        assert!(records.contains(&Record::ExtendedLinearAddress(1)));
+    }
+
+    #[test]
+    fn test_record_offsets_reconstruct_addresses() {
+        let flash: Vec<u8> = (0..0x10020).map(|address| (address % 253) as u8).collect();
+
+        let mut upper = 0;
+        let mut rebuilt = vec![0xFF; flash.len()];
+        for record in binary_to_records(&flash) {
+            match record {
+                Record::ExtendedLinearAddress(address) => upper = (address as usize) << 16,
+                Record::Data { offset, value } => {
+                    let start = upper + offset as usize;
+                    rebuilt[start..start + value.len()].copy_from_slice(&value);
+                }
+                record => panic!("Unexpected record {:?}", record),
+            }
+        }
+        assert_eq!(rebuilt, flash);
+    }
+
+    #[test]
+    fn test_gaps_between_hex_records_are_erased() {
+        let hex = ":020000000102FB\n\
+                   :020006000304F1\n\
+                   :00000001FF\n";
+
+        assert_eq!(
+            intel_hex_to_binary(hex).unwrap(),
+            vec![0x01, 0x02, 0xFF, 0xFF, 0xFF, 0xFF, 0x03, 0x04]
+        );
+    }
+
+    #[test]
+    fn test_hex_digits_are_decoded_from_bytes() {
+        assert_eq!(decode_hex(b"1e9F0a"), Some(vec![0x1e, 0x9f, 0x0a]));
+        assert_eq!(decode_hex(b"1e9"), None);
+        assert_eq!(decode_hex(b"1g"), None);
+        assert_eq!(decode_hex("1e9€".as_bytes()), None);
+    }
+
+    #[test]
+    fn test_hex_data_past_largest_flash_is_refused() {
+        // Two bytes at 0x40000, just past 256KB
+        let hex = ":020000040004F6\n\
+                   :020000000102FB\n\
+                   :00000001FF\n";
+
+        match intel_hex_to_binary(hex) {
+            Err(AvrError::FirmwareError(message)) => assert!(message.contains("0x40000")),
+            result => panic!("Expected a firmware error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_extended_address_records_place_data_above_64k() {
+        let mut bin = vec![0xFF; 0x10010];
+        bin[0x10000..].fill(0x5A);
+
+        let hex = binary_to_intel_hex(&bin).unwrap();
+        assert_eq!(intel_hex_to_binary(&hex).unwrap(), bin);
+    }
+
+    #[test]
+    fn test_overlapping_hex_records_are_refused() {
+        let hex = ":020000000102FB\n\
+                   :020001000304F6\n\
+                   :00000001FF\n";
+
+        assert!(matches!(
+            intel_hex_to_binary(hex),
+            Err(AvrError::FirmwareError(_))
+        ));
+    }
+
+    #[test]
+    fn test_bad_hex_checksum_is_refused() {
+        let hex = ":020000000102FC\n:00000001FF\n";
+
+        assert!(matches!(
+            intel_hex_to_binary(hex),
+            Err(AvrError::FirmwareError(_))
+        ));
+    }
+
+    #[test]
+    fn test_data_after_extended_linear_address_lands_above_64k() {
+        let hex = ":020000000102FB\n\
+                   :020000040001F9\n\
+                   :04000000DEADBEEFC4\n\
+                   :00000001FF\n";
+
+        let bin = intel_hex_to_binary(hex).unwrap();
+        assert_eq!(bin.len(), 0x10004);
+        assert_eq!(bin[..2], [0x01, 0x02]);
+        assert!(bin[2..0x10000].iter().all(|byte| *byte == 0xFF));
+        assert_eq!(bin[0x10000..], [0xDE, 0xAD, 0xBE, 0xEF]);
+    }
+
+    #[test]
+    fn test_out_of_order_hex_records_are_placed_by_address() {
+        let hex = ":020006000304F1\n\
+                   :020000000102FB\n\
+                   :00000001FF\n";
+
+        assert_eq!(
+            intel_hex_to_binary(hex).unwrap(),
+            vec![0x01, 0x02, 0xFF, 0xFF, 0xFF, 0xFF, 0x03, 0x04]
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use super::{MemoryMap, decode_hex};
+use crate::error::{AvrError, AvrResult};
+
+/// Flatten a Motorola S-record file into a binary starting at address 0.
+/// S1, S2 and S3 data records are placed through the same memory map as
+/// Intel hex, so gaps are filled with 0xFF and overlapping records are refused.
+/// Every record's checksum is checked, including the ones that carry no data
+pub(crate) fn srec_to_binary(srec_content: &str) -> AvrResult<Vec<u8>> {
+    let mut memory = MemoryMap::default();
+
+    for (index, line) in srec_content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (record_type, bytes) = parse_record(line)
+            .map_err(|e| AvrError::FirmwareError(format!("S-record line {}: {}", index + 1, e)))?;
+
+        let address_size = match record_type {
+            b'1' => 2,
+            b'2' => 3,
+            b'3' => 4,
+            // Header, record count and start address records carry no data
+            b'0' | b'5'..=b'9' => continue,
+            _ => {
+                return Err(AvrError::FirmwareError(format!(
+                    "S-record line {}: unknown record type S{}",
+                    index + 1,
+                    record_type as char
+                )));
+            }
+        };
+        if bytes.len() < address_size {
+            return Err(AvrError::FirmwareError(format!(
+                "S-record line {}: record too short for its address",
+                index + 1
+            )));
+        }
+
+        let address = bytes[..address_size]
+            .iter()
+            .fold(0, |address, byte| (address << 8) | *byte as usize);
+        memory.insert(address, &bytes[address_size..])?;
+    }
+
+    Ok(memory.into_binary())
+}
+
+/// Split a record into its type and the address and data bytes, after
+/// checking the byte count and checksum
+fn parse_record(line: &str) -> Result<(u8, Vec<u8>), String> {
+    let line = line.as_bytes();
+    if line.len() < 4 || line[0] != b'S' {
+        return Err(String::from("not an S-record"));
+    }
+    let record_type = line[1];
+
+    let hex = &line[2..];
+    if !hex.len().is_multiple_of(2) {
+        return Err(String::from("odd number of hex digits"));
+    }
+    let bytes = decode_hex(hex).ok_or_else(|| String::from("invalid hex digits"))?;
+
+    // The count covers the address, the data and the checksum
+    let count = bytes[0] as usize;
+    if count + 1 != bytes.len() || count == 0 {
+        return Err(format!(
+            "byte count {} doesn't match the record length",
+            count
+        ));
+    }
+
+    let checksum = !bytes[..count]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    if checksum != bytes[count] {
+        return Err(format!(
+            "checksum mismatch (expected {:#04x}, got {:#04x})",
+            checksum, bytes[count]
+        ));
+    }
+
+    Ok((record_type, bytes[1..count].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::intel_hex_to_binary;
+
+    #[test]
+    fn test_blink_srec_matches_blink_hex() {
+        let srec = include_str!("../../tests/blink.srec");
+        let hex = include_str!("../../tests/blink.hex");
+
+        let bin = srec_to_binary(srec).unwrap();
+        assert_eq!(bin[..4], [0x0C, 0x94, 0x5C, 0x00]);
+        assert_eq!(bin, intel_hex_to_binary(hex).unwrap());
+    }
+
+    #[test]
+    fn test_wider_address_records_and_gaps() {
+        // S2 at 0x010000 and S3 at 0x00000004
+        let srec = "S20801000001020304EC\n\
+                    S309000000041122334448\n\
+                    S9030000FC\n";
+
+        let bin = srec_to_binary(srec).unwrap();
+        assert_eq!(bin[4..8], [0x11, 0x22, 0x33, 0x44]);
+        assert!(bin[8..0x10000].iter().all(|byte| *byte == 0xFF));
+        assert_eq!(bin[0x10000..], [0x01, 0x02, 0x03, 0x04]);
+    }
+
+    #[test]
+    fn test_srec_data_past_largest_flash_is_refused() {
+        // S3 at 0x800000, in avr-gcc's SRAM address space
+        let srec = "S30700800000010275\n";
+
+        assert!(matches!(
+            srec_to_binary(srec),
+            Err(AvrError::FirmwareError(_))
+        ));
+    }
+
+    #[test]
+    fn test_non_ascii_srec_is_refused() {
+        // '€' is three bytes of UTF-8, splitting it would panic
+        assert!(matches!(
+            srec_to_binary("S1€0\n"),
+            Err(AvrError::FirmwareError(_))
+        ));
+    }
+
+    #[test]
+    fn test_bad_srec_checksum_is_refused() {
+        let srec = "S1070000DEADBEEF00\n";
+
+        match srec_to_binary(srec) {
+            Err(AvrError::FirmwareError(message)) => assert!(message.contains("checksum")),
+            result => panic!("Expected a firmware error, got {:?}", result),
+        }
+    }
+}
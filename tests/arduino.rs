@@ -1,6 +1,10 @@
 #[cfg(test)]
 mod tests {
-    use avrman::{ProtocolType, interface::SerialportParams, protocols::stk500v1::Stk500v1Params};
+    use avrman::{
+        ProtocolType,
+        interface::{SerialportParams, serialport::ResetLine},
+        protocols::stk500v1::Stk500v1Params,
+    };
 
     #[test]
     fn test_arduino_programming() {
@@ -42,6 +46,8 @@ mod tests {
                     page_size: 128,
                     num_pages: 256,
                     product_id: vec![0x0043, 0x7523, 0x0001, 0xea60, 0x6015],
+                    reset_line: ResetLine::Both,
+                    ..Default::default()
                 }))
                 .unwrap();
 
@@ -0,0 +1,56 @@
+#[cfg(all(test, feature = "async"))]
+mod tests {
+    use std::sync::Arc;
+
+    use avrman::{
+        Programmer, ProtocolType,
+        interface::{loopback::LoopbackInterface, serialport::ResetLine},
+        progress::Phase,
+        protocols::stk500v1::Stk500v1Params,
+    };
+
+    const SIGNATURE: [u8; 3] = [0x1e, 0x95, 0x0f];
+
+    fn params() -> Stk500v1Params {
+        Stk500v1Params {
+            port: String::from("loopback"),
+            baud: 115200,
+            device_signature: SIGNATURE.to_vec(),
+            page_size: 128,
+            num_pages: 256,
+            reset_line: ResetLine::Both,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_hex_file_is_programmed_on_blocking_pool() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let mut programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+        let mut events = programmer.progress_channel();
+        let programmer = Arc::new(programmer);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let report = runtime
+            .block_on(programmer.program_hex_file_async("tests/blink.hex"))
+            .unwrap();
+
+        let mut last_programmed = None;
+        while let Ok(event) = events.try_recv() {
+            if event.phase == Phase::Programming {
+                last_programmed = Some(event);
+            }
+        }
+        let last_programmed = last_programmed.unwrap();
+        assert_eq!(last_programmed.page, report.pages_written);
+        assert_eq!(last_programmed.total_pages, report.pages_written);
+        assert_ne!(flash.lock().unwrap()[0], 0xFF);
+    }
+}
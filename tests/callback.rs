@@ -0,0 +1,126 @@
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use avrman::{
+        Programmer, ProtocolType,
+        interface::{
+            DeviceInterface, callback::CallbackInterface, loopback::LoopbackInterface,
+            serialport::ResetLine,
+        },
+        protocols::stk500v1::Stk500v1Params,
+    };
+
+    #[test]
+    fn test_closure_backed_interface_programs_firmware() {
+        let signature = vec![0x1e, 0x95, 0x0f];
+
+        // The closures bridge to a simulated bootloader, the way a WASM host
+        // would bridge to WebSerial
+        let device = LoopbackInterface::new(signature.clone(), 32 * 1024);
+        let flash = device.flash();
+        let device = Arc::new(Mutex::new(device));
+        let resets = Arc::new(Mutex::new(0));
+
+        let interface = {
+            let send_device = Arc::clone(&device);
+            let receive_device = Arc::clone(&device);
+            let reset_device = Arc::clone(&device);
+            let resets = Arc::clone(&resets);
+
+            CallbackInterface::new(
+                move |command| send_device.lock().unwrap().send(command),
+                move || receive_device.lock().unwrap().receive(),
+                move || {
+                    *resets.lock().unwrap() += 1;
+                    reset_device.lock().unwrap().reset()
+                },
+            )
+        };
+
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(Stk500v1Params {
+                port: String::from("callback"),
+                baud: 115200,
+                device_signature: signature,
+                page_size: 128,
+                num_pages: 256,
+                reset_line: ResetLine::Both,
+                ..Default::default()
+            }),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let firmware: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        programmer.program_binary(firmware.clone()).unwrap();
+
+        assert_eq!(*resets.lock().unwrap(), 1);
+        let flash = flash.lock().unwrap();
+        assert_eq!(flash[..300], firmware[..]);
+    }
+
+    #[test]
+    fn test_bytes_programmed_can_be_polled_from_another_thread() {
+        let signature = vec![0x1e, 0x95, 0x0f];
+        let device = Arc::new(Mutex::new(LoopbackInterface::new(
+            signature.clone(),
+            32 * 1024,
+        )));
+
+        // Every command takes a millisecond to go out, like a slow serial link
+        let interface = {
+            let send_device = Arc::clone(&device);
+            let receive_device = Arc::clone(&device);
+            let reset_device = Arc::clone(&device);
+
+            CallbackInterface::new(
+                move |command| {
+                    thread::sleep(Duration::from_millis(1));
+                    send_device.lock().unwrap().send(command)
+                },
+                move || receive_device.lock().unwrap().receive(),
+                move || reset_device.lock().unwrap().reset(),
+            )
+        };
+
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(Stk500v1Params {
+                port: String::from("callback"),
+                baud: 115200,
+                device_signature: signature,
+                page_size: 128,
+                num_pages: 256,
+                reset_line: ResetLine::Both,
+                ..Default::default()
+            }),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let firmware = vec![0xAA; 128 * 32];
+        let observed = thread::scope(|scope| {
+            let upload = scope.spawn(|| programmer.program_binary(firmware.clone()));
+
+            let mut observed = Vec::new();
+            while !upload.is_finished() {
+                observed.push(programmer.bytes_programmed());
+                thread::sleep(Duration::from_millis(1));
+            }
+            upload.join().unwrap().unwrap();
+            observed
+        });
+
+        assert!(
+            observed
+                .iter()
+                .any(|bytes| *bytes > 0 && *bytes < firmware.len())
+        );
+        assert!(observed.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(programmer.bytes_programmed(), firmware.len());
+    }
+}
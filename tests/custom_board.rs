@@ -0,0 +1,79 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use avrman::{
+        Programmer, ProtocolType,
+        boards::custom_stk500v2_protocol,
+        error::AvrError,
+        interface::{loopback::LoopbackInterface, recording::RecordingInterface},
+        protocols::stk500v2::{MESSAGE_START, Stk500v2Message, TOKEN},
+    };
+
+    const SIGNATURE: [u8; 3] = [0x1e, 0x98, 0x01];
+
+    #[test]
+    fn test_custom_stk500v2_valid_geometry() {
+        let protocol =
+            custom_stk500v2_protocol("/dev/ttyACM0", 115200, SIGNATURE.to_vec(), 256, 1024)
+                .unwrap();
+
+        match protocol {
+            ProtocolType::Stk500v2(params) => {
+                assert_eq!(params.port, "/dev/ttyACM0");
+                assert_eq!(params.baud, 115200);
+                assert_eq!(params.device_signature, SIGNATURE.to_vec());
+                assert_eq!(params.page_size, 256);
+                assert_eq!(params.num_pages, 1024);
+            }
+            _ => panic!("Custom STK500v2 board was not routed to the STK500v2 protocol"),
+        }
+    }
+
+    #[test]
+    fn test_custom_stk500v2_signs_on_with_v2_frames() {
+        let protocol =
+            custom_stk500v2_protocol("loopback", 115200, SIGNATURE.to_vec(), 256, 1024).unwrap();
+        // The loopback only speaks STK500v1, it's only here to take the
+        // sign-on. What matters is how the sign-on was framed
+        let interface = RecordingInterface::new(Box::new(LoopbackInterface::new(
+            SIGNATURE.to_vec(),
+            256 * 1024,
+        )));
+        let commands = interface.commands();
+        // Nothing will answer the sign-on, no need to wait long for it
+        let mut programmer = Programmer::from_protocol_and_interface(protocol, Box::new(interface))
+            .unwrap()
+            .with_deadline(Duration::from_millis(100));
+        programmer.set_auto_reset(false);
+
+        assert!(programmer.read_signature().is_err());
+
+        let commands = commands.lock().unwrap();
+        let sign_on = commands.first().expect("Nothing was sent");
+        assert_eq!(sign_on[0], MESSAGE_START);
+        assert_eq!(sign_on[4], TOKEN);
+        assert_eq!(sign_on[5], Stk500v2Message::CmdSignOn as u8);
+    }
+
+    #[test]
+    fn test_custom_stk500v2_invalid_geometry() {
+        let signature = SIGNATURE.to_vec();
+
+        // Page size must be a power of two
+        let result = custom_stk500v2_protocol("/dev/ttyACM0", 115200, signature.clone(), 100, 10);
+        assert!(matches!(result, Err(AvrError::ConfigurationError(_))));
+
+        // No pages at all
+        let result = custom_stk500v2_protocol("/dev/ttyACM0", 115200, signature.clone(), 256, 0);
+        assert!(matches!(result, Err(AvrError::ConfigurationError(_))));
+
+        // Bigger than any AVR flash
+        let result = custom_stk500v2_protocol("/dev/ttyACM0", 115200, signature, 256, 2048);
+        assert!(matches!(result, Err(AvrError::ConfigurationError(_))));
+
+        // Signature of the wrong length
+        let result = custom_stk500v2_protocol("/dev/ttyACM0", 115200, vec![0x1e, 0x98], 256, 1024);
+        assert!(matches!(result, Err(AvrError::ConfigurationError(_))));
+    }
+}
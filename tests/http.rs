@@ -0,0 +1,95 @@
+#[cfg(all(test, feature = "http"))]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use avrman::{
+        Programmer, ProtocolType,
+        error::AvrError,
+        interface::{loopback::LoopbackInterface, serialport::ResetLine},
+        protocols::stk500v1::Stk500v1Params,
+    };
+
+    const SIGNATURE: [u8; 3] = [0x1e, 0x95, 0x0f];
+
+    const HEX: &str = ":100000000C9434000C943E000C943E000C943E0082\n\
+                       :00000001FF\n";
+
+    fn params() -> Stk500v1Params {
+        Stk500v1Params {
+            port: String::from("loopback"),
+            baud: 115200,
+            device_signature: SIGNATURE.to_vec(),
+            page_size: 128,
+            num_pages: 256,
+            reset_line: ResetLine::Both,
+            ..Default::default()
+        }
+    }
+
+    /// Serve a single request with the given content type and body, returning
+    /// the URL to fetch it from
+    fn serve_once(content_type: &'static str, body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                content_type,
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+
+        format!("http://{}/firmware.hex", address)
+    }
+
+    #[test]
+    fn test_hex_is_downloaded_and_programmed() {
+        let url = serve_once("text/plain", HEX);
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        programmer.program_hex_url(&url).unwrap();
+
+        assert_eq!(
+            flash.lock().unwrap()[..8],
+            [0x0C, 0x94, 0x34, 0x00, 0x0C, 0x94, 0x3E, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_html_response_is_refused() {
+        let url = serve_once("text/html", "<html>Not found</html>");
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let result = programmer.program_hex_url(&url);
+
+        assert!(matches!(result, Err(AvrError::NetworkError(_))));
+        assert!(flash.lock().unwrap().iter().all(|byte| *byte == 0xFF));
+    }
+}
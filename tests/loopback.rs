@@ -0,0 +1,1024 @@
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    use avrman::{
+        Microcontroller, Programmer, ProtocolType,
+        boards::protocol_for_mcu,
+        container::Container,
+        diff::Mismatch,
+        error::{AvrError, AvrResult},
+        fuses::{EepromOnErase, Fuses},
+        interface::{
+            DeviceInterface, DeviceInterfaceType, SerialportParams, loopback::LoopbackInterface,
+            recording::RecordingInterface, serialport::ResetLine,
+        },
+        progress::{Phase, ProgressEvent},
+        protocols::stk500v1::{PageWriteWait, Stk500v1Message, Stk500v1Params, SyncConfig},
+        provision::{FirmwareSource, ProvisionPlan},
+    };
+
+    const SIGNATURE: [u8; 3] = [0x1e, 0x95, 0x0f];
+
+    fn params() -> Stk500v1Params {
+        Stk500v1Params {
+            port: String::from("loopback"),
+            baud: 115200,
+            device_signature: SIGNATURE.to_vec(),
+            page_size: 128,
+            num_pages: 256,
+            reset_line: ResetLine::Both,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_partial_eeprom_update_preserves_untouched_bytes() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let eeprom = interface.eeprom();
+        for (address, cell) in eeprom.lock().unwrap().iter_mut().enumerate().take(16) {
+            *cell = address as u8;
+        }
+
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        // Spans the tail of one EEPROM page and the head of the next
+        programmer.update_eeprom_at(6, &[0xAA, 0xBB, 0xCC]).unwrap();
+
+        let eeprom = eeprom.lock().unwrap();
+        assert_eq!(
+            eeprom[..12],
+            [0, 1, 2, 3, 4, 5, 0xAA, 0xBB, 0xCC, 9, 10, 11]
+        );
+        assert_eq!(eeprom[12..16], [12, 13, 14, 15]);
+    }
+
+    #[test]
+    fn test_eeprom_image_is_programmed_and_verified() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let eeprom = interface.eeprom();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        // Not a multiple of the EEPROM page size
+        let table: Vec<u8> = (0..30).collect();
+        programmer.program_eeprom_binary(table.clone()).unwrap();
+
+        let eeprom = eeprom.lock().unwrap();
+        assert_eq!(eeprom[..30], table[..]);
+        assert!(eeprom[30..].iter().all(|byte| *byte == 0xFF));
+    }
+
+    #[test]
+    fn test_eeprom_update_lands_at_its_byte_address() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let eeprom = interface.eeprom();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        programmer
+            .update_eeprom_at(0x40, &[0x11, 0x22, 0x33])
+            .unwrap();
+
+        {
+            let eeprom = eeprom.lock().unwrap();
+            assert_eq!(eeprom[0x40..0x43], [0x11, 0x22, 0x33]);
+            assert!(eeprom[..0x40].iter().all(|byte| *byte == 0xFF));
+            assert!(eeprom[0x43..].iter().all(|byte| *byte == 0xFF));
+        }
+        assert_eq!(
+            programmer.read_eeprom(0x44).unwrap()[0x40..],
+            [0x11, 0x22, 0x33, 0xFF]
+        );
+    }
+
+    #[test]
+    fn test_eeprom_update_past_the_end_is_refused() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let eeprom = interface.eeprom();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        // The last two bytes would land past the 1KB EEPROM
+        let result = programmer.update_eeprom_at(1022, &[0x11, 0x22, 0x33, 0x44]);
+        assert!(matches!(result, Err(AvrError::ConfigurationError(_))));
+        assert!(eeprom.lock().unwrap().iter().all(|byte| *byte == 0xFF));
+    }
+
+    #[test]
+    fn test_eeprom_update_waits_for_each_page_write() {
+        let interface = RecordingInterface::new(Box::new(LoopbackInterface::new(
+            SIGNATURE.to_vec(),
+            32 * 1024,
+        )));
+        let sent = interface.commands();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(Stk500v1Params {
+                page_write_wait: PageWriteWait::PollReady,
+                ..params()
+            }),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        // Spans two EEPROM pages
+        programmer.update_eeprom_at(6, &[0xAA, 0xBB, 0xCC]).unwrap();
+
+        let sent = sent.lock().unwrap();
+        let page_writes: Vec<usize> = sent
+            .iter()
+            .enumerate()
+            .filter(|(_, command)| {
+                command[0] == Stk500v1Message::CmndStkProgPage as u8 && command[3] == b'E'
+            })
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(page_writes.len(), 2);
+        for index in page_writes {
+            // RDY/BSY poll
+            assert_eq!(
+                sent[index + 1][..2],
+                [Stk500v1Message::CmndStkUniversal as u8, 0xF0]
+            );
+        }
+    }
+
+    #[test]
+    fn test_flash_dump_with_short_final_page() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        for (address, cell) in flash.lock().unwrap().iter_mut().enumerate().take(400) {
+            *cell = (address % 251) as u8;
+        }
+
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        // Two full 128 byte pages and a 44 byte tail
+        let path = std::env::temp_dir().join("avrman_flash_dump.bin");
+        programmer
+            .dump_flash_to_file(path.to_str().unwrap(), 300)
+            .unwrap();
+
+        let dump = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(dump, flash.lock().unwrap()[..300]);
+    }
+
+    #[test]
+    fn test_flash_above_128k_is_not_wrapped() {
+        let signature = vec![0x1e, 0x98, 0x01];
+        let interface = LoopbackInterface::new(signature.clone(), 256 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(Stk500v1Params {
+                device_signature: signature,
+                page_size: 256,
+                num_pages: 1024,
+                ..params()
+            }),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let image: Vec<u8> = (0..0x20000 + 512)
+            .map(|address: usize| (address / 256 + address) as u8)
+            .collect();
+        programmer.program_binary(image.clone()).unwrap();
+
+        let flash = flash.lock().unwrap();
+        assert_eq!(flash[..0x100], image[..0x100]);
+        assert_eq!(flash[0x20000..0x20100], image[0x20000..0x20100]);
+    }
+
+    #[test]
+    fn test_program_end_is_found_in_mostly_erased_flash() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        {
+            let mut flash = flash.lock().unwrap();
+            for (address, cell) in flash.iter_mut().enumerate().take(5000) {
+                *cell = address as u8;
+            }
+            // Erased-looking bytes inside the program don't end it early
+            flash[4000..4200].fill(0xFF);
+            flash[4998] = 0xFF;
+        }
+
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        assert_eq!(programmer.find_program_end().unwrap(), 5000);
+    }
+
+    #[test]
+    fn test_program_end_of_erased_flash_is_zero() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        assert_eq!(programmer.find_program_end().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_container_is_programmed() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let firmware: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let container = Container::new(SIGNATURE, firmware.clone());
+        programmer.program_container(&container.encode()).unwrap();
+
+        assert_eq!(flash.lock().unwrap()[..200], firmware[..]);
+    }
+
+    #[test]
+    fn test_container_for_another_chip_is_refused() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        // ATmega2560
+        let container = Container::new([0x1e, 0x98, 0x01], vec![0x0C, 0x94, 0x34, 0x00]);
+        let result = programmer.program_container(&container.encode());
+
+        assert!(matches!(result, Err(AvrError::FirmwareError(_))));
+        assert!(flash.lock().unwrap().iter().all(|byte| *byte == 0xFF));
+    }
+
+    #[test]
+    fn test_raw_binary_file_is_programmed_from_address_zero() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let firmware: Vec<u8> = (0..333).map(|i| (i * 7) as u8).collect();
+        let path = std::env::temp_dir().join("avrman_firmware.bin");
+        std::fs::write(&path, &firmware).unwrap();
+
+        let result = programmer.program_bin_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert_eq!(flash.lock().unwrap()[..333], firmware[..]);
+    }
+
+    #[test]
+    fn test_empty_binary_file_is_refused() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let path = std::env::temp_dir().join("avrman_empty.bin");
+        std::fs::write(&path, []).unwrap();
+
+        let result = programmer.program_bin_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(AvrError::FirmwareError(_))));
+    }
+
+    #[test]
+    fn test_dry_run_records_handshake_and_page_write() {
+        let (mut programmer, commands) =
+            Programmer::dry_run(ProtocolType::Stk500v1(params())).unwrap();
+        programmer.verify_after_programming(false);
+
+        programmer
+            .program_hex_buffer(":040000000C945C0000\n:00000001FF\n")
+            .unwrap();
+
+        let eop = Stk500v1Message::SyncCrcEop as u8;
+        // 128 byte pages, 1KB of EEPROM and 32KB of flash
+        let mut set_device = vec![Stk500v1Message::CmndStkSetDevice as u8];
+        set_device.extend([0; 12]);
+        set_device.extend([0x00, 0x80, 0x04, 0x00, 0x00, 0x00, 0x80, 0x00]);
+        set_device.push(eop);
+
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec![
+                vec![Stk500v1Message::CmndStkGetSync as u8, eop],
+                vec![Stk500v1Message::CmndStkReadSign as u8, eop],
+                set_device,
+                vec![Stk500v1Message::CmndStkEnterProgMode as u8, eop],
+                vec![Stk500v1Message::CmndStkLoadAddress as u8, 0, 0, eop],
+                vec![
+                    Stk500v1Message::CmndStkProgPage as u8,
+                    0,
+                    4,
+                    b'F',
+                    0x0C,
+                    0x94,
+                    0x5C,
+                    0x00,
+                    eop
+                ],
+                vec![Stk500v1Message::CmndStkLeaveProgMode as u8, eop],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_progress_callback_is_called_per_page_without_progress_bar() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let mut programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let events: Arc<Mutex<Vec<ProgressEvent>>> = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        programmer.on_progress(move |event| sink.lock().unwrap().push(event));
+
+        // Two full pages and a partial one
+        programmer.program_binary(vec![0x5A; 300]).unwrap();
+
+        let events = events.lock().unwrap();
+        let pages = |phase| {
+            events
+                .iter()
+                .filter(|event| event.phase == phase)
+                .map(|event| (event.page, event.total_pages))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(pages(Phase::Programming), vec![(1, 3), (2, 3), (3, 3)]);
+        assert_eq!(pages(Phase::Verifying), vec![(1, 3), (2, 3), (3, 3)]);
+    }
+
+    #[test]
+    fn test_progress_is_streamed_from_programming_thread() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let (handle, events) =
+            programmer.program_with_progress(FirmwareSource::Binary(vec![0x5A; 300]));
+        // Ends when the programming thread drops the programmer
+        let events: Vec<ProgressEvent> = events.iter().collect();
+        let report = handle.join().unwrap().unwrap();
+
+        assert_eq!(report.pages_written, 3);
+        assert_eq!(events.len(), 6);
+        assert_eq!(events[2].phase, Phase::Programming);
+        assert_eq!(events[5].phase, Phase::Verifying);
+        assert!(
+            flash.lock().unwrap()[..300]
+                .iter()
+                .all(|byte| *byte == 0x5A)
+        );
+    }
+
+    #[test]
+    fn test_program_report_counts_pages_and_verified_bytes() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let mut programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let report = programmer.program_binary(vec![0x5A; 300]).unwrap();
+        assert_eq!(report.bytes_written, 300);
+        assert_eq!(report.pages_written, 3);
+        assert_eq!(report.verify_bytes, 300);
+        assert!(report.duration >= report.upload_duration + report.verify_duration);
+
+        programmer.verify_after_programming(false);
+        let report = programmer.program_binary(vec![0x5A; 300]).unwrap();
+        assert_eq!(report.verify_bytes, 0);
+        assert!(report.verify_duration.is_zero());
+    }
+
+    #[test]
+    fn test_attiny85_board_is_programmed_in_64_byte_pages() {
+        let protocol = protocol_for_mcu(
+            Microcontroller::Attiny85,
+            Some(DeviceInterfaceType::Serial(SerialportParams {
+                port: Some(String::from("loopback")),
+                baud: None,
+            })),
+        )
+        .unwrap();
+        let interface = LoopbackInterface::new(vec![0x1e, 0x93, 0x0b], 8 * 1024);
+        let flash = interface.flash();
+        let programmer =
+            Programmer::from_protocol_and_interface(protocol, Box::new(interface)).unwrap();
+
+        let image: Vec<u8> = (0..200).map(|address| address as u8).collect();
+        let report = programmer.program_binary(image.clone()).unwrap();
+
+        assert_eq!(report.pages_written, 4);
+        let flash = flash.lock().unwrap();
+        assert_eq!(flash[..200], image[..]);
+        assert!(flash[200..].iter().all(|byte| *byte == 0xFF));
+    }
+
+    #[test]
+    fn test_attiny85_holding_firmware_is_erased_before_programming() {
+        let protocol = protocol_for_mcu(
+            Microcontroller::Attiny85,
+            Some(DeviceInterfaceType::Serial(SerialportParams {
+                port: Some(String::from("loopback")),
+                baud: None,
+            })),
+        )
+        .unwrap();
+        let interface = LoopbackInterface::new(vec![0x1e, 0x93, 0x0b], 8 * 1024);
+        let flash = interface.flash();
+        // Earlier firmware, with bits an ISP page write can't set again
+        flash.lock().unwrap()[..512].fill(0x00);
+        let programmer =
+            Programmer::from_protocol_and_interface(protocol, Box::new(interface)).unwrap();
+
+        let image: Vec<u8> = (0..200).map(|address| address as u8).collect();
+        programmer.program_binary(image.clone()).unwrap();
+
+        let flash = flash.lock().unwrap();
+        assert_eq!(flash[..200], image[..]);
+        assert!(flash[200..].iter().all(|byte| *byte == 0xFF));
+    }
+
+    #[test]
+    fn test_verify_only_reports_first_differing_page() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let image: Vec<u8> = (0..300).map(|address| address as u8).collect();
+        flash.lock().unwrap()[..300].copy_from_slice(&image);
+
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+        programmer.verify_binary(image.clone()).unwrap();
+
+        flash.lock().unwrap()[0x85] ^= 0xFF;
+        match programmer.verify_binary(image) {
+            Err(AvrError::ProgrammerError(message)) => assert!(message.contains("0x80")),
+            result => panic!("Expected a programmer error, got {:?}", result),
+        }
+        // Nothing was written
+        assert_eq!(flash.lock().unwrap()[0x85], 0x85 ^ 0xFF);
+    }
+
+    #[test]
+    fn test_flash_diff_lists_every_corrupted_byte() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let image = vec![0x5A; 700];
+        programmer.program_binary(image.clone()).unwrap();
+        // Bad bytes in two different pages
+        flash.lock().unwrap()[3] = 0x00;
+        flash.lock().unwrap()[600] = 0x5B;
+
+        let found = programmer.diff_flash(&image).unwrap();
+        assert_eq!(
+            found,
+            vec![
+                Mismatch {
+                    address: 3,
+                    expected: 0x5A,
+                    actual: 0x00
+                },
+                Mismatch {
+                    address: 600,
+                    expected: 0x5A,
+                    actual: 0x5B
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_program_at_address_keeps_lower_pages() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        programmer.program_binary(vec![0x11; 300]).unwrap();
+        let report = programmer
+            .program_binary_at(vec![0x22; 200], 0x7000)
+            .unwrap();
+        assert_eq!(report.pages_written, 2);
+
+        let flash = flash.lock().unwrap();
+        assert!(flash[..300].iter().all(|b| *b == 0x11));
+        assert!(flash[0x7000..0x7000 + 200].iter().all(|b| *b == 0x22));
+    }
+
+    #[test]
+    fn test_program_at_unaligned_address_is_refused() {
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024)),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            programmer.program_binary_at(vec![0x22; 16], 0x7010),
+            Err(AvrError::ConfigurationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_blank_check_finds_programmed_bytes() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        assert!(programmer.blank_check(1024).unwrap());
+
+        flash.lock().unwrap()[700] = 0x00;
+        assert!(!programmer.blank_check(1024).unwrap());
+        assert!(programmer.blank_check(700).unwrap());
+    }
+
+    #[test]
+    fn test_hex_file_with_non_ascii_path_is_programmed() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let path = std::env::temp_dir().join("avrman_fïrmwäre_blink.hex");
+        std::fs::write(&path, include_str!("blink.hex")).unwrap();
+
+        let result = programmer.program_hex_file(path.as_path());
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert_eq!(flash.lock().unwrap()[..4], [0x0C, 0x94, 0x5C, 0x00]);
+    }
+
+    #[test]
+    fn test_hex_file_is_programmed_end_to_end() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        programmer.program_hex_file("tests/blink.hex").unwrap();
+
+        // Lay the data records out by hand, independent of the crate's parser
+        let mut expected = vec![0xFF; 32 * 1024];
+        for record in ihex::Reader::new(include_str!("blink.hex")) {
+            if let ihex::Record::Data { offset, value } = record.unwrap() {
+                let offset = offset as usize;
+                expected[offset..offset + value.len()].copy_from_slice(&value);
+            }
+        }
+        assert_eq!(*flash.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_hex_is_programmed_from_a_reader() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let reader = std::io::Cursor::new(include_bytes!("blink.hex"));
+        programmer.program_hex_reader(reader).unwrap();
+
+        assert_eq!(flash.lock().unwrap()[..4], [0x0C, 0x94, 0x5C, 0x00]);
+    }
+
+    #[test]
+    fn test_blocking_stk500v1_programs_and_verifies() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::BlockingStk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let image: Vec<u8> = (0..300).map(|i| (i % 251) as u8).collect();
+        let report = programmer.program_binary(image.clone()).unwrap();
+
+        assert_eq!(report.verify_bytes, 300);
+        assert_eq!(flash.lock().unwrap()[..300], image[..]);
+    }
+
+    #[test]
+    fn test_closed_programmer_releases_its_interface() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let mut programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        programmer.close();
+        assert!(matches!(
+            programmer.program_binary(vec![0x5A; 16]),
+            Err(AvrError::Communication(_))
+        ));
+
+        // The loopback was dropped with the transport, there's nothing to reopen
+        assert!(matches!(
+            programmer.reopen(),
+            Err(AvrError::ConfigurationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_provision_erases_then_programs() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let report = programmer
+            .provision(ProvisionPlan {
+                erase: true,
+                eeprom: EepromOnErase::Erase,
+                fuses: None,
+                firmware: FirmwareSource::Binary(vec![0x5A; 300]),
+                lock_bits: None,
+            })
+            .unwrap();
+
+        assert!(report.erased);
+        assert_eq!(report.program.bytes_written, 300);
+        assert!(
+            flash.lock().unwrap()[..300]
+                .iter()
+                .all(|byte| *byte == 0x5A)
+        );
+    }
+
+    /// Default ATmega328p fuses, with EESAVE unprogrammed
+    const FUSES_WITHOUT_EESAVE: Fuses = Fuses {
+        low: 0xff,
+        high: 0xde,
+        extended: Some(0xfd),
+    };
+
+    #[test]
+    fn test_erase_restores_eeprom_without_eesave() {
+        let interface =
+            LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024).with_fuses(FUSES_WITHOUT_EESAVE);
+        let flash = interface.flash();
+        let eeprom = interface.eeprom();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        programmer.program_binary(vec![0x5A; 300]).unwrap();
+        let calibration: Vec<u8> = (0..64).collect();
+        programmer
+            .program_eeprom_binary(calibration.clone())
+            .unwrap();
+
+        programmer.erase_with(EepromOnErase::Restore(64)).unwrap();
+        assert!(flash.lock().unwrap().iter().all(|byte| *byte == 0xFF));
+        assert_eq!(eeprom.lock().unwrap()[..64], calibration[..]);
+    }
+
+    #[test]
+    fn test_provision_in_isp_mode_restores_eeprom_after_programming() {
+        let interface =
+            LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024).with_fuses(FUSES_WITHOUT_EESAVE);
+        let flash = interface.flash();
+        let eeprom = interface.eeprom();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(Stk500v1Params {
+                isp_mode: true,
+                ..params()
+            }),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        let calibration: Vec<u8> = (0..64).collect();
+        programmer
+            .program_eeprom_binary(calibration.clone())
+            .unwrap();
+
+        // Programming erases the chip once more in ISP mode
+        programmer
+            .provision(ProvisionPlan {
+                erase: true,
+                eeprom: EepromOnErase::Restore(64),
+                fuses: None,
+                firmware: FirmwareSource::Binary(vec![0x5A; 300]),
+                lock_bits: None,
+            })
+            .unwrap();
+
+        assert!(
+            flash.lock().unwrap()[..300]
+                .iter()
+                .all(|byte| *byte == 0x5A)
+        );
+        assert_eq!(eeprom.lock().unwrap()[..64], calibration[..]);
+    }
+
+    #[test]
+    fn test_safe_mode_leaves_bootloader_fuses_alone() {
+        // The loopback answers fuse reads with 0x00 like Optiboot does, which
+        // would read as RSTDISBL programmed
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(Stk500v1Params {
+                safe_mode: true,
+                ..params()
+            }),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        programmer.program_binary(vec![0x5A; 16]).unwrap();
+        assert!(flash.lock().unwrap()[..16].iter().all(|byte| *byte == 0x5A));
+    }
+
+    #[test]
+    fn test_erase_is_refused_without_eesave() {
+        let interface =
+            LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024).with_fuses(FUSES_WITHOUT_EESAVE);
+        let eeprom = interface.eeprom();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        programmer.program_eeprom_binary(vec![0x42; 8]).unwrap();
+        assert!(matches!(
+            programmer.erase_with(EepromOnErase::Refuse),
+            Err(AvrError::ConfigurationError(_))
+        ));
+        assert_eq!(eeprom.lock().unwrap()[..8], [0x42; 8]);
+
+        // Plain erase clears EEPROM along with flash
+        programmer.erase().unwrap();
+        assert!(eeprom.lock().unwrap().iter().all(|byte| *byte == 0xFF));
+    }
+
+    #[test]
+    fn test_provision_stops_at_the_failing_step() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(params()),
+            Box::new(interface),
+        )
+        .unwrap();
+
+        // The loopback reads every fuse back as 0x00
+        let result = programmer.provision(ProvisionPlan {
+            erase: false,
+            eeprom: EepromOnErase::Erase,
+            fuses: Some(Fuses {
+                low: 0xff,
+                high: 0xde,
+                extended: Some(0xfd),
+            }),
+            firmware: FirmwareSource::Binary(vec![0x5A; 300]),
+            lock_bits: None,
+        });
+
+        match result {
+            Err(AvrError::ProgrammerError(message)) => assert!(message.contains("writing fuses")),
+            result => panic!("Expected a programmer error, got {:?}", result),
+        }
+        assert!(flash.lock().unwrap().iter().all(|byte| *byte == 0xFF));
+    }
+
+    /// Loopback that loses the first `lost` commands, like a board that
+    /// missed its auto-reset
+    struct LateStart {
+        inner: LoopbackInterface,
+        lost: usize,
+    }
+
+    impl DeviceInterface for LateStart {
+        fn send(&mut self, command: Vec<u8>) -> AvrResult<()> {
+            if self.lost > 0 {
+                self.lost -= 1;
+                return Ok(());
+            }
+            self.inner.send(command)
+        }
+
+        fn receive(&mut self) -> AvrResult<Vec<u8>> {
+            self.inner.receive()
+        }
+
+        fn reset(&mut self) -> AvrResult<()> {
+            self.inner.reset()
+        }
+    }
+
+    #[test]
+    fn test_failed_sync_is_retried_from_reset() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let single_sync = Stk500v1Params {
+            sync_config: SyncConfig {
+                attempts: 1,
+                delay_ms: 0,
+            },
+            ..params()
+        };
+        let mut programmer = Programmer::from_protocol_and_interface(
+            ProtocolType::Stk500v1(single_sync),
+            Box::new(LateStart {
+                inner: interface,
+                lost: 1,
+            }),
+        )
+        .unwrap();
+        programmer.set_retries(1);
+
+        programmer.program_binary(vec![0x5A; 16]).unwrap();
+        assert!(flash.lock().unwrap()[..16].iter().all(|byte| *byte == 0x5A));
+    }
+
+    /// Serial server in front of a simulated bootloader, like ser2net with a
+    /// board attached. Each read from the client is handed to the bootloader
+    /// as one command, which holds since STK500v1 waits for every answer
+    fn serial_server(mut interface: LoopbackInterface) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; 1024];
+            while let Ok(size @ 1..) = stream.read(&mut buffer) {
+                interface.send(buffer[..size].to_vec()).unwrap();
+                let response = interface.receive().unwrap();
+                stream.write_all(&response).unwrap();
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_programs_board_behind_tcp_serial_server() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let port = serial_server(interface);
+
+        let programmer = Programmer::from_mcu_and_interface(
+            Microcontroller::ArduinoUno,
+            DeviceInterfaceType::Tcp {
+                host: String::from("127.0.0.1"),
+                port,
+            },
+        )
+        .unwrap();
+
+        let image: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        programmer.program_binary(image.clone()).unwrap();
+        assert_eq!(flash.lock().unwrap()[..image.len()], image[..]);
+    }
+
+    /// Telnet server that refuses RFC 2217 port control, in front of a
+    /// simulated bootloader. IAC bytes are doubled in the data both ways
+    fn refusing_rfc2217_server(mut interface: LoopbackInterface) -> u16 {
+        const IAC: u8 = 0xFF;
+        const WILL: u8 = 251;
+        const DONT: u8 = 254;
+        const COM_PORT_OPTION: u8 = 44;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut offer = [0; 3];
+            stream.read_exact(&mut offer).unwrap();
+            assert_eq!(offer, [IAC, WILL, COM_PORT_OPTION]);
+            stream.write_all(&[IAC, DONT, COM_PORT_OPTION]).unwrap();
+
+            let mut buffer = [0; 1024];
+            while let Ok(size @ 1..) = stream.read(&mut buffer) {
+                let mut command = Vec::with_capacity(size);
+                let mut escaped = false;
+                for &byte in &buffer[..size] {
+                    if byte == IAC && !escaped {
+                        escaped = true;
+                        continue;
+                    }
+                    escaped = false;
+                    command.push(byte);
+                }
+                interface.send(command).unwrap();
+
+                let response: Vec<u8> = interface
+                    .receive()
+                    .unwrap()
+                    .into_iter()
+                    .flat_map(|byte| {
+                        if byte == IAC {
+                            vec![IAC, IAC]
+                        } else {
+                            vec![byte]
+                        }
+                    })
+                    .collect();
+                stream.write_all(&response).unwrap();
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_programs_board_behind_server_refusing_rfc2217() {
+        let interface = LoopbackInterface::new(SIGNATURE.to_vec(), 32 * 1024);
+        let flash = interface.flash();
+        let port = refusing_rfc2217_server(interface);
+
+        let programmer = Programmer::from_mcu_and_interface(
+            Microcontroller::ArduinoUno,
+            DeviceInterfaceType::Rfc2217 {
+                host: String::from("127.0.0.1"),
+                port,
+            },
+        )
+        .unwrap();
+
+        // Without reset support the board is taken to be in its bootloader
+        // already, rather than failing with NoResetSupported
+        let image: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        programmer.program_binary(image.clone()).unwrap();
+        assert_eq!(flash.lock().unwrap()[..image.len()], image[..]);
+    }
+}